@@ -123,6 +123,12 @@ export const CONVERSION_TYPES = [
   'altText',
   'poracle',
   'sql',
+  'kml',
+  'kmz',
+  'gpx',
+  'wkt',
+  'wkb',
+  'shapefile',
 ] as const
 
 export const GEOMETRY_CONVERSION_TYPES = [
@@ -217,6 +217,27 @@ export interface Config {
   route_plugins: string[]
   clustering_plugins: string[]
   bootstrap_plugins: string[]
+  plugin_manifests: PluginManifest[]
+}
+
+export interface PluginArg {
+  name: string
+  type: 'string' | 'integer' | 'float' | 'boolean'
+  default?: string | number | boolean | null
+  required: boolean
+  description?: string | null
+}
+
+export interface PluginManifest {
+  plugin: string
+  folder: 'clustering' | 'routing' | 'bootstrap'
+  name: string
+  description?: string | null
+  interpreter?: string | null
+  entry?: string | null
+  args: PluginArg[]
+  protocol: number
+  timeout?: number | null
 }
 
 export type CombinedState = Partial<UsePersist> & Partial<UseStatic>
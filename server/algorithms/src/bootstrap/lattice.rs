@@ -0,0 +1,99 @@
+//! Hex lattice anchored to a fixed global origin, so the grids of neighboring areas line up
+//!
+//! Rows are laid out north and south of the equator at fixed distances and grouped into bands.
+//! Within a band the columns are evenly spaced in longitude starting from the prime meridian,
+//! using the spacing at the edge of the band closest to the equator, so circles are never
+//! further apart than the hex spacing. Bands are separated by a narrower gap that keeps the seam
+//! covered however the columns of the two bands happen to line up.
+use geo::Point;
+use model::api::Precision;
+
+/// Same earth radius that the haversine calculations use
+const METERS_PER_DEGREE: Precision = 6_371_008.8 * std::f64::consts::PI / 180.;
+const ROWS_PER_BAND: i64 = 32;
+/// Keeps the column spacing finite near the poles
+const MIN_COS: Precision = 0.01;
+
+fn row_spacing(radius: Precision) -> Precision {
+    radius * 1.5
+}
+
+fn band_height(radius: Precision) -> Precision {
+    (ROWS_PER_BAND - 1) as Precision * row_spacing(radius) + radius
+}
+
+/// Latitude of a row in degrees, `band` and `row` are relative to the equator
+fn row_latitude(radius: Precision, band: i64, row: i64) -> Precision {
+    (band as Precision * band_height(radius) + row as Precision * row_spacing(radius))
+        / METERS_PER_DEGREE
+}
+
+/// Longitude between two columns of a band, in degrees
+fn column_spacing(radius: Precision, band: i64) -> Precision {
+    let reference = if band >= 0 {
+        row_latitude(radius, band, 0)
+    } else {
+        row_latitude(radius, band, ROWS_PER_BAND - 1)
+    };
+    3_f64.sqrt() * radius / (METERS_PER_DEGREE * reference.to_radians().cos().max(MIN_COS))
+}
+
+/// Lattice points within the bounding box, given as `[min_lon, min_lat, max_lon, max_lat]`
+pub fn points(radius: Precision, bbox: [Precision; 4]) -> Vec<Point> {
+    let [min_lon, min_lat, max_lon, max_lat] = bbox;
+    let height = band_height(radius) / METERS_PER_DEGREE;
+    let first_band = (min_lat / height).floor() as i64;
+    let last_band = (max_lat / height).floor() as i64;
+
+    let mut points = vec![];
+    for band in first_band..=last_band {
+        let spacing = column_spacing(radius, band);
+        for row in 0..ROWS_PER_BAND {
+            let lat = row_latitude(radius, band, row);
+            if lat < min_lat || lat > max_lat {
+                continue;
+            }
+            let offset = if row % 2 == 1 { 0.5 } else { 0. };
+            let first_column = (min_lon / spacing - offset).ceil() as i64;
+            let last_column = (max_lon / spacing - offset).floor() as i64;
+            for column in first_column..=last_column {
+                points.push(Point::new((column as Precision + offset) * spacing, lat));
+            }
+        }
+    }
+    points
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use geo::HaversineDistance;
+
+    #[test]
+    fn neighboring_boxes_line_up() {
+        let whole = points(70., [-74.02, 40., -73.98, 40.03]);
+        let mut halves = points(70., [-74.02, 40., -74., 40.03]);
+        halves.extend(points(70., [-74., 40., -73.98, 40.03]));
+        halves.dedup();
+
+        assert_eq!(whole.len(), halves.len());
+        assert!(halves.iter().all(|point| whole.contains(point)));
+    }
+
+    #[test]
+    fn covers_band_seams() {
+        // crosses the seam between the first two bands north of the equator
+        let seam = band_height(70.) / METERS_PER_DEGREE;
+        let lattice = points(70., [9.99, seam - 0.01, 10.03, seam + 0.01]);
+        for i in 0..40 {
+            for j in 0..40 {
+                let point = Point::new(10. + j as f64 * 0.0005, seam - 0.005 + i as f64 * 0.00025);
+                let nearest = lattice
+                    .iter()
+                    .map(|center| center.haversine_distance(&point))
+                    .fold(Precision::MAX, Precision::min);
+                assert!(nearest <= 70.01, "{:?} is {}m away", point, nearest);
+            }
+        }
+    }
+}
@@ -4,11 +4,13 @@ use geojson::{Feature, FeatureCollection};
 use model::api::{calc_mode::CalculationMode, sort_by::SortBy, Precision, ToFeature};
 
 use crate::{
+    context::Context,
     plugin::{Folder, Plugin},
     stats::Stats,
     utils,
 };
 
+mod lattice;
 pub mod radius;
 pub mod s2;
 
@@ -22,33 +24,74 @@ pub fn main(
     route_split_level: u64,
     stats: &mut Stats,
     routing_args: &str,
+    routing_time: u64,
     bootstrapping_rags: &str,
+    fixed_grid: bool,
+    neighbors: &FeatureCollection,
+    context: &Context,
 ) -> Vec<Feature> {
     let mut features = vec![];
 
-    for feature in area.features {
+    for (index, feature) in area.features.iter().enumerate() {
+        if context.is_cancelled() {
+            break;
+        }
+        context.report(index, area.features.len());
         match &calculation_mode {
             CalculationMode::Radius => {
-                let mut new_radius = radius::BootstrapRadius::new(&feature, radius);
-                new_radius.sort(&sort_by, route_split_level, routing_args);
+                let mut new_radius = if fixed_grid {
+                    // the other areas of the request border each other as well
+                    let others: Vec<Feature> = area
+                        .features
+                        .iter()
+                        .enumerate()
+                        .filter(|(i, _)| *i != index)
+                        .map(|(_, other)| other.clone())
+                        .chain(neighbors.features.iter().cloned())
+                        .collect();
+                    radius::BootstrapRadius::new_fixed(feature, radius, &others)
+                } else {
+                    radius::BootstrapRadius::new(feature, radius)
+                };
+                new_radius.sort(
+                    &sort_by,
+                    route_split_level,
+                    routing_args,
+                    routing_time,
+                    context,
+                );
 
                 *stats += &new_radius.stats;
                 features.push(new_radius.feature());
             }
             CalculationMode::S2 => {
-                let mut new_s2 = s2::BootstrapS2::new(&feature, s2_level as u64, s2_size);
-                new_s2.sort(&sort_by, route_split_level, routing_args);
+                let mut new_s2 = s2::BootstrapS2::new(feature, s2_level as u64, s2_size, context);
+                new_s2.sort(
+                    &sort_by,
+                    route_split_level,
+                    routing_args,
+                    routing_time,
+                    context,
+                );
 
                 *stats += &new_s2.stats;
                 features.push(new_s2.feature());
             }
             CalculationMode::Custom(plugin) => {
                 match Plugin::new(plugin, Folder::Bootstrap, 0, bootstrapping_rags) {
-                    Ok(plugin_manager) => {
+                    Ok(mut plugin_manager) => {
                         let time = Instant::now();
-                        match plugin_manager.run(feature.to_string()) {
+                        match plugin_manager
+                            .set_context(context)
+                            .set_option("radius", radius)
+                            .set_option("s2_level", s2_level)
+                            .set_option("s2_size", s2_size)
+                            .set_option("area", feature)
+                            .run_text(feature.to_string())
+                        {
                             Ok(sorted_clusters) => {
                                 let mut plugin_stats = Stats::new(plugin.to_string(), 0);
+                                plugin_manager.report_to(&mut plugin_stats);
                                 plugin_stats.set_cluster_time(time);
                                 plugin_stats.cluster_stats(0., &vec![], &sorted_clusters);
                                 features.push(sorted_clusters.to_feature(None));
@@ -56,11 +99,16 @@ pub fn main(
                             }
                             Err(e) => {
                                 log::error!("Error while running plugin: {}", e);
+                                plugin_manager.report_to(stats);
+                                if !e.is_cancelled() {
+                                    stats.plugin_errors.push(e.to_string());
+                                }
                             }
                         }
                     }
                     Err(e) => {
                         log::error!("Plugin not found: {}", e);
+                        stats.plugin_errors.push(e.to_string());
                     }
                 }
             }
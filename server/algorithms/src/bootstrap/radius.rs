@@ -1,6 +1,7 @@
 use std::time::Instant;
 
-use crate::{routing, stats::Stats};
+use super::lattice;
+use crate::{context::Context, routing, stats::Stats};
 
 use geo::{Contains, Extremes, HaversineDestination, HaversineDistance, Point, Polygon};
 use geojson::{Feature, Geometry, Value};
@@ -8,22 +9,46 @@ use model::{
     api::{single_vec::SingleVec, sort_by::SortBy, Precision, ToFeature, ToGeometryVec},
     db::sea_orm_active_enums::Type,
 };
-use rayon::prelude::{IntoParallelRefIterator, ParallelIterator};
+use rayon::prelude::{IntoParallelIterator, IntoParallelRefIterator, ParallelIterator};
 
 #[derive(Debug)]
 pub struct BootstrapRadius<'a> {
     feature: &'a Feature,
     result: SingleVec,
     radius: Precision,
+    /// Polygons of the neighboring areas, `Some` when the circles are aligned to the global lattice
+    neighbors: Option<Vec<Polygon<Precision>>>,
     pub stats: Stats,
 }
 
 impl<'a> BootstrapRadius<'a> {
     pub fn new(feature: &'a Feature, radius: Precision) -> Self {
+        Self::build(feature, radius, None)
+    }
+
+    /// Places the circles on the global hex lattice so they line up with the circles of any other
+    /// area with the same radius. Border circles that one of the `neighbors` has a better claim to
+    /// are left for that neighbor, so shared borders aren't covered twice
+    pub fn new_fixed(feature: &'a Feature, radius: Precision, neighbors: &[Feature]) -> Self {
+        let neighbors = neighbors
+            .iter()
+            .filter_map(|neighbor| neighbor.geometry.clone())
+            .flat_map(|geometry| geometry.to_geometry_vec())
+            .filter_map(|geometry| Polygon::<Precision>::try_from(&geometry).ok())
+            .collect();
+        Self::build(feature, radius, Some(neighbors))
+    }
+
+    fn build(
+        feature: &'a Feature,
+        radius: Precision,
+        neighbors: Option<Vec<Polygon<Precision>>>,
+    ) -> Self {
         let mut new_bootstrap = Self {
             feature,
             result: vec![],
             radius,
+            neighbors,
             stats: Stats::new("BootstrapRadius".to_string(), 0),
         };
 
@@ -37,7 +62,14 @@ impl<'a> BootstrapRadius<'a> {
         new_bootstrap
     }
 
-    pub fn sort(&mut self, sort_by: &SortBy, route_split_level: u64, routing_args: &str) {
+    pub fn sort(
+        &mut self,
+        sort_by: &SortBy,
+        route_split_level: u64,
+        routing_args: &str,
+        routing_time: u64,
+        context: &Context,
+    ) {
         self.result = routing::main(
             &vec![],
             self.result.clone(),
@@ -46,6 +78,11 @@ impl<'a> BootstrapRadius<'a> {
             self.radius,
             &mut self.stats,
             routing_args,
+            routing_time,
+            None,
+            None,
+            None,
+            context,
         );
     }
 
@@ -89,9 +126,53 @@ impl<'a> BootstrapRadius<'a> {
     }
 
     fn generate_circles(&self, geometry: &Geometry) -> Vec<Point> {
+        let polygon = Polygon::<Precision>::try_from(geometry).unwrap();
+        match &self.neighbors {
+            Some(neighbors) => self.lattice_circles(&polygon, neighbors),
+            None => self.local_circles(&polygon),
+        }
+    }
+
+    /// Circles on the global lattice that touch the polygon and that no neighbor has a better claim to
+    fn lattice_circles(
+        &self,
+        polygon: &Polygon<Precision>,
+        neighbors: &[Polygon<Precision>],
+    ) -> Vec<Point> {
+        let extremes = polygon.extremes().unwrap();
+        let max = Point::new(extremes.x_max.coord.x, extremes.y_max.coord.y)
+            .haversine_destination(45., self.radius * 2.);
+        let min = Point::new(extremes.x_min.coord.x, extremes.y_min.coord.y)
+            .haversine_destination(225., self.radius * 2.);
+
+        let candidates: Vec<Point> =
+            lattice::points(self.radius, [min.x(), min.y(), max.x(), max.y()])
+                .into_par_iter()
+                .filter(|point| boundary_score(polygon, point) <= self.radius)
+                .collect();
+        let before = candidates.len();
+        let circles: Vec<Point> = candidates
+            .into_par_iter()
+            .filter(|point| {
+                let score = boundary_score(polygon, point);
+                neighbors.iter().all(|neighbor| {
+                    let neighbor_score = boundary_score(neighbor, point);
+                    neighbor_score > self.radius || score <= neighbor_score
+                })
+            })
+            .collect();
+        if circles.len() < before {
+            log::info!(
+                "left {} border circles to neighboring areas",
+                before - circles.len()
+            );
+        }
+        circles
+    }
+
+    fn local_circles(&self, polygon: &Polygon<Precision>) -> Vec<Point> {
         let mut circles: Vec<Point> = vec![];
 
-        let polygon = Polygon::<Precision>::try_from(geometry).unwrap();
         let external_points = polygon.exterior().points().collect::<Vec<Point>>();
         let internal_points: Vec<_> = polygon
             .interiors()
@@ -144,6 +225,23 @@ impl<'a> BootstrapRadius<'a> {
     }
 }
 
+/// Distance from the point to the edge of the polygon, negative when the point is inside of it
+fn boundary_score(polygon: &Polygon<Precision>, point: &Point) -> Precision {
+    let distance = polygon
+        .interiors()
+        .iter()
+        .map(|interior| point_line_distance(&interior.points().collect(), point))
+        .fold(
+            point_line_distance(&polygon.exterior().points().collect(), point),
+            Precision::min,
+        );
+    if polygon.contains(point) {
+        -distance
+    } else {
+        distance
+    }
+}
+
 fn dot(u: &Point, v: &Point) -> Precision {
     u.x() * v.x() + u.y() * v.y()
 }
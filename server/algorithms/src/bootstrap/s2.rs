@@ -1,6 +1,7 @@
 use std::time::Instant;
 
 use crate::{
+    context::Context,
     routing, rtree,
     s2::{BuildGrid, Dir, ToPointArray, Traverse},
     stats::Stats,
@@ -25,16 +26,18 @@ pub struct BootstrapS2<'a> {
     result: SingleVec,
     level: u64,
     size: u8,
+    context: &'a Context,
     pub stats: Stats,
 }
 
 impl<'a> BootstrapS2<'a> {
-    pub fn new(feature: &'a Feature, level: u64, size: u8) -> Self {
+    pub fn new(feature: &'a Feature, level: u64, size: u8, context: &'a Context) -> Self {
         let mut new_bootstrap = Self {
             feature,
             result: vec![],
             level,
             size,
+            context,
             stats: Stats::new("BootstrapS2".to_string(), 0),
         };
 
@@ -48,7 +51,14 @@ impl<'a> BootstrapS2<'a> {
         new_bootstrap
     }
 
-    pub fn sort(&mut self, sort_by: &SortBy, route_split_level: u64, routing_args: &str) {
+    pub fn sort(
+        &mut self,
+        sort_by: &SortBy,
+        route_split_level: u64,
+        routing_args: &str,
+        routing_time: u64,
+        context: &Context,
+    ) {
         self.result = routing::main(
             &vec![],
             self.result.clone(),
@@ -57,6 +67,11 @@ impl<'a> BootstrapS2<'a> {
             0.,
             &mut self.stats,
             routing_args,
+            routing_time,
+            None,
+            None,
+            None,
+            context,
         );
     }
 
@@ -201,7 +216,7 @@ impl<'a> BootstrapS2<'a> {
             let mut repeat_check = 0;
             let mut last_report = 0;
 
-            while repeat_check < 5000 {
+            while repeat_check < 5000 && !self.context.is_cancelled() {
                 traversing += 1;
                 if cells.contains_cellid(&current) {
                     cell_grids.push(current);
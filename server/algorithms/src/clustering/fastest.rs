@@ -4,7 +4,7 @@ use model::api::single_vec::SingleVec;
 use rstar::PointDistance;
 use std::collections::HashMap;
 
-use crate::project::Plane;
+use crate::{context::Context, project::Plane};
 
 #[derive(Debug, Clone)]
 struct BoundingBox {
@@ -64,11 +64,11 @@ impl FromKey for String {
     }
 }
 
-pub fn main(input: &SingleVec, radius: f64, min_points: usize) -> Vec<[f64; 2]> {
+pub fn main(input: &SingleVec, radius: f64, min_points: usize, context: &Context) -> Vec<[f64; 2]> {
     let plane = Plane::new(input).radius(radius);
     let output = plane.project();
 
-    let point_map = cluster(output, min_points);
+    let point_map = cluster(output, min_points, context);
 
     let output = {
         let mut seen_map: HashSet<String> = HashSet::new();
@@ -97,7 +97,11 @@ fn update(point_map: &mut ClusterMap, key: PointTuple, p: Coord) {
     });
 }
 
-fn cluster(points: Vec<Coord>, min_points: usize) -> HashMap<String, Vec<String>> {
+fn cluster(
+    points: Vec<Coord>,
+    min_points: usize,
+    context: &Context,
+) -> HashMap<String, Vec<String>> {
     let sqrt2: f64 = 2.0_f64.sqrt();
     let additive_factor: f64 = sqrt2 / 2.;
     let sqrt2_x_one_point_five_minus_one: f64 = (sqrt2 * 1.5) - 1.;
@@ -105,7 +109,12 @@ fn cluster(points: Vec<Coord>, min_points: usize) -> HashMap<String, Vec<String>
 
     let mut udc_point_map: ClusterMap = HashMap::new();
 
-    for p in points.into_iter() {
+    let total = points.len();
+    for (i, p) in points.into_iter().enumerate() {
+        if context.is_cancelled() {
+            break;
+        }
+        context.report(i, total);
         let v = (p.x / sqrt2).floor() as i32;
         let h = (p.y / sqrt2).floor() as i32;
         let vertical_times_sqrt2 = v as f64 * sqrt2;
@@ -1,32 +1,80 @@
 use geojson::{Feature, Geometry};
-use hashbrown::HashSet;
+use hashbrown::{HashMap, HashSet};
 use model::api::{cluster_mode::ClusterMode, single_vec::SingleVec, GetBbox, Precision};
 
 use ::s2::cellid::CellID;
 use rayon::{
     prelude::{
-        IntoParallelIterator, IntoParallelRefIterator, IntoParallelRefMutIterator, ParallelIterator,
+        IndexedParallelIterator, IntoParallelIterator, IntoParallelRefIterator,
+        IntoParallelRefMutIterator, ParallelIterator,
     },
     slice::ParallelSliceMut,
 };
 use rstar::RTree;
-use std::{io::Write, time::Instant};
+use std::{
+    cmp::Ordering,
+    collections::BinaryHeap,
+    io::Write,
+    sync::atomic::{self, AtomicUsize},
+    time::{Duration, Instant},
+};
 use sysinfo::{System, SystemExt};
 
 use crate::{
     bootstrap::radius,
     clustering::rtree::{cluster::Cluster, point::Point},
+    context::Context,
     rtree::{self, point::ToPoint, SortDedupe},
     s2,
     utils::info_log,
 };
 
+use super::set_cover;
+
+/// Seconds that the optimal cluster mode searches for when no time limit is set
+const DEFAULT_TIME_LIMIT: u64 = 30;
+
 pub struct Greedy {
     cluster_mode: ClusterMode,
     cluster_split_level: u64,
+    context: Context,
+    fixed_grid: bool,
     max_clusters: usize,
     min_points: usize,
     radius: Precision,
+    time_limit: u64,
+    weights: HashMap<CellID, Precision>,
+    lower_bound: AtomicUsize,
+}
+
+/// A cluster in the weighted greedy queue, `weight` may be stale until it is popped
+struct WeightedCandidate {
+    weight: Precision,
+    count: usize,
+    index: usize,
+}
+
+impl PartialEq for WeightedCandidate {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for WeightedCandidate {}
+
+impl PartialOrd for WeightedCandidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for WeightedCandidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.weight
+            .total_cmp(&other.weight)
+            .then(self.count.cmp(&other.count))
+            .then(other.index.cmp(&self.index))
+    }
 }
 
 impl Default for Greedy {
@@ -34,9 +82,14 @@ impl Default for Greedy {
         Greedy {
             cluster_mode: ClusterMode::Balanced,
             cluster_split_level: 0,
+            context: Context::default(),
+            fixed_grid: false,
             max_clusters: usize::MAX,
             min_points: 1,
             radius: 70.,
+            time_limit: DEFAULT_TIME_LIMIT,
+            weights: HashMap::new(),
+            lower_bound: AtomicUsize::new(0),
         }
     }
 }
@@ -46,6 +99,16 @@ impl<'a> Greedy {
         self.cluster_mode = cluster_mode;
         self
     }
+    /// Stops clustering early when the context is cancelled
+    pub fn set_context(&mut self, context: &Context) -> &mut Self {
+        self.context = context.clone();
+        self
+    }
+    /// Aligns the honeycomb candidates to the global hex lattice
+    pub fn set_fixed_grid(&mut self, fixed_grid: bool) -> &mut Self {
+        self.fixed_grid = fixed_grid;
+        self
+    }
     pub fn set_radius(&mut self, radius: Precision) -> &mut Self {
         self.radius = radius;
         self
@@ -62,29 +125,70 @@ impl<'a> Greedy {
         self.cluster_split_level = cluster_split_level;
         self
     }
+    /// Seconds that the optimal cluster mode searches for, `0` uses the default.
+    /// The limit covers the whole run, split cells share it rather than getting their own
+    pub fn set_time_limit(&mut self, time_limit: u64) -> &mut Self {
+        self.time_limit = if time_limit == 0 {
+            DEFAULT_TIME_LIMIT
+        } else {
+            time_limit
+        };
+        self
+    }
+    /// Sets the weight of each data point, clusters are then picked by the weight they cover.
+    /// `weights` must line up with `points`, an empty Vec disables weighting. Data points in the
+    /// same level 20 cell are clustered as one point, so their weights are added up
+    pub fn set_weights(&mut self, points: &SingleVec, weights: &[Precision]) -> &mut Self {
+        if weights.len() != points.len() {
+            if !weights.is_empty() {
+                log::error!(
+                    "{} weights were set for {} data points, weighting is disabled",
+                    weights.len(),
+                    points.len()
+                );
+            }
+            self.weights = HashMap::new();
+            return self;
+        }
+        let mut cells = HashMap::new();
+        for (point, weight) in points.iter().zip(weights.iter()) {
+            *cells
+                .entry(Point::new(self.radius, 20, *point).cell_id)
+                .or_insert(0.) += *weight;
+        }
+        self.weights = cells;
+        self
+    }
+
+    /// Fewest clusters that could cover the data points, only set by the optimal cluster mode
+    pub fn lower_bound(&self) -> usize {
+        self.lower_bound.load(atomic::Ordering::Relaxed)
+    }
+
+    fn get_weight(&self, point: &Point) -> Precision {
+        self.weights.get(&point.cell_id).copied().unwrap_or(1.)
+    }
 
     pub fn run(&'a self, points: &SingleVec) -> SingleVec {
         let time = Instant::now();
         log::info!("starting algorithm with {} data points", points.len());
+        // one time limit for the whole run, however many cells it is split into
+        let deadline = time
+            .checked_add(Duration::from_secs(self.time_limit))
+            .unwrap_or(time + Duration::from_secs(u32::MAX as u64));
 
         let return_set = if self.cluster_split_level == 0 {
-            self.setup(points)
+            self.setup(points, deadline)
         } else {
             let cell_maps = s2::create_cell_map(&points, self.cluster_split_level);
 
             let mut return_set = HashSet::new();
             std::thread::scope(|s| {
-                let mut handlers = vec![];
-                for (key, values) in cell_maps.iter() {
-                    log::debug!("Cell: {} | Points: {}", key, values.len());
-                    let thread = s.spawn(move || self.setup(values));
-                    handlers.push(thread);
-                }
                 let handlers: Vec<std::thread::ScopedJoinHandle<'_, HashSet<Point>>> = cell_maps
                     .iter()
                     .map(|(key, values)| {
                         log::debug!("Cell: {} | Points: {}", key, values.len());
-                        s.spawn(move || self.setup(values))
+                        s.spawn(move || self.setup(values, deadline))
                     })
                     .collect();
                 log::info!("created {} threads", handlers.len());
@@ -114,7 +218,10 @@ impl<'a> Greedy {
                 let ratio = i as Precision / 8 as Precision;
                 let new_point = point.interpolate(neighbor, ratio, 0., 0.);
                 clusters.insert(new_point);
-                if self.cluster_mode == ClusterMode::Balanced {
+                if matches!(
+                    self.cluster_mode,
+                    ClusterMode::Balanced | ClusterMode::Optimal
+                ) {
                     for wiggle in vec![0.00025, 0.0001] {
                         let wiggle_lat: Precision = wiggle / 2.;
                         let wiggle_lon = wiggle;
@@ -200,11 +307,15 @@ impl<'a> Greedy {
             }),
             ..Default::default()
         };
-        radius::BootstrapRadius::new(&feat, self.radius)
-            .result()
-            .into_iter()
-            .map(|p| Point::new(self.radius, 20, p))
-            .collect()
+        if self.fixed_grid {
+            radius::BootstrapRadius::new_fixed(&feat, self.radius, &[])
+        } else {
+            radius::BootstrapRadius::new(&feat, self.radius)
+        }
+        .result()
+        .into_iter()
+        .map(|p| Point::new(self.radius, 20, p))
+        .collect()
     }
 
     fn associate_clusters(
@@ -290,14 +401,22 @@ impl<'a> Greedy {
         clustered_clusters
     }
 
-    fn setup(&'a self, points: &SingleVec) -> HashSet<Point> {
+    fn setup(&'a self, points: &SingleVec, deadline: Instant) -> HashSet<Point> {
         let time = Instant::now();
         let point_tree: RTree<Point> = rtree::spawn(self.radius, points);
         log::info!("created point tree in {:.2}s", time.elapsed().as_secs_f32());
 
         let clusters_with_data = self.associate_clusters(points, &point_tree);
 
-        let mut solution = self.cluster(clusters_with_data).into_iter().collect();
+        let mut solution = if self.cluster_mode == ClusterMode::Optimal {
+            self.cluster_optimal(clusters_with_data, deadline)
+        } else if self.weights.is_empty() {
+            self.cluster(clusters_with_data)
+        } else {
+            self.cluster_weighted(clusters_with_data)
+        }
+        .into_iter()
+        .collect();
 
         self.update_unique(&mut solution);
 
@@ -326,8 +445,12 @@ impl<'a> Greedy {
         let mut logging_time = 0.;
 
         'greedy: while current >= self.min_points && new_clusters.len() < self.max_clusters {
+            if self.context.is_cancelled() {
+                break;
+            }
             let mut clusters_of_interest: Vec<&Cluster<'_>> = vec![];
             current_iteration += 1;
+            self.context.report(current_iteration, total_iterations);
             let time = Instant::now();
             for (index, clusters) in clusters_with_data.iter().enumerate() {
                 if index < current {
@@ -439,6 +562,143 @@ impl<'a> Greedy {
         new_clusters
     }
 
+    /// Lazy greedy set cover that picks the cluster covering the most uncovered weight.
+    /// The weight a cluster covers can only go down, so a popped cluster is only re-queued
+    /// when its weight is stale.
+    fn cluster_weighted(
+        &'a self,
+        clusters_with_data: Vec<Vec<Cluster<'a>>>,
+    ) -> HashSet<Cluster<'a>> {
+        let time = Instant::now();
+        log::info!("starting weighted solution");
+        let candidates: Vec<Cluster> = clusters_with_data.into_iter().flatten().collect();
+        let mut queue: BinaryHeap<WeightedCandidate> = candidates
+            .par_iter()
+            .enumerate()
+            .map(|(index, cluster)| {
+                let mut points = cluster.all.clone();
+                points.sort_dedupe();
+                WeightedCandidate {
+                    weight: points.iter().map(|p| self.get_weight(p)).sum(),
+                    count: points.len(),
+                    index,
+                }
+            })
+            .collect::<Vec<WeightedCandidate>>()
+            .into();
+
+        let mut new_clusters = HashSet::<Cluster>::new();
+        let mut blocked_points = HashSet::<&Point>::new();
+
+        while let Some(candidate) = queue.pop() {
+            if new_clusters.len() >= self.max_clusters || self.context.is_cancelled() {
+                break;
+            }
+            let cluster = &candidates[candidate.index];
+            let mut unique: Vec<&Point> = cluster
+                .all
+                .iter()
+                .filter(|p| !blocked_points.contains(*p))
+                .copied()
+                .collect();
+            unique.sort_dedupe();
+            if unique.len() < self.min_points {
+                continue;
+            }
+            let weight: Precision = unique.iter().map(|p| self.get_weight(p)).sum();
+            if weight < candidate.weight {
+                queue.push(WeightedCandidate {
+                    weight,
+                    count: unique.len(),
+                    index: candidate.index,
+                });
+                continue;
+            }
+            blocked_points.extend(unique.iter().copied());
+            new_clusters.insert(Cluster {
+                point: cluster.point,
+                unique,
+                all: cluster.all.clone(),
+            });
+        }
+
+        log::info!(
+            "finished weighted solution in {:.2}s",
+            time.elapsed().as_secs_f32()
+        );
+        log::info!("weighted solution size: {}", new_clusters.len());
+
+        new_clusters
+    }
+
+    /// Solves the candidate clusters as an exact set cover, falling back to the best cover found
+    /// when the time limit is hit
+    fn cluster_optimal(
+        &'a self,
+        clusters_with_data: Vec<Vec<Cluster<'a>>>,
+        deadline: Instant,
+    ) -> HashSet<Cluster<'a>> {
+        let time = Instant::now();
+        log::info!("starting optimal solution");
+        if self.max_clusters != usize::MAX {
+            log::warn!("max_clusters is ignored by the optimal cluster mode");
+        }
+        // like the other modes, points that no cluster with `min_points` covers are left out
+        let candidates: Vec<Cluster> = clusters_with_data
+            .into_iter()
+            .flatten()
+            .filter(|cluster| cluster.all.iter().collect::<HashSet<_>>().len() >= self.min_points)
+            .collect();
+        let mut indexes: HashMap<&Point, usize> = HashMap::new();
+        let sets: Vec<Vec<usize>> = candidates
+            .iter()
+            .map(|cluster| {
+                let mut set: Vec<usize> = cluster
+                    .all
+                    .iter()
+                    .map(|point| {
+                        let next = indexes.len();
+                        *indexes.entry(*point).or_insert(next)
+                    })
+                    .collect();
+                set.sort_unstable();
+                set.dedup();
+                set
+            })
+            .collect();
+
+        let solution = set_cover::solve(&sets, indexes.len(), deadline, &self.context);
+        self.lower_bound
+            .fetch_add(solution.lower_bound, atomic::Ordering::Relaxed);
+        if !solution.optimal {
+            log::warn!(
+                "optimal solution timed out after {:.2}s, using the best solution found",
+                time.elapsed().as_secs_f32()
+            );
+        }
+
+        let mut chosen = vec![false; candidates.len()];
+        solution.chosen.into_iter().for_each(|i| chosen[i] = true);
+        let new_clusters: HashSet<Cluster> = candidates
+            .into_iter()
+            .zip(chosen)
+            .filter(|(_, chosen)| *chosen)
+            .map(|(cluster, _)| cluster)
+            .collect();
+
+        log::info!(
+            "finished optimal solution in {:.2}s",
+            time.elapsed().as_secs_f32()
+        );
+        log::info!(
+            "optimal solution size: {} | lower bound: {}",
+            new_clusters.len(),
+            solution.lower_bound
+        );
+
+        new_clusters
+    }
+
     fn update_unique(&'a self, clusters: &mut Vec<Cluster>) {
         let time = Instant::now();
         log::info!("updating unique");
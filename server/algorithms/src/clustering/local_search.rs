@@ -0,0 +1,357 @@
+//! Improvement pass that tries to remove clusters from any clustering result
+//!
+//! Each round drops clusters whose points are all covered by other clusters, merges pairs of
+//! nearby clusters whose points fit in a single circle, and removes clusters whose points can be
+//! handed to their neighbors by re-centering them. A change is only kept if every data point
+//! that was covered before it is still covered after it.
+use std::time::{Duration, Instant};
+
+use hashbrown::HashMap;
+use model::api::{point_array::PointArray, single_vec::SingleVec, Precision};
+use rstar::RTree;
+
+use crate::{
+    context::Context,
+    rtree::{self, point::Point},
+    sec,
+};
+
+/// Attempts given to the smallest enclosing circle for each candidate
+const SEC_ATTEMPTS: usize = 10;
+
+struct LocalSearch {
+    radius: Precision,
+    points: SingleVec,
+    point_tree: RTree<Point>,
+    /// Index in `points` of each level 20 cell
+    lookup: HashMap<u64, usize>,
+    clusters: Vec<Option<PointArray>>,
+    /// Indexes of the points within the radius of each cluster
+    covers: Vec<Vec<usize>>,
+    /// Number of clusters that cover each point
+    counts: Vec<usize>,
+    deadline: Instant,
+    context: Context,
+}
+
+impl LocalSearch {
+    fn new(
+        radius: Precision,
+        data_points: &SingleVec,
+        clusters: &SingleVec,
+        time: u64,
+        context: &Context,
+    ) -> Self {
+        let mut lookup = HashMap::new();
+        let mut points = vec![];
+        for point in data_points.iter() {
+            lookup
+                .entry(Point::new(radius, 20, *point).cell_id.0)
+                .or_insert_with(|| {
+                    points.push(*point);
+                    points.len() - 1
+                });
+        }
+        let mut search = Self {
+            radius,
+            point_tree: rtree::spawn(radius, &points),
+            counts: vec![0; points.len()],
+            points,
+            lookup,
+            clusters: clusters.iter().map(|cluster| Some(*cluster)).collect(),
+            covers: vec![],
+            deadline: Instant::now()
+                .checked_add(Duration::from_secs(time))
+                .unwrap_or_else(|| Instant::now() + Duration::from_secs(u32::MAX as u64)),
+            context: context.clone(),
+        };
+        search.covers = clusters
+            .iter()
+            .map(|cluster| search.covered_by(cluster))
+            .collect();
+        for cover in search.covers.iter() {
+            for point in cover.iter() {
+                search.counts[*point] += 1;
+            }
+        }
+        search
+    }
+
+    fn timed_out(&self) -> bool {
+        Instant::now() > self.deadline || self.context.is_cancelled()
+    }
+
+    fn active(&self) -> usize {
+        self.clusters.iter().filter(|c| c.is_some()).count()
+    }
+
+    fn covered_by(&self, center: &PointArray) -> Vec<usize> {
+        let mut covered: Vec<usize> = self
+            .point_tree
+            .locate_all_at_point(center)
+            .filter_map(|point| self.lookup.get(&point.cell_id.0).copied())
+            .collect();
+        covered.sort_unstable();
+        covered.dedup();
+        covered
+    }
+
+    /// Points of the given clusters that no other cluster covers
+    fn needed(&self, clusters: &[usize]) -> Vec<usize> {
+        let mut needed: Vec<usize> = clusters
+            .iter()
+            .flat_map(|cluster| self.covers[*cluster].iter().copied())
+            .filter(|point| {
+                let own = clusters
+                    .iter()
+                    .filter(|cluster| self.covers[**cluster].binary_search(point).is_ok())
+                    .count();
+                self.counts[*point] == own
+            })
+            .collect();
+        needed.sort_unstable();
+        needed.dedup();
+        needed
+    }
+
+    fn enclose(&self, points: &[usize]) -> Option<PointArray> {
+        let points: SingleVec = points.iter().map(|point| self.points[*point]).collect();
+        sec::enclose(self.radius, &points, SEC_ATTEMPTS)
+    }
+
+    /// Active clusters within twice the radius of `cluster`, nearest first
+    fn neighbors(
+        &self,
+        tree: &RTree<Point>,
+        ids: &HashMap<u64, Vec<usize>>,
+        cluster: usize,
+    ) -> Vec<usize> {
+        let Some(center) = self.clusters[cluster] else {
+            return vec![];
+        };
+        let center = Point::new(self.radius, 20, center);
+        let mut neighbors: Vec<(Precision, usize)> = tree
+            .locate_all_at_point(&center.center)
+            .filter_map(|point| ids.get(&point.cell_id.0))
+            .flatten()
+            .filter(|other| **other != cluster)
+            .filter_map(|other| {
+                let distance = center.haversine_distance(&self.clusters[*other]?);
+                (distance <= self.radius * 2.).then_some((distance, *other))
+            })
+            .collect();
+        neighbors.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)));
+        neighbors.dedup_by_key(|(_, other)| *other);
+        neighbors.into_iter().map(|(_, other)| other).collect()
+    }
+
+    /// Moves or removes clusters, only if every point they covered is still covered afterwards
+    fn apply(&mut self, changes: &[(usize, Option<PointArray>)]) -> bool {
+        let new_covers: Vec<Vec<usize>> = changes
+            .iter()
+            .map(|(_, center)| match center {
+                Some(center) => self.covered_by(center),
+                None => vec![],
+            })
+            .collect();
+        let mut counts: HashMap<usize, usize> = HashMap::new();
+        for (cluster, _) in changes.iter() {
+            for point in self.covers[*cluster].iter() {
+                *counts.entry(*point).or_insert(self.counts[*point]) -= 1;
+            }
+        }
+        for cover in new_covers.iter() {
+            for point in cover.iter() {
+                *counts.entry(*point).or_insert(self.counts[*point]) += 1;
+            }
+        }
+        if counts.values().any(|count| *count == 0) {
+            return false;
+        }
+        for (point, count) in counts {
+            self.counts[point] = count;
+        }
+        for ((cluster, center), cover) in changes.iter().zip(new_covers) {
+            self.clusters[*cluster] = *center;
+            self.covers[*cluster] = cover;
+        }
+        true
+    }
+
+    /// Tree of the active clusters, with the radius widened to find neighbors that moved this round
+    fn cluster_tree(&self) -> (RTree<Point>, HashMap<u64, Vec<usize>>) {
+        let mut ids: HashMap<u64, Vec<usize>> = HashMap::new();
+        let mut centers = vec![];
+        for (i, cluster) in self.clusters.iter().enumerate() {
+            if let Some(center) = cluster {
+                ids.entry(Point::new(self.radius, 20, *center).cell_id.0)
+                    .or_default()
+                    .push(i);
+                centers.push(*center);
+            }
+        }
+        (rtree::spawn(self.radius * 3., &centers), ids)
+    }
+
+    /// Active clusters, the ones that cover the fewest points first
+    fn order(&self) -> Vec<usize> {
+        let mut order: Vec<usize> = (0..self.clusters.len())
+            .filter(|i| self.clusters[*i].is_some())
+            .collect();
+        order.sort_by_key(|i| self.covers[*i].len());
+        order
+    }
+
+    fn remove_redundant(&mut self) {
+        for cluster in self.order() {
+            if self.needed(&[cluster]).is_empty() {
+                self.apply(&[(cluster, None)]);
+            }
+        }
+    }
+
+    fn merge_pairs(&mut self) {
+        let (tree, ids) = self.cluster_tree();
+        for cluster in self.order() {
+            if self.timed_out() {
+                return;
+            }
+            for neighbor in self.neighbors(&tree, &ids, cluster) {
+                let needed = self.needed(&[cluster, neighbor]);
+                let merged = if needed.is_empty() {
+                    self.apply(&[(cluster, None), (neighbor, None)])
+                } else if let Some(center) = self.enclose(&needed) {
+                    self.apply(&[(cluster, Some(center)), (neighbor, None)])
+                } else {
+                    false
+                };
+                if merged {
+                    break;
+                }
+            }
+        }
+    }
+
+    fn shift_out(&mut self) {
+        let (tree, ids) = self.cluster_tree();
+        'cluster: for cluster in self.order() {
+            if self.timed_out() {
+                return;
+            }
+            if self.clusters[cluster].is_none() {
+                continue;
+            }
+            let needed = self.needed(&[cluster]);
+            let neighbors = self.neighbors(&tree, &ids, cluster);
+            // points each neighbor has to keep covering, plus the ones it takes over
+            let mut assigned: Vec<Vec<usize>> = neighbors
+                .iter()
+                .map(|neighbor| self.needed(&[*neighbor]))
+                .collect();
+            let mut centers: Vec<Option<PointArray>> = vec![None; neighbors.len()];
+
+            for point in needed {
+                let taken = neighbors.iter().enumerate().find_map(|(i, neighbor)| {
+                    let center = Point::new(self.radius, 20, self.clusters[*neighbor]?);
+                    if center.haversine_distance(&self.points[point]) > self.radius * 2. {
+                        return None;
+                    }
+                    let mut candidate = assigned[i].clone();
+                    candidate.push(point);
+                    self.enclose(&candidate).map(|center| (i, center))
+                });
+                match taken {
+                    Some((i, center)) => {
+                        assigned[i].push(point);
+                        centers[i] = Some(center);
+                    }
+                    None => continue 'cluster,
+                }
+            }
+            let mut changes = vec![(cluster, None)];
+            changes.extend(
+                neighbors
+                    .iter()
+                    .zip(centers)
+                    .filter(|(_, center)| center.is_some())
+                    .map(|(neighbor, center)| (*neighbor, center)),
+            );
+            self.apply(&changes);
+        }
+    }
+}
+
+/// Tries to remove clusters for up to `time` seconds without losing coverage of any data point,
+/// returns the remaining clusters and how many were removed
+pub fn main(
+    radius: Precision,
+    data_points: &SingleVec,
+    clusters: SingleVec,
+    time: u64,
+    context: &Context,
+) -> (SingleVec, usize) {
+    let time_start = Instant::now();
+    log::info!("starting local search on {} clusters", clusters.len());
+    let mut search = LocalSearch::new(radius, data_points, &clusters, time, context);
+
+    let mut rounds = 0;
+    loop {
+        let before = search.active();
+        rounds += 1;
+        search.remove_redundant();
+        search.merge_pairs();
+        search.shift_out();
+        if search.active() == before || search.timed_out() {
+            break;
+        }
+    }
+    let result: SingleVec = search.clusters.into_iter().flatten().collect();
+    let eliminated = clusters.len() - result.len();
+    log::info!(
+        "local search removed {} clusters in {} rounds, {:.2}s",
+        eliminated,
+        rounds,
+        time_start.elapsed().as_secs_f32()
+    );
+    (result, eliminated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn removes_redundant_and_merges() {
+        let points: SingleVec = vec![[40., -74.], [40., -73.9995], [40.01, -74.]];
+        let clusters: SingleVec = vec![
+            [40., -74.],
+            [40., -73.9995],
+            [40., -73.99975],
+            [40.01, -74.],
+        ];
+        let (result, eliminated) = main(70., &points, clusters, 5, &Context::default());
+
+        assert_eq!(eliminated, 2);
+        assert_eq!(result.len(), 2);
+        assert!(result.contains(&[40.01, -74.]));
+    }
+
+    #[test]
+    fn keeps_coverage() {
+        let points: SingleVec = (0..20)
+            .map(|i| {
+                [
+                    40. + (i % 4) as f64 * 0.0004,
+                    -74. + (i / 4) as f64 * 0.0004,
+                ]
+            })
+            .collect();
+        let (result, _) = main(70., &points, points.clone(), 5, &Context::default());
+        let tree = rtree::spawn(70., &result);
+
+        assert!(result.len() < points.len());
+        assert!(points
+            .iter()
+            .all(|point| tree.locate_at_point(point).is_some()));
+    }
+}
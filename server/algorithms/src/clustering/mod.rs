@@ -1,6 +1,7 @@
 use std::{time::Instant, vec};
 
 use crate::{
+    context::Context,
     plugin::{Folder, JoinFunction, Plugin},
     stats::Stats,
     utils,
@@ -11,14 +12,19 @@ use self::greedy::Greedy;
 use super::*;
 
 use geojson::FeatureCollection;
-use model::api::{calc_mode::CalculationMode, cluster_mode::ClusterMode, single_vec::SingleVec};
+use model::api::{
+    calc_mode::CalculationMode, cluster_mode::ClusterMode, single_vec::SingleVec, Precision,
+};
 
 mod fastest;
 mod greedy;
+mod local_search;
 mod s2;
+mod set_cover;
 
 pub fn main(
     data_points: &SingleVec,
+    weights: &[Precision],
     cluster_mode: ClusterMode,
     radius: f64,
     min_points: usize,
@@ -30,36 +36,57 @@ pub fn main(
     s2_size: u8,
     collection: FeatureCollection,
     clustering_args: &str,
+    clustering_time: u64,
+    local_search_time: u64,
     center_clusters: bool,
+    fixed_grid: bool,
+    context: &Context,
 ) -> SingleVec {
     if data_points.is_empty() {
         return vec![];
     }
+    if !weights.is_empty()
+        && (matches!(calculation_mode, CalculationMode::S2)
+            || matches!(
+                cluster_mode,
+                ClusterMode::Fastest | ClusterMode::Optimal | ClusterMode::Custom(_)
+            ))
+    {
+        log::warn!("weights are only used by the greedy cluster modes and will be ignored");
+    }
     let time = Instant::now();
+    let mut lower_bound = 0;
     let clusters = match calculation_mode {
         CalculationMode::S2 => collection
             .into_iter()
-            .flat_map(|feature| s2::cluster(feature, data_points, s2_level, s2_size))
+            .flat_map(|feature| s2::cluster(feature, data_points, s2_level, s2_size, context))
             .collect(),
         _ => match cluster_mode {
             ClusterMode::Fastest => {
-                let clusters = fastest::main(&data_points, radius, min_points);
+                let clusters = fastest::main(&data_points, radius, min_points, context);
                 clusters
             }
             ClusterMode::Honeycomb
             | ClusterMode::Balanced
             | ClusterMode::Fast
             | ClusterMode::Better
-            | ClusterMode::Best => {
+            | ClusterMode::Best
+            | ClusterMode::Optimal => {
                 let mut greedy = Greedy::default();
                 greedy
                     .set_cluster_mode(cluster_mode)
                     .set_cluster_split_level(cluster_split_level)
                     .set_max_clusters(max_clusters)
                     .set_min_points(min_points)
-                    .set_radius(radius);
+                    .set_radius(radius)
+                    .set_time_limit(clustering_time)
+                    .set_fixed_grid(fixed_grid)
+                    .set_context(context)
+                    .set_weights(data_points, weights);
 
-                greedy.run(&data_points)
+                let clusters = greedy.run(&data_points);
+                lower_bound = greedy.lower_bound();
+                clusters
             }
             ClusterMode::Custom(plugin) => {
                 match Plugin::new(
@@ -68,23 +95,45 @@ pub fn main(
                     cluster_split_level,
                     clustering_args,
                 ) {
-                    Ok(plugin_manager) => {
-                        match plugin_manager.run_multi::<JoinFunction>(data_points, None) {
+                    Ok(mut plugin_manager) => {
+                        let clusters = match plugin_manager
+                            .set_context(context)
+                            .set_option("radius", radius)
+                            .set_option("min_points", min_points)
+                            .set_option("max_clusters", max_clusters)
+                            .set_option("clustering_time", clustering_time)
+                            .set_option("area", &collection)
+                            .run_multi::<JoinFunction>(data_points, None)
+                        {
                             Ok(sorted_clusters) => sorted_clusters,
                             Err(e) => {
                                 log::error!("Error while running plugin: {}", e);
+                                if !e.is_cancelled() {
+                                    stats.plugin_errors.push(e.to_string());
+                                }
                                 vec![]
                             }
-                        }
+                        };
+                        plugin_manager.report_to(stats);
+                        clusters
                     }
                     Err(e) => {
                         log::error!("Plugin not found: {}", e);
+                        stats.plugin_errors.push(e.to_string());
                         vec![]
                     }
                 }
             }
         },
     };
+    let clusters = if local_search_time > 0 {
+        let (clusters, eliminated) =
+            local_search::main(radius, data_points, clusters, local_search_time, context);
+        stats.clusters_eliminated = eliminated;
+        clusters
+    } else {
+        clusters
+    };
     let clusters = if center_clusters {
         sec::with_data(radius, data_points, &clusters)
     } else {
@@ -92,6 +141,10 @@ pub fn main(
     };
     stats.set_cluster_time(time);
     stats.cluster_stats(radius, data_points, &clusters);
+    if !weights.is_empty() {
+        stats.weight_stats(radius, data_points, weights, &clusters);
+    }
+    stats.set_lower_bound(lower_bound);
     stats.set_score();
 
     clusters
@@ -109,5 +162,6 @@ pub fn all_clustering_options() -> Vec<String> {
     options.push("fast".to_string());
     options.push("better".to_string());
     options.push("best".to_string());
+    options.push("optimal".to_string());
     options
 }
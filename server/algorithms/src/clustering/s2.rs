@@ -5,10 +5,17 @@ use rayon::iter::{IntoParallelIterator, ParallelIterator};
 use s2::cellid::CellID;
 
 use crate::bootstrap;
+use crate::context::Context;
 use crate::s2::cell_coverage;
 
-pub fn cluster(feature: Feature, data: &SingleVec, level: u8, size: u8) -> SingleVec {
-    let bootstrap_cells = bootstrap::s2::BootstrapS2::new(&feature, level as u64, size);
+pub fn cluster(
+    feature: Feature,
+    data: &SingleVec,
+    level: u8,
+    size: u8,
+    context: &Context,
+) -> SingleVec {
+    let bootstrap_cells = bootstrap::s2::BootstrapS2::new(&feature, level as u64, size, context);
     let all_cells = bootstrap_cells.result();
 
     let valid_cells = data
@@ -0,0 +1,411 @@
+//! Exact set cover backing [ClusterMode::Optimal](model::api::cluster_mode::ClusterMode::Optimal)
+//!
+//! The elements are first split into independent components, sets never span two components so
+//! each one is solved on its own. A component is solved with a depth first branch and bound:
+//! branch on the uncovered element with the fewest remaining sets, excluding each set once its
+//! branch has been explored, and prune with a lower bound from elements that no single set can
+//! cover together. A lazy greedy cover gives the starting upper bound, so the best cover found so
+//! far is returned if the time limit is hit.
+use std::{cmp::Reverse, collections::BinaryHeap, time::Instant};
+
+use crate::context::Context;
+
+#[derive(Debug, Default)]
+pub struct Solution {
+    /// Indexes of the chosen sets
+    pub chosen: Vec<usize>,
+    /// No cover can use fewer sets than this
+    pub lower_bound: usize,
+    /// Whether every component was solved before the time limit
+    pub optimal: bool,
+}
+
+struct Component<'a> {
+    /// Elements of each set, as local element indexes
+    sets: Vec<Vec<usize>>,
+    /// Sets that contain each element, as local set indexes
+    element_sets: Vec<Vec<usize>>,
+    /// Global index of each local set
+    set_ids: Vec<usize>,
+    covered: Vec<u32>,
+    excluded: Vec<bool>,
+    /// Sets of each element that aren't excluded
+    options: Vec<usize>,
+    blocked: Vec<u32>,
+    stamp: u32,
+    best: Vec<usize>,
+    deadline: &'a Instant,
+    context: &'a Context,
+    timed_out: bool,
+}
+
+impl<'a> Component<'a> {
+    fn new(
+        sets: Vec<Vec<usize>>,
+        set_ids: Vec<usize>,
+        elements: usize,
+        deadline: &'a Instant,
+        context: &'a Context,
+    ) -> Self {
+        let mut element_sets = vec![vec![]; elements];
+        for (i, set) in sets.iter().enumerate() {
+            for element in set.iter() {
+                element_sets[*element].push(i);
+            }
+        }
+        Self {
+            excluded: vec![false; sets.len()],
+            options: element_sets.iter().map(|sets| sets.len()).collect(),
+            sets,
+            element_sets,
+            set_ids,
+            covered: vec![0; elements],
+            blocked: vec![0; elements],
+            stamp: 0,
+            best: vec![],
+            deadline,
+            context,
+            timed_out: false,
+        }
+    }
+
+    /// Sets `timed_out` once the deadline has passed or the context is cancelled
+    fn check_time(&mut self) -> bool {
+        if !self.timed_out && (Instant::now() > *self.deadline || self.context.is_cancelled()) {
+            self.timed_out = true;
+        }
+        self.timed_out
+    }
+
+    fn gain(&self, set: usize) -> usize {
+        self.sets[set]
+            .iter()
+            .filter(|element| self.covered[**element] == 0)
+            .count()
+    }
+
+    fn add(&mut self, set: usize) {
+        for element in self.sets[set].iter() {
+            self.covered[*element] += 1;
+        }
+    }
+
+    fn remove(&mut self, set: usize) {
+        for element in self.sets[set].iter() {
+            self.covered[*element] -= 1;
+        }
+    }
+
+    fn exclude(&mut self, set: usize) {
+        self.excluded[set] = true;
+        for element in self.sets[set].iter() {
+            self.options[*element] -= 1;
+        }
+    }
+
+    fn include(&mut self, set: usize) {
+        self.excluded[set] = false;
+        for element in self.sets[set].iter() {
+            self.options[*element] += 1;
+        }
+    }
+
+    /// Picks the set covering the most uncovered elements until everything is covered.
+    /// Gains only shrink, so a popped set whose gain is still current is the best pick.
+    /// Past the deadline, each remaining element is covered by its first set
+    fn greedy(&mut self) -> Vec<usize> {
+        let mut chosen = vec![];
+        let mut queue: BinaryHeap<(usize, Reverse<usize>)> = (0..self.sets.len())
+            .map(|set| (self.sets[set].len(), Reverse(set)))
+            .collect();
+        while let Some((gain, Reverse(set))) = queue.pop() {
+            if self.check_time() {
+                break;
+            }
+            let current = self.gain(set);
+            if current == 0 {
+                continue;
+            }
+            if current < gain {
+                queue.push((current, Reverse(set)));
+                continue;
+            }
+            self.add(set);
+            chosen.push(set);
+        }
+        if self.timed_out {
+            for element in 0..self.element_sets.len() {
+                if self.covered[element] == 0 {
+                    if let Some(set) = self.element_sets[element].first().copied() {
+                        self.add(set);
+                        chosen.push(set);
+                    }
+                }
+            }
+        }
+        chosen.iter().for_each(|set| self.remove(*set));
+        chosen
+    }
+
+    /// Counts uncovered elements that pairwise share no available set, each needs its own set
+    fn lower_bound(&mut self) -> usize {
+        self.stamp += 1;
+        let mut bound = 0;
+        for element in 0..self.element_sets.len() {
+            if self.covered[element] > 0 || self.blocked[element] == self.stamp {
+                continue;
+            }
+            bound += 1;
+            for set in self.element_sets[element].iter() {
+                if self.excluded[*set] {
+                    continue;
+                }
+                for other in self.sets[*set].iter() {
+                    self.blocked[*other] = self.stamp;
+                }
+            }
+        }
+        bound
+    }
+
+    /// Uncovered element with the fewest available sets, `None` when everything is covered
+    fn branch_element(&self) -> Option<(usize, usize)> {
+        let mut best: Option<(usize, usize)> = None;
+        for element in 0..self.options.len() {
+            if self.covered[element] > 0 {
+                continue;
+            }
+            let options = self.options[element];
+            if best.map_or(true, |(fewest, _)| options < fewest) {
+                best = Some((options, element));
+                if options <= 1 {
+                    break;
+                }
+            }
+        }
+        best
+    }
+
+    /// Sets to branch on at the current node, best first.
+    /// `None` when the node is a leaf, can be pruned, or the time is up
+    fn branches(&mut self, chosen: &[usize]) -> Option<Vec<usize>> {
+        if self.check_time() {
+            return None;
+        }
+        let Some((options, element)) = self.branch_element() else {
+            if chosen.len() < self.best.len() {
+                self.best = chosen.to_vec();
+            }
+            return None;
+        };
+        if options == 0 || chosen.len() + self.lower_bound() >= self.best.len() {
+            return None;
+        }
+        let mut branches: Vec<(usize, usize)> = self.element_sets[element]
+            .iter()
+            .filter(|set| !self.excluded[**set])
+            .map(|set| (self.gain(*set), *set))
+            .collect();
+        branches.sort_by(|a, b| b.cmp(a));
+        Some(branches.into_iter().map(|(_, set)| set).collect())
+    }
+
+    /// Depth first search with an explicit stack, each frame holds the sets of a node and the
+    /// next one to try. A set is excluded once its branch is done and included again when its
+    /// node is left
+    fn search(&mut self) {
+        let mut chosen: Vec<usize> = vec![];
+        let mut stack: Vec<(Vec<usize>, usize)> = vec![];
+        let mut expand = true;
+        loop {
+            if expand {
+                if let Some(branches) = self.branches(&chosen) {
+                    stack.push((branches, 0));
+                }
+            }
+            if self.timed_out {
+                break;
+            }
+            let Some((branches, next)) = stack.last_mut() else {
+                break;
+            };
+            if *next > 0 {
+                let done = branches[*next - 1];
+                chosen.pop();
+                self.remove(done);
+                self.exclude(done);
+            }
+            if *next == branches.len() {
+                if let Some((branches, _)) = stack.pop() {
+                    branches.into_iter().for_each(|set| self.include(set));
+                }
+                expand = false;
+                continue;
+            }
+            let set = branches[*next];
+            *next += 1;
+            self.add(set);
+            chosen.push(set);
+            expand = true;
+        }
+    }
+
+    /// Returns the chosen global set indexes, the lower bound, and whether it's optimal
+    fn solve(mut self) -> (Vec<usize>, usize, bool) {
+        self.best = self.greedy();
+        let root_bound = self.lower_bound();
+        if !self.timed_out && root_bound < self.best.len() {
+            self.search();
+        }
+        let optimal = !self.timed_out;
+        let lower_bound = if optimal { self.best.len() } else { root_bound };
+        let chosen = self.best.iter().map(|set| self.set_ids[*set]).collect();
+        (chosen, lower_bound, optimal)
+    }
+}
+
+fn find(parents: &mut [usize], element: usize) -> usize {
+    let mut root = element;
+    while parents[root] != root {
+        root = parents[root];
+    }
+    let mut current = element;
+    while parents[current] != root {
+        let next = parents[current];
+        parents[current] = root;
+        current = next;
+    }
+    root
+}
+
+/// Finds the fewest sets that cover every element that's in at least one set.
+/// `sets` holds the element indexes of each set, each below `elements`.
+/// The search stops at the deadline or when the context is cancelled
+pub fn solve(
+    sets: &[Vec<usize>],
+    elements: usize,
+    deadline: Instant,
+    context: &Context,
+) -> Solution {
+    let mut parents: Vec<usize> = (0..elements).collect();
+    for set in sets.iter() {
+        if let Some(first) = set.first() {
+            let root = find(&mut parents, *first);
+            for element in set.iter().skip(1) {
+                let other = find(&mut parents, *element);
+                parents[other] = root;
+            }
+        }
+    }
+
+    // component index of each root and the index of each element within its component
+    let mut component_of = vec![usize::MAX; elements];
+    let mut local = vec![usize::MAX; elements];
+    let mut components: Vec<(Vec<Vec<usize>>, Vec<usize>, usize)> = vec![];
+    for (id, set) in sets.iter().enumerate() {
+        let Some(first) = set.first() else {
+            continue;
+        };
+        let root = find(&mut parents, *first);
+        if component_of[root] == usize::MAX {
+            component_of[root] = components.len();
+            components.push((vec![], vec![], 0));
+        }
+        let component = &mut components[component_of[root]];
+        let local_set = set
+            .iter()
+            .map(|element| {
+                if local[*element] == usize::MAX {
+                    local[*element] = component.2;
+                    component.2 += 1;
+                }
+                local[*element]
+            })
+            .collect();
+        component.0.push(local_set);
+        component.1.push(id);
+    }
+    components.sort_by_key(|(sets, _, elements)| sets.len() * elements);
+    log::info!("split set cover into {} components", components.len());
+
+    let mut solution = Solution {
+        optimal: true,
+        ..Default::default()
+    };
+    for (component_sets, set_ids, component_elements) in components {
+        let (chosen, lower_bound, optimal) = Component::new(
+            component_sets,
+            set_ids,
+            component_elements,
+            &deadline,
+            context,
+        )
+        .solve();
+        solution.chosen.extend(chosen);
+        solution.lower_bound += lower_bound;
+        solution.optimal &= optimal;
+    }
+    solution
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn beats_greedy() {
+        // greedy takes the big middle set first and then needs both halves anyway
+        let sets = vec![vec![0, 1, 2, 3, 4, 5], vec![0, 1, 2, 6], vec![3, 4, 5, 7]];
+        let solution = solve(
+            &sets,
+            8,
+            Instant::now() + Duration::from_secs(5),
+            &Context::default(),
+        );
+        let mut chosen = solution.chosen.clone();
+        chosen.sort();
+
+        assert_eq!(chosen, vec![1, 2]);
+        assert_eq!(solution.lower_bound, 2);
+        assert!(solution.optimal);
+    }
+
+    #[test]
+    fn solves_components_separately() {
+        let sets = vec![
+            vec![0, 1],
+            vec![1, 2],
+            vec![0, 2],
+            vec![3],
+            vec![4, 5],
+            vec![5],
+        ];
+        let solution = solve(
+            &sets,
+            6,
+            Instant::now() + Duration::from_secs(5),
+            &Context::default(),
+        );
+
+        assert_eq!(solution.chosen.len(), 4);
+        assert_eq!(solution.lower_bound, 4);
+        assert!(solution.optimal);
+    }
+
+    #[test]
+    fn covers_everything_past_the_deadline() {
+        let sets = vec![vec![0, 1, 2, 3, 4, 5], vec![0, 1, 2, 6], vec![3, 4, 5, 7]];
+        let solution = solve(&sets, 8, Instant::now(), &Context::default());
+        let mut covered: Vec<usize> = solution
+            .chosen
+            .iter()
+            .flat_map(|set| sets[*set].iter().copied())
+            .collect();
+        covered.sort();
+        covered.dedup();
+
+        assert_eq!(covered, (0..8).collect::<Vec<_>>());
+        assert!(!solution.optimal);
+    }
+}
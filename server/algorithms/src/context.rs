@@ -0,0 +1,96 @@
+//! Shared state that lets the caller of a calculation follow its progress and stop it early
+use std::sync::{
+    atomic::{AtomicBool, AtomicUsize, Ordering},
+    Arc, OnceLock,
+};
+
+use serde::Serialize;
+use serde_json::Value;
+
+#[derive(Debug, Default)]
+struct State {
+    cancelled: AtomicBool,
+    done: AtomicUsize,
+    total: AtomicUsize,
+    args: OnceLock<Value>,
+}
+
+/// Handed to the algorithms by the caller, clones share the same state.
+/// Algorithms poll [Context::is_cancelled] between iterations and return what they have so far
+/// once it's set, so their result should be discarded after a cancellation
+#[derive(Debug, Clone, Default)]
+pub struct Context {
+    state: Arc<State>,
+}
+
+/// Cancels the context when dropped, e.g. when the request that started a calculation goes away
+pub struct CancelOnDrop(Context);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.cancel();
+    }
+}
+
+impl Context {
+    pub fn cancel(&self) {
+        self.state.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.state.cancelled.load(Ordering::Relaxed)
+    }
+
+    pub fn cancel_on_drop(&self) -> CancelOnDrop {
+        CancelOnDrop(self.clone())
+    }
+
+    /// Reports how far along the current step of the calculation is
+    pub fn report(&self, done: usize, total: usize) {
+        self.state.total.store(total, Ordering::Relaxed);
+        self.state.done.store(done, Ordering::Relaxed);
+    }
+
+    /// Fraction of the current step that is done, from 0 to 1
+    pub fn progress(&self) -> f64 {
+        let total = self.state.total.load(Ordering::Relaxed);
+        if total == 0 {
+            return 0.;
+        }
+        (self.state.done.load(Ordering::Relaxed) as f64 / total as f64).min(1.)
+    }
+
+    /// Sets the args the calculation was started with, only the first call has an effect
+    pub fn set_args(&self, args: impl Serialize) {
+        match serde_json::to_value(args) {
+            Ok(args) => {
+                self.state.args.set(args).ok();
+            }
+            Err(err) => log::warn!("unable to serialize the args of the calculation: {}", err),
+        }
+    }
+
+    /// The args the calculation was started with, sent to plugins using the JSON protocol
+    pub fn args(&self) -> Option<&Value> {
+        self.state.args.get()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clones_share_state() {
+        let context = Context::default();
+        let clone = context.clone();
+        clone.report(1, 4);
+        assert_eq!(context.progress(), 0.25);
+
+        {
+            let _guard = clone.cancel_on_drop();
+            assert!(!context.is_cancelled());
+        }
+        assert!(context.is_cancelled());
+    }
+}
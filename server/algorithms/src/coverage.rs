@@ -0,0 +1,54 @@
+//! Finds the holes in an existing route's coverage
+use model::api::{point_array::PointArray, single_vec::SingleVec, Precision};
+use rayon::prelude::{IntoParallelRefIterator, ParallelIterator};
+
+use crate::rtree;
+
+#[derive(Debug, Default)]
+pub struct CoverageGaps {
+    /// Data points that are not covered by any cluster
+    pub uncovered: SingleVec,
+    /// Clusters that cover fewer than `min_points` data points, with the number they do cover
+    pub clusters: Vec<(PointArray, usize)>,
+}
+
+pub fn gaps(
+    radius: Precision,
+    points: &SingleVec,
+    clusters: &SingleVec,
+    min_points: usize,
+) -> CoverageGaps {
+    let point_tree = rtree::spawn(radius, points);
+    let cluster_tree = rtree::spawn(radius, clusters);
+
+    let uncovered = points
+        .par_iter()
+        .filter(|point| cluster_tree.locate_at_point(point).is_none())
+        .copied()
+        .collect();
+    let clusters = clusters
+        .par_iter()
+        .map(|cluster| (*cluster, point_tree.locate_all_at_point(cluster).count()))
+        .filter(|(_, count)| *count < min_points)
+        .collect();
+
+    CoverageGaps {
+        uncovered,
+        clusters,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_uncovered_points_and_weak_clusters() {
+        let clusters: SingleVec = vec![[40., -74.], [40., -73.99]];
+        let points: SingleVec = vec![[40., -74.], [40.0001, -74.], [40., -73.99], [40.01, -74.]];
+        let gaps = gaps(70., &points, &clusters, 2);
+
+        assert_eq!(gaps.uncovered, vec![[40.01, -74.]]);
+        assert_eq!(gaps.clusters, vec![([40., -73.99], 1)]);
+    }
+}
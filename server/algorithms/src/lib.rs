@@ -2,6 +2,9 @@ use model;
 
 pub mod bootstrap;
 pub mod clustering;
+pub mod context;
+pub mod coverage;
+pub mod partition;
 mod plugin;
 mod project;
 pub mod routing;
@@ -10,3 +13,5 @@ pub mod s2;
 mod sec;
 pub mod stats;
 pub mod utils;
+
+pub use plugin::manifest;
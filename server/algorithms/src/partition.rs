@@ -0,0 +1,75 @@
+//! Splits points between the areas of a collection, so each point belongs to exactly one area
+use geo::{Centroid, Contains, HaversineDistance, Point};
+use geojson::FeatureCollection;
+use model::api::{single_vec::SingleVec, Precision};
+use rayon::prelude::{IntoParallelRefIterator, ParallelIterator};
+
+/// Index of the area that each point belongs to. A point belongs to the first area that contains
+/// it, or the area with the nearest centroid if none do. `None` when no area has a valid geometry
+pub fn assign(points: &SingleVec, areas: &FeatureCollection) -> Vec<Option<usize>> {
+    let geometries: Vec<Option<(geo::Geometry<Precision>, Point)>> = areas
+        .features
+        .iter()
+        .map(|feature| {
+            let geometry = geo::Geometry::<Precision>::try_from(feature.geometry.clone()?).ok()?;
+            let centroid = geometry.centroid()?;
+            Some((geometry, centroid))
+        })
+        .collect();
+
+    points
+        .par_iter()
+        .map(|point| {
+            let point = Point::new(point[1], point[0]);
+            geometries
+                .iter()
+                .position(|area| matches!(area, Some((geometry, _)) if geometry.contains(&point)))
+                .or_else(|| {
+                    geometries
+                        .iter()
+                        .enumerate()
+                        .filter_map(|(i, area)| {
+                            area.as_ref()
+                                .map(|(_, centroid)| (i, centroid.haversine_distance(&point)))
+                        })
+                        .min_by(|a, b| a.1.total_cmp(&b.1))
+                        .map(|(i, _)| i)
+                })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use geojson::{Feature, Geometry, Value};
+
+    fn square(min_lon: f64, min_lat: f64) -> Feature {
+        let ring = vec![
+            vec![min_lon, min_lat],
+            vec![min_lon + 1., min_lat],
+            vec![min_lon + 1., min_lat + 1.],
+            vec![min_lon, min_lat + 1.],
+            vec![min_lon, min_lat],
+        ];
+        Feature {
+            geometry: Some(Geometry::new(Value::Polygon(vec![ring]))),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn assigns_by_containment_then_centroid() {
+        let areas = FeatureCollection {
+            features: vec![square(0., 0.), square(1., 0.)],
+            bbox: None,
+            foreign_members: None,
+        };
+        let points: SingleVec = vec![[0.5, 0.5], [0.5, 1.5], [0.5, 2.2], [-0.5, 0.1]];
+
+        assert_eq!(
+            assign(&points, &areas),
+            vec![Some(0), Some(1), Some(1), Some(0)]
+        );
+    }
+}
@@ -0,0 +1,120 @@
+use std::{
+    fmt::{self, Display},
+    io,
+    process::ExitStatus,
+    time::Duration,
+};
+
+#[derive(Debug)]
+pub enum PluginError {
+    /// The plugin, its entry point, or its interpreter couldn't be found
+    NotFound(String),
+    /// The args were rejected by the manifest of the plugin
+    InvalidArgs {
+        plugin: String,
+        message: String,
+    },
+    /// The child process couldn't be started or talked to
+    Io {
+        plugin: String,
+        source: io::Error,
+    },
+    Cancelled {
+        plugin: String,
+    },
+    Timeout {
+        plugin: String,
+        after: Duration,
+        stderr: String,
+    },
+    Exit {
+        plugin: String,
+        status: ExitStatus,
+        stderr: String,
+    },
+    MalformedOutput {
+        plugin: String,
+        message: String,
+        stderr: String,
+    },
+    /// The plugin set the `error` of its JSON output
+    Reported {
+        plugin: String,
+        message: String,
+    },
+}
+
+impl PluginError {
+    pub fn io(plugin: &str, source: io::Error) -> Self {
+        Self::Io {
+            plugin: plugin.to_string(),
+            source,
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        matches!(self, Self::Cancelled { .. })
+    }
+}
+
+fn with_stderr(f: &mut fmt::Formatter<'_>, stderr: &str) -> fmt::Result {
+    if stderr.is_empty() {
+        Ok(())
+    } else {
+        write!(f, "\nstderr:\n{}", stderr)
+    }
+}
+
+impl Display for PluginError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotFound(message) => write!(f, "{}", message),
+            Self::InvalidArgs { plugin, message } => {
+                write!(f, "invalid args for {}: {}", plugin, message)
+            }
+            Self::Io { plugin, source } => write!(f, "{} child process failed: {}", plugin, source),
+            Self::Cancelled { plugin } => write!(f, "{} child process was cancelled", plugin),
+            Self::Timeout {
+                plugin,
+                after,
+                stderr,
+            } => {
+                write!(
+                    f,
+                    "{} child process timed out after {}s",
+                    plugin,
+                    after.as_secs()
+                )?;
+                with_stderr(f, stderr)
+            }
+            Self::Exit {
+                plugin,
+                status,
+                stderr,
+            } => {
+                write!(f, "{} child process exited with {}", plugin, status)?;
+                with_stderr(f, stderr)
+            }
+            Self::MalformedOutput {
+                plugin,
+                message,
+                stderr,
+            } => {
+                write!(f, "malformed output from {}: {}", plugin, message)?;
+                with_stderr(f, stderr)
+            }
+            Self::Reported { plugin, message } => {
+                write!(f, "{} returned an error: {}", plugin, message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for PluginError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
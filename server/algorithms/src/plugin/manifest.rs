@@ -0,0 +1,353 @@
+//! Manifests that describe a plugin and the args it accepts
+//!
+//! A single file plugin is described by a `<plugin>.koji.json` file next to it and a directory
+//! plugin by a `koji.json` file inside of it. Manifests are parsed once, on startup, and plugins
+//! without one keep working as before, their args just aren't validated.
+use std::{collections::HashSet, fs, path::Path, sync::OnceLock};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::utils;
+
+use super::{protocol, Folder, Plugin};
+
+/// Suffix of the manifest of a single file plugin
+pub const SUFFIX: &str = ".koji.json";
+
+/// Name of the manifest inside of a directory plugin
+pub const DIR_FILE: &str = "koji.json";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ArgKind {
+    #[default]
+    String,
+    Integer,
+    Float,
+    Boolean,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ArgSpec {
+    /// Name of the arg without the leading `--`
+    pub name: String,
+    #[serde(rename = "type", default)]
+    pub kind: ArgKind,
+    /// Passed to the plugin when the arg isn't provided
+    #[serde(default)]
+    pub default: Option<Value>,
+    #[serde(default)]
+    pub required: bool,
+    #[serde(default)]
+    pub description: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Manifest {
+    /// Name of the plugin in the plugin lists, e.g. `tsp` or `plugin_1.py`
+    #[serde(skip_deserializing)]
+    pub plugin: String,
+    /// `clustering`, `routing`, or `bootstrap`
+    #[serde(skip_deserializing)]
+    pub folder: String,
+    /// Display name, defaults to the plugin name
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    /// Used instead of guessing the interpreter from the file extension
+    #[serde(default)]
+    pub interpreter: Option<String>,
+    /// Entry point of a directory plugin, relative to the directory
+    #[serde(default)]
+    pub entry: Option<String>,
+    /// Every arg the plugin accepts
+    #[serde(default)]
+    pub args: Vec<ArgSpec>,
+    /// `0` for the text protocol, otherwise the version of the JSON protocol
+    #[serde(default)]
+    pub protocol: u8,
+    /// Number of long running processes to keep, `0` spawns a process for every run
+    #[serde(default)]
+    pub workers: usize,
+    /// Seconds the plugin is given before it's killed, defaults to `PLUGIN_TIMEOUT`
+    #[serde(default)]
+    pub timeout: Option<u64>,
+    /// Megabytes of memory the plugin can use, Linux only
+    #[serde(default)]
+    pub memory_limit: Option<u64>,
+    /// Seconds of CPU time the plugin can use, Linux only. Can't be combined with `workers`, the
+    /// CPU time of a worker adds up over all of its runs, `timeout` limits each run instead
+    #[serde(default)]
+    pub cpu_limit: Option<u64>,
+}
+
+impl Manifest {
+    fn read(path: &Path, folder: &Folder, plugin: &str) -> Result<Self, String> {
+        let file = fs::read_to_string(path).map_err(|err| err.to_string())?;
+        let mut manifest: Manifest = serde_json::from_str(&file).map_err(|err| err.to_string())?;
+        manifest.check()?;
+        manifest.plugin = plugin.to_string();
+        manifest.folder = folder.to_string();
+        if manifest.name.is_empty() {
+            manifest.name = plugin.to_string();
+        }
+        Ok(manifest)
+    }
+
+    fn check(&self) -> Result<(), String> {
+        if self.protocol > protocol::VERSION {
+            return Err(format!(
+                "unsupported protocol version {}, expected 0 to {}",
+                self.protocol,
+                protocol::VERSION
+            ));
+        }
+        if self.workers > 0 && self.cpu_limit.is_some() {
+            return Err(
+                "`cpu_limit` can't be combined with `workers`, use `timeout` instead".to_string(),
+            );
+        }
+        Ok(())
+    }
+
+    /// Args that Kōji adds to every run of the plugin, they're accepted whether they're listed in
+    /// the manifest or not and may be repeated, the plugin gets both the user's and Kōji's
+    pub fn reserved_args(&self) -> &'static [&'static str] {
+        match self.folder.parse::<Folder>() {
+            Ok(Folder::Clustering) => &["radius", "min_points", "max_clusters"],
+            Ok(Folder::Bootstrap) => &["radius"],
+            _ => &[],
+        }
+    }
+
+    /// Checks the args against the ones in the manifest and adds the defaults of the missing ones
+    pub fn resolve_args(&self, args: &[String]) -> Result<Vec<String>, String> {
+        let mut resolved = vec![];
+        let mut seen = HashSet::new();
+        let mut iter = args.iter().peekable();
+
+        while let Some(arg) = iter.next() {
+            let name = arg
+                .strip_prefix("--")
+                .ok_or_else(|| format!("unexpected value `{}`, args must start with `--`", arg))?;
+            let reserved = self.reserved_args().contains(&name);
+            let spec = self.args.iter().find(|spec| spec.name == name);
+            if spec.is_none() && !reserved {
+                return Err(format!("unknown arg `{}` for {}", arg, self.name));
+            }
+            if !seen.insert(name) && !reserved {
+                return Err(format!("`{}` was provided more than once", arg));
+            }
+            let value = iter.next_if(|value| !value.starts_with("--"));
+            resolved.push(arg.clone());
+            match (spec.map(|spec| spec.kind), value) {
+                (Some(ArgKind::Boolean), None) => {}
+                (_, Some(value)) => {
+                    if let Some(spec) = spec {
+                        spec.check(value)?;
+                    }
+                    resolved.push(value.clone());
+                }
+                (_, None) => return Err(format!("`{}` is missing a value", arg)),
+            }
+        }
+        for spec in self
+            .args
+            .iter()
+            .filter(|spec| !seen.contains(spec.name.as_str()))
+        {
+            match spec.default.as_ref() {
+                Some(Value::Bool(false)) | Some(Value::Null) => {}
+                Some(Value::Bool(true)) if spec.kind == ArgKind::Boolean => {
+                    resolved.push(format!("--{}", spec.name))
+                }
+                Some(Value::String(value)) => {
+                    resolved.push(format!("--{}", spec.name));
+                    resolved.push(value.clone());
+                }
+                Some(value) => {
+                    resolved.push(format!("--{}", spec.name));
+                    resolved.push(value.to_string());
+                }
+                None if spec.required => {
+                    return Err(format!("`--{}` is required by {}", spec.name, self.name))
+                }
+                None => {}
+            }
+        }
+        Ok(resolved)
+    }
+}
+
+impl ArgSpec {
+    fn check(&self, value: &str) -> Result<(), String> {
+        let valid = match self.kind {
+            ArgKind::String => true,
+            // wide enough for any `u64` or `i64`, e.g. an unbounded `max_clusters`
+            ArgKind::Integer => value.parse::<i128>().is_ok(),
+            ArgKind::Float => value.parse::<f64>().is_ok(),
+            ArgKind::Boolean => value.parse::<bool>().is_ok(),
+        };
+        if valid {
+            Ok(())
+        } else {
+            let expected = match self.kind {
+                ArgKind::String => "a string",
+                ArgKind::Integer => "an integer",
+                ArgKind::Float => "a float",
+                ArgKind::Boolean => "a boolean",
+            };
+            Err(format!(
+                "`--{}` expects {}, received `{}`",
+                self.name, expected, value
+            ))
+        }
+    }
+}
+
+/// Path of the manifest of the plugin, whether it exists or not
+fn manifest_path(folder: &Folder, plugin: &str) -> String {
+    let plugin_path = format!("algorithms/src/{folder}/plugins/{plugin}");
+    if Path::new(&plugin_path).is_dir() {
+        format!("{plugin_path}/{DIR_FILE}")
+    } else {
+        format!("{plugin_path}{SUFFIX}")
+    }
+}
+
+fn load() -> Vec<Manifest> {
+    let mut manifests = vec![];
+    for folder in [Folder::Clustering, Folder::Routing, Folder::Bootstrap] {
+        let plugins =
+            utils::get_plugin_list(&format!("algorithms/src/{folder}/plugins")).unwrap_or(vec![]);
+        for plugin in plugins {
+            let path = manifest_path(&folder, &plugin);
+            let path = Path::new(&path);
+            if !path.exists() {
+                continue;
+            }
+            match Manifest::read(path, &folder, &plugin) {
+                Ok(manifest) => manifests.push(manifest),
+                Err(err) => log::error!(
+                    "[PLUGINS] Invalid manifest {}, ignoring it: {}",
+                    path.display(),
+                    err
+                ),
+            }
+        }
+    }
+    manifests
+}
+
+/// Every valid manifest, they're parsed on the first call
+pub fn all() -> &'static [Manifest] {
+    static MANIFESTS: OnceLock<Vec<Manifest>> = OnceLock::new();
+    MANIFESTS.get_or_init(|| {
+        let manifests = load();
+        log::info!("[PLUGINS] Loaded {} plugin manifests", manifests.len());
+        manifests
+    })
+}
+
+pub fn find(folder: &Folder, plugin: &str) -> Option<&'static Manifest> {
+    let folder = folder.to_string();
+    all()
+        .iter()
+        .find(|manifest| manifest.folder == folder && manifest.plugin == plugin)
+}
+
+/// Checks that the plugin exists and validates its args the same way they're validated before
+/// it's spawned, returns the args that would be passed to it
+pub fn validate(folder: &str, plugin: &str, args: &str) -> Result<Vec<String>, String> {
+    let folder = folder.parse::<Folder>()?;
+    Plugin::new(plugin, folder, 0, args)
+        .map(|plugin| plugin.args().to_vec())
+        .map_err(|err| err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use model::api::args::{Args, ArgsUnwrapped};
+
+    fn manifest() -> Manifest {
+        serde_json::from_str(
+            r#"{
+                "name": "test",
+                "args": [
+                    { "name": "iterations", "type": "integer", "default": 10 },
+                    { "name": "mode", "required": true },
+                    { "name": "verbose", "type": "boolean" }
+                ]
+            }"#,
+        )
+        .unwrap()
+    }
+
+    fn args(args: &str) -> Vec<String> {
+        args.split_whitespace().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn adds_defaults() {
+        assert_eq!(
+            manifest().resolve_args(&args("--mode fast --verbose")),
+            Ok(args("--mode fast --verbose --iterations 10"))
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_args() {
+        let manifest = manifest();
+        assert!(manifest.resolve_args(&args("--iterations 5")).is_err());
+        assert!(manifest
+            .resolve_args(&args("--mode fast --iterations five"))
+            .is_err());
+        assert!(manifest
+            .resolve_args(&args("--mode fast --unknown 1"))
+            .is_err());
+        assert!(manifest.resolve_args(&args("--mode")).is_err());
+    }
+
+    #[test]
+    fn accepts_injected_args() {
+        let mut manifest = manifest();
+        assert!(manifest
+            .resolve_args(&args("--mode fast --radius 70"))
+            .is_err());
+
+        manifest.folder = Folder::Clustering.to_string();
+        manifest.args.push(ArgSpec {
+            name: "max_clusters".to_string(),
+            kind: ArgKind::Integer,
+            default: None,
+            required: false,
+            description: None,
+        });
+        let request: Args =
+            serde_json::from_value(serde_json::json!({ "clustering_args": "--mode fast" }))
+                .unwrap();
+        let ArgsUnwrapped {
+            clustering_args, ..
+        } = request.init(Some("fast"));
+        assert!(clustering_args.contains(&format!("--max_clusters {}", usize::MAX)));
+        let resolved = manifest.resolve_args(&args(&clustering_args)).unwrap();
+        assert!(resolved.contains(&"--min_points".to_string()));
+
+        manifest.folder = Folder::Bootstrap.to_string();
+        assert!(manifest
+            .resolve_args(&args("--mode fast --min_points 3"))
+            .is_err());
+    }
+
+    #[test]
+    fn rejects_cpu_limit_of_workers() {
+        let mut manifest = manifest();
+        manifest.cpu_limit = Some(10);
+        assert!(manifest.check().is_ok());
+        manifest.workers = 2;
+        assert!(manifest.check().is_err());
+    }
+}
@@ -0,0 +1,708 @@
+use std::fmt::Display;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::str::FromStr;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Mutex,
+};
+use std::time::{Duration, Instant};
+
+use crate::context::Context;
+use crate::s2::create_cell_index_map;
+use crate::stats::{PointProperties, Stats};
+use crate::utils;
+use model::api::single_vec::SingleVec;
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+use self::error::PluginError;
+use self::protocol::{Input, Output, Protocol};
+use self::sandbox::{Limits, StderrCapture};
+
+pub mod error;
+pub mod manifest;
+pub mod protocol;
+pub mod sandbox;
+mod worker;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Folder {
+    Routing,
+    Clustering,
+    Bootstrap,
+}
+
+impl Display for Folder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Folder::Routing => write!(f, "routing"),
+            Folder::Clustering => write!(f, "clustering"),
+            Folder::Bootstrap => write!(f, "bootstrap"),
+        }
+    }
+}
+
+impl FromStr for Folder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "routing" => Ok(Folder::Routing),
+            "clustering" => Ok(Folder::Clustering),
+            "bootstrap" => Ok(Folder::Bootstrap),
+            _ => Err(format!(
+                "unknown plugin folder `{s}`, expected `clustering`, `routing`, or `bootstrap`"
+            )),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Plugin {
+    plugin_path: String,
+    interpreter: String,
+    args: Vec<String>,
+    context: Context,
+    folder: Folder,
+    protocol: Protocol,
+    options: Map<String, Value>,
+    report: Mutex<Report>,
+    limits: Limits,
+    /// Size of the worker pool, `0` when a process is spawned for every run
+    workers: usize,
+    pub plugin: String,
+    pub split_level: u64,
+}
+
+/// Warnings, stats, properties, and routes returned by plugins using the JSON protocol
+#[derive(Debug, Default)]
+struct Report {
+    warnings: Vec<String>,
+    stats: Vec<Value>,
+    properties: Vec<PointProperties>,
+    /// Routes of every run, a run that didn't split its points adds them as a single route
+    routes: Vec<SingleVec>,
+    /// Whether any run split its points into routes, `routes` is only reported then
+    split: bool,
+}
+
+pub type JoinFunction = fn(&Plugin, Vec<SingleVec>) -> SingleVec;
+
+trait ParseCoord {
+    fn parse_next_coord(&mut self) -> Option<f64>;
+}
+
+impl ParseCoord for std::str::Split<'_, &str> {
+    fn parse_next_coord(&mut self) -> Option<f64> {
+        if let Some(coord) = self.next() {
+            if coord.contains(" ") {
+                return coord.split(",").parse_next_coord();
+            }
+            if let Ok(coord) = coord.parse::<f64>() {
+                return Some(coord);
+            }
+        }
+        None
+    }
+}
+
+impl Plugin {
+    pub fn new(
+        plugin: &str,
+        folder: Folder,
+        route_split_level: u64,
+        input_args: &str,
+    ) -> Result<Self, PluginError> {
+        let mut plugin_path = format!("algorithms/src/{folder}/plugins/{plugin}");
+        if !Path::new(&plugin_path).exists() {
+            return Err(PluginError::NotFound(format!(
+                "plugin {plugin} does not exist"
+            )));
+        }
+        let mut interpreter = match plugin.split(".").last() {
+            Some("py") => "python3",
+            Some("js") => "node",
+            Some("sh") => "bash",
+            Some("ts") => "ts-node",
+            val => {
+                if plugin == val.unwrap_or("") {
+                    &plugin_path
+                } else {
+                    ""
+                }
+            }
+        }
+        .to_string();
+        let manifest = manifest::find(&folder, plugin);
+        let workers = manifest.map_or(0, |manifest| manifest.workers);
+        let mut protocol = Protocol::Text;
+        if let Some(manifest) = manifest {
+            if let Some(entry) = manifest.entry.as_ref() {
+                if Path::new(&plugin_path).is_dir() {
+                    let entry_path = format!("{plugin_path}/{entry}");
+                    if interpreter == plugin_path {
+                        interpreter = entry_path.clone();
+                    }
+                    plugin_path = entry_path;
+                }
+            }
+            if let Some(manifest_interpreter) = manifest.interpreter.as_ref() {
+                interpreter = manifest_interpreter.clone();
+            }
+            // workers always speak the JSON protocol, one document per line
+            if manifest.protocol > 0 || manifest.workers > 0 {
+                protocol = Protocol::Json;
+            }
+        }
+        let args = input_args
+            .split_whitespace()
+            .skip_while(|arg| !arg.starts_with("--"))
+            .filter(|arg| {
+                if *arg == protocol::FLAG {
+                    protocol = Protocol::Json;
+                    false
+                } else {
+                    true
+                }
+            })
+            .map(|arg| arg.to_string())
+            .collect::<Vec<String>>();
+        let args = match manifest {
+            Some(manifest) => {
+                manifest
+                    .resolve_args(&args)
+                    .map_err(|message| PluginError::InvalidArgs {
+                        plugin: plugin.to_string(),
+                        message,
+                    })?
+            }
+            None => args,
+        };
+
+        for (index, pre_arg) in input_args
+            .split_whitespace()
+            .take_while(|arg| !arg.starts_with("--"))
+            .enumerate()
+        {
+            log::info!("[PLUGIN PARSER] {index} | pre_arg: {}", pre_arg);
+            if index == 0 {
+                interpreter = pre_arg.to_string();
+            } else if index == 1 {
+                plugin_path = format!("algorithms/src/{folder}/plugins/{pre_arg}");
+            } else {
+                log::warn!("Unrecognized argument: {pre_arg} for plugin: {plugin}")
+            }
+        }
+
+        if interpreter.is_empty() {
+            return Err(PluginError::NotFound(
+                "Unrecognized plugin, please create a PR to add support for it".to_string(),
+            ));
+        };
+        let path = Path::new(&plugin_path);
+        if path.is_dir() {
+            return Err(PluginError::NotFound(
+                format!("{plugin} is a directory, not a file, something may not be right with the provided args"),
+            ));
+        }
+        if path.exists() {
+            plugin_path = path.display().to_string();
+            if interpreter == plugin_path {
+                log::info!("{interpreter} {}", args.join(" "));
+            } else {
+                log::info!("{interpreter} {plugin_path} {}", args.join(" "));
+            }
+        } else {
+            return Err(PluginError::NotFound(format!(
+                "{plugin} does not exist{}",
+                if plugin == "tsp" {
+                    ", rerun the OR Tools Script"
+                } else {
+                    ""
+                }
+            )));
+        }
+
+        Ok(Plugin {
+            plugin: plugin.to_string(),
+            plugin_path,
+            interpreter,
+            split_level: route_split_level,
+            args,
+            context: Context::default(),
+            folder,
+            protocol,
+            options: Map::new(),
+            report: Mutex::new(Report::default()),
+            limits: Limits::new(manifest),
+            workers,
+        })
+    }
+
+    /// Args that are passed to the plugin, after the defaults of its manifest were added
+    pub fn args(&self) -> &[String] {
+        &self.args
+    }
+
+    /// Command that starts the plugin, with its stdio piped
+    fn command(&self) -> Command {
+        let mut command = Command::new(&self.interpreter);
+        if self.plugin_path != self.interpreter {
+            command.arg(&self.plugin_path);
+        };
+        if self.protocol == Protocol::Json {
+            command.env("KOJI_PROTOCOL_VERSION", protocol::VERSION.to_string());
+        }
+        if self.workers > 0 {
+            command.env("KOJI_WORKER", "1");
+        }
+        self.limits.apply(&mut command, &self.plugin);
+        // workers outlive the run they were started for, they get the args with every input
+        if self.workers == 0 {
+            command.args(self.args.iter());
+        }
+        command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        command
+    }
+
+    /// Sent to plugins using the JSON protocol along with the points
+    pub fn set_option(&mut self, key: &str, value: impl Serialize) -> &mut Self {
+        match serde_json::to_value(value) {
+            Ok(value) => {
+                self.options.insert(key.to_string(), value);
+            }
+            Err(err) => log::warn!("unable to send {} to {}: {}", key, self.plugin, err),
+        }
+        self
+    }
+
+    /// Adds the warnings, stats, properties, and routes returned by the plugin so far
+    pub fn report_to(&self, stats: &mut Stats) {
+        if let Ok(mut report) = self.report.lock() {
+            stats.plugin_warnings.append(&mut report.warnings);
+            stats.plugin_stats.append(&mut report.stats);
+            stats.plugin_properties.append(&mut report.properties);
+            // other plugins return a single list of points, their routes are joined
+            if report.split && self.folder == Folder::Routing {
+                stats.plugin_routes.append(&mut report.routes);
+            }
+            report.routes.clear();
+            report.split = false;
+        }
+    }
+
+    /// Whether a run so far split its points into routes
+    pub(crate) fn split_routes(&self) -> bool {
+        self.report.lock().map_or(false, |report| report.split)
+    }
+
+    /// Calls `f` without keeping the routes of the runs it makes, e.g. to join the cells
+    pub(crate) fn without_routes<T>(&self, f: impl FnOnce() -> T) -> T {
+        let saved = self
+            .report
+            .lock()
+            .map(|mut report| (std::mem::take(&mut report.routes), report.split))
+            .ok();
+        let result = f();
+        if let (Some((routes, split)), Ok(mut report)) = (saved, self.report.lock()) {
+            report.routes = routes;
+            report.split = split;
+        }
+        result
+    }
+
+    /// The child processes are killed when the context is cancelled
+    pub fn set_context(&mut self, context: &Context) -> &mut Self {
+        self.context = context.clone();
+        self
+    }
+
+    /// Records a warning that is added to the stats along with the ones of the JSON output
+    pub fn warn(&self, warning: String) {
+        if let Ok(mut report) = self.report.lock() {
+            report.warnings.push(warning);
+        }
+    }
+
+    /// Kills the child process if the context is cancelled or the timeout passes before
+    /// `finished` is set, the thread returns whether it timed out
+    fn watch(
+        &self,
+        child: Arc<Mutex<Child>>,
+        finished: Arc<AtomicBool>,
+    ) -> std::thread::JoinHandle<bool> {
+        let context = self.context.clone();
+        let plugin = self.plugin.clone();
+        let deadline = self.limits.timeout.map(|timeout| Instant::now() + timeout);
+        std::thread::spawn(move || {
+            while !finished.load(Ordering::Relaxed) {
+                let timed_out = deadline.map_or(false, |deadline| Instant::now() >= deadline);
+                if context.is_cancelled() || timed_out {
+                    let killed = child
+                        .lock()
+                        .map_err(|err| err.to_string())
+                        .and_then(|mut child| child.kill().map_err(|err| err.to_string()));
+                    match killed {
+                        Ok(_) => log::info!("killed {} child process", plugin),
+                        Err(err) => log::error!("failed to kill {} child process: {}", plugin, err),
+                    }
+                    return timed_out;
+                }
+                std::thread::sleep(Duration::from_millis(100));
+            }
+            false
+        })
+    }
+
+    /// Runs the plugin once per S2 cell when `split_level` is set, cells that fail keep their
+    /// input points in their original order and are added to the warnings, unless they all fail
+    pub fn run_multi<T>(
+        &self,
+        points: &SingleVec,
+        joiner: Option<T>,
+    ) -> Result<SingleVec, PluginError>
+    where
+        T: Fn(&Self, Vec<SingleVec>) -> SingleVec,
+    {
+        let handlers = if self.split_level == 0 {
+            vec![self.run(points)?]
+        } else {
+            let results = create_cell_index_map(points, self.split_level)
+                .into_values()
+                .collect::<Vec<Vec<usize>>>()
+                .into_par_iter()
+                .map(|ids| {
+                    let cell: SingleVec = ids.iter().map(|id| points[*id]).collect();
+                    let result = self.run_with_ids(&cell, &ids);
+                    (cell, result)
+                })
+                .collect::<Vec<_>>();
+            let mut handlers = vec![];
+            let mut failed = vec![];
+            for (cell, result) in results {
+                match result {
+                    Ok(points) => handlers.push(points),
+                    Err(err) if err.is_cancelled() => return Err(err),
+                    Err(err) => failed.push((cell, err)),
+                }
+            }
+            if handlers.is_empty() {
+                if let Some((_, err)) = failed.into_iter().next() {
+                    return Err(err);
+                }
+            } else {
+                for (cell, err) in failed {
+                    log::error!("Error while running plugin on a cell: {}", err);
+                    self.warn(format!(
+                        "a cell kept its {} points in their original order: {}",
+                        cell.len(),
+                        err
+                    ));
+                    if let Ok(mut report) = self.report.lock() {
+                        report.routes.push(cell.clone());
+                    }
+                    handlers.push(cell);
+                }
+            }
+            handlers
+        };
+        if let Some(joiner) = joiner {
+            Ok(joiner(self, handlers))
+        } else {
+            Ok(handlers.into_iter().flatten().collect())
+        }
+    }
+
+    pub fn run(&self, points: &SingleVec) -> Result<SingleVec, PluginError> {
+        let ids: Vec<usize> = (0..points.len()).collect();
+        self.run_with_ids(points, &ids)
+    }
+
+    /// `ids` are sent to plugins using the JSON protocol, one for each point
+    fn run_with_ids(&self, points: &SingleVec, ids: &[usize]) -> Result<SingleVec, PluginError> {
+        self.spawn(utils::stringify_points(points), points, ids)
+    }
+
+    /// Text plugins get `text` instead of the points, JSON plugins get an empty list of points
+    /// and are expected to read what they need from the options
+    pub fn run_text(&self, text: String) -> Result<SingleVec, PluginError> {
+        self.spawn(text, &vec![], &[])
+    }
+
+    fn input(
+        &self,
+        text: String,
+        points: &SingleVec,
+        ids: &[usize],
+    ) -> Result<String, PluginError> {
+        match self.protocol {
+            Protocol::Text => Ok(text),
+            Protocol::Json => serde_json::to_string(&Input {
+                version: protocol::VERSION,
+                folder: self.folder.to_string(),
+                points,
+                ids,
+                args: &self.args,
+                options: &self.options,
+                context: self.context.args(),
+            })
+            .map_err(|err| {
+                PluginError::io(
+                    &self.plugin,
+                    io::Error::new(io::ErrorKind::InvalidInput, err),
+                )
+            }),
+        }
+    }
+
+    fn spawn(
+        &self,
+        text: String,
+        points: &SingleVec,
+        ids: &[usize],
+    ) -> Result<SingleVec, PluginError> {
+        if self.context.is_cancelled() {
+            return Err(PluginError::Cancelled {
+                plugin: self.plugin.clone(),
+            });
+        }
+        let input = self.input(text, points, ids)?;
+        if self.workers > 0 {
+            let time = Instant::now();
+            let (results, stderr) = worker::run(self, input, points, ids)?;
+            return self.check_results(results, vec![], stderr, time);
+        }
+        log::info!("spawning {} child process", self.plugin);
+
+        let time = Instant::now();
+
+        let mut child = self
+            .command()
+            .spawn()
+            .map_err(|err| PluginError::io(&self.plugin, err))?;
+
+        let mut stdin = child.stdin.take().ok_or_else(|| {
+            PluginError::io(
+                &self.plugin,
+                io::Error::new(io::ErrorKind::NotFound, "Failed to open stdin"),
+            )
+        })?;
+
+        std::thread::spawn(move || match stdin.write_all(input.as_bytes()) {
+            Ok(_) => match stdin.flush() {
+                Ok(_) => {}
+                Err(err) => {
+                    log::error!("failed to flush stdin: {}", err);
+                }
+            },
+            Err(err) => {
+                log::error!("failed to write to stdin: {}", err)
+            }
+        });
+
+        let stdout = child.stdout.take().ok_or_else(|| {
+            PluginError::io(
+                &self.plugin,
+                io::Error::new(io::ErrorKind::Other, "Could not capture stdout"),
+            )
+        })?;
+        let stderr = child
+            .stderr
+            .take()
+            .map(|stderr| StderrCapture::new(self.plugin.clone(), stderr));
+
+        let child = Arc::new(Mutex::new(child));
+        let finished = Arc::new(AtomicBool::new(false));
+        let watcher = self.watch(child.clone(), finished.clone());
+
+        let mut results = vec![];
+        let mut invalid = vec![];
+        let mut output = None;
+        let mut reader = BufReader::new(stdout);
+        if self.protocol == Protocol::Json {
+            let mut stdout = String::new();
+            match reader.read_to_string(&mut stdout) {
+                Ok(_) => output = Some(Output::parse(&stdout)),
+                Err(e) => log::error!("Error reading output: {}", e),
+            }
+        }
+        for line in reader.lines() {
+            match line {
+                Ok(line) => {
+                    if line.contains(" ") {
+                        let mut iter: std::str::Split<'_, &str> = line.trim().split(" ");
+                        while let Some(line) = iter.next() {
+                            let mut coord: std::str::Split<'_, &str> = line.trim().split(",");
+                            let lat = coord.parse_next_coord();
+                            let lng = coord.parse_next_coord();
+                            if lat.is_none() || lng.is_none() {
+                                invalid.push(line.to_string())
+                            } else {
+                                results.push([lat.unwrap(), lng.unwrap()])
+                            }
+                        }
+                    } else {
+                        let mut iter: std::str::Split<'_, &str> = line.trim().split(",");
+                        let lat = iter.parse_next_coord();
+                        let lng = iter.parse_next_coord();
+                        if lat.is_none() || lng.is_none() {
+                            invalid.push(line)
+                        } else {
+                            results.push([lat.unwrap(), lng.unwrap()])
+                        }
+                    }
+                }
+                Err(e) => {
+                    log::error!("Error reading line: {}", e);
+                }
+            }
+        }
+
+        // stdout is closed once the child exits or is killed by the watcher
+        finished.store(true, Ordering::Relaxed);
+        let timed_out = watcher.join().unwrap_or_else(|_| {
+            log::error!("{} watcher thread panicked", self.plugin);
+            false
+        });
+        let status = child
+            .lock()
+            .map_err(|err| {
+                PluginError::io(
+                    &self.plugin,
+                    io::Error::new(io::ErrorKind::Other, err.to_string()),
+                )
+            })?
+            .wait()
+            .map_err(|err| PluginError::io(&self.plugin, err))?;
+        let stderr = stderr.map(StderrCapture::finish).unwrap_or_default();
+
+        if self.context.is_cancelled() {
+            return Err(PluginError::Cancelled {
+                plugin: self.plugin.clone(),
+            });
+        }
+        if timed_out {
+            return Err(PluginError::Timeout {
+                plugin: self.plugin.clone(),
+                after: time.elapsed(),
+                stderr,
+            });
+        }
+        if !status.success() {
+            return Err(PluginError::Exit {
+                plugin: self.plugin.clone(),
+                status,
+                stderr,
+            });
+        }
+        if let Some(output) = output {
+            results = self.parse_output(output, &stderr, points, ids)?;
+        }
+        self.check_results(results, invalid, stderr, time)
+    }
+
+    /// Drops the closing point of the route and errors when no points were returned
+    fn check_results(
+        &self,
+        mut results: SingleVec,
+        invalid: Vec<String>,
+        stderr: String,
+        time: Instant,
+    ) -> Result<SingleVec, PluginError> {
+        if let Some(first) = results.first() {
+            if let Some(last) = results.last() {
+                if first == last && results.len() > 1 {
+                    results.pop();
+                }
+            }
+        }
+
+        if !invalid.is_empty() {
+            log::warn!(
+                "Some invalid results were returned from the plugin: `{}`",
+                invalid.join(", ")
+            );
+        }
+        if results.is_empty() {
+            Err(PluginError::MalformedOutput {
+                plugin: self.plugin.clone(),
+                message: match self.protocol {
+                    Protocol::Text => format!(
+                        "no valid points in the output \n{}\noutput should return points in the following format: `lat,lng lat,lng`",
+                        invalid.join(", ")
+                    ),
+                    Protocol::Json => "no points in the output".to_string(),
+                },
+                stderr,
+            })
+        } else {
+            log::info!(
+                "{} child process finished in {}s with {} points",
+                self.plugin,
+                time.elapsed().as_secs_f32(),
+                results.len()
+            );
+            // Ok(output_indexes.into_iter().map(|i| points[i]).collect())
+            Ok(results)
+        }
+    }
+
+    /// Records the warnings and stats of a JSON output and returns its points, `points` and `ids`
+    /// are the input that the ids of the output refer to
+    fn parse_output(
+        &self,
+        output: Result<Output, String>,
+        stderr: &str,
+        points: &SingleVec,
+        ids: &[usize],
+    ) -> Result<SingleVec, PluginError> {
+        let mut output = output.map_err(|message| PluginError::MalformedOutput {
+            plugin: self.plugin.clone(),
+            message,
+            stderr: stderr.to_string(),
+        })?;
+        if let Some(message) = output.error.take() {
+            return Err(PluginError::Reported {
+                plugin: self.plugin.clone(),
+                message,
+            });
+        }
+        for warning in output.warnings.iter() {
+            log::warn!("[{}] {}", self.plugin, warning);
+        }
+        let warnings = std::mem::take(&mut output.warnings);
+        let stats = output.stats.take();
+        let resolved =
+            output
+                .resolve(points, ids)
+                .map_err(|message| PluginError::MalformedOutput {
+                    plugin: self.plugin.clone(),
+                    message,
+                    stderr: stderr.to_string(),
+                })?;
+        if let Ok(mut report) = self.report.lock() {
+            report.warnings.extend(warnings);
+            report.stats.extend(stats);
+            report.properties.extend(
+                resolved
+                    .properties
+                    .into_iter()
+                    .map(|(point, properties)| PointProperties { point, properties }),
+            );
+            match resolved.routes {
+                Some(routes) => {
+                    report.split = true;
+                    report.routes.extend(routes);
+                }
+                None => report.routes.push(resolved.points.clone()),
+            }
+        }
+        Ok(resolved.points)
+    }
+}
@@ -0,0 +1,228 @@
+//! JSON protocol for plugins that opt in with the `--koji-json` arg
+//!
+//! Kōji writes one [Input] document to the plugin's stdin and reads one [Output] document from
+//! its stdout. Plugins that don't opt in keep using the text protocol, `lat,lng lat,lng` in and
+//! the same format back out.
+//!
+//! A plugin returns a single list of points, or splits them into `routes`. Either can carry
+//! `properties` for each point, they're reported in the stats along with the point they belong to.
+use std::collections::HashMap;
+
+use model::api::{single_vec::SingleVec, Precision};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+/// Latest version of the protocol, plugins can reply with this version or older
+pub const VERSION: u8 = 1;
+
+/// Arg that opts a plugin into the JSON protocol, it isn't passed on to the plugin
+pub const FLAG: &str = "--koji-json";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    Text,
+    Json,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Input<'a> {
+    pub version: u8,
+    /// `clustering`, `routing`, or `bootstrap`
+    pub folder: String,
+    pub points: &'a SingleVec,
+    /// Id of each point, its index in the points of the whole run before they were split by S2 cell
+    pub ids: &'a [usize],
+    /// Args of the run, also passed on the command line unless the plugin runs as a worker
+    pub args: &'a [String],
+    /// What the algorithm knows about the calculation, e.g. `radius`, `min_points`, or `area`
+    pub options: &'a Map<String, Value>,
+    /// Args the calculation was started with, without the data points and clusters
+    pub context: Option<&'a Value>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct Output {
+    pub version: u8,
+    /// Clusters or the sorted route
+    #[serde(default)]
+    pub points: SingleVec,
+    /// Ids of the input points in the order of the sorted route, used when `points` is empty
+    #[serde(default)]
+    pub ids: Vec<usize>,
+    #[serde(default)]
+    pub warnings: Vec<String>,
+    /// Any stats the plugin wants to report, they're added to the plugin stats as is
+    #[serde(default)]
+    pub stats: Option<Value>,
+    /// Set when the plugin failed, the rest of the output is ignored
+    #[serde(default)]
+    pub error: Option<String>,
+    /// Routes that the points were split into, `points` and `ids` are ignored when it's set
+    #[serde(default)]
+    pub routes: Option<Vec<Route>>,
+    /// Properties of each point, in the same order as `points` or `ids`
+    #[serde(default)]
+    pub properties: Vec<Map<String, Value>>,
+}
+
+/// One of the routes returned by a plugin, its points are given the same way as the output's
+#[derive(Debug, Default, Deserialize)]
+pub struct Route {
+    #[serde(default)]
+    pub points: SingleVec,
+    #[serde(default)]
+    pub ids: Vec<usize>,
+    #[serde(default)]
+    pub properties: Vec<Map<String, Value>>,
+}
+
+/// Points of an output after the ids were looked up
+#[derive(Debug, Default)]
+pub struct Resolved {
+    /// Points of every route, one after the other
+    pub points: SingleVec,
+    /// `None` when the plugin didn't split the points into routes
+    pub routes: Option<Vec<SingleVec>>,
+    pub properties: Vec<([Precision; 2], Map<String, Value>)>,
+}
+
+/// The points given by `points` or, when it's empty, by `ids`, along with their properties
+fn resolve(
+    returned: SingleVec,
+    returned_ids: Vec<usize>,
+    properties: Vec<Map<String, Value>>,
+    lookup: &HashMap<usize, usize>,
+    points: &SingleVec,
+) -> Result<(SingleVec, Vec<([Precision; 2], Map<String, Value>)>), String> {
+    let returned = if !returned.is_empty() || returned_ids.is_empty() {
+        returned
+    } else {
+        returned_ids
+            .iter()
+            .map(|id| match lookup.get(id) {
+                Some(index) => Ok(points[*index]),
+                None => Err(format!("unknown point id {}", id)),
+            })
+            .collect::<Result<SingleVec, String>>()?
+    };
+    if !properties.is_empty() && properties.len() != returned.len() {
+        return Err(format!(
+            "{} properties were returned for {} points",
+            properties.len(),
+            returned.len()
+        ));
+    }
+    let properties = returned
+        .iter()
+        .copied()
+        .zip(properties)
+        .filter(|(_, properties)| !properties.is_empty())
+        .collect();
+    Ok((returned, properties))
+}
+
+impl Output {
+    pub fn parse(stdout: &str) -> Result<Self, String> {
+        let output: Output = serde_json::from_str(stdout.trim())
+            .map_err(|err| format!("invalid JSON output: {}", err))?;
+        if output.version == 0 || output.version > VERSION {
+            return Err(format!(
+                "unsupported protocol version {}, expected 1 to {}",
+                output.version, VERSION
+            ));
+        }
+        Ok(output)
+    }
+
+    /// The points of the output, looked up by their id when the plugin returned ids
+    pub fn into_points(self, points: &SingleVec, ids: &[usize]) -> Result<SingleVec, String> {
+        Ok(self.resolve(points, ids)?.points)
+    }
+
+    /// The points, routes, and properties of the output, with the ids looked up in the input
+    pub fn resolve(self, points: &SingleVec, ids: &[usize]) -> Result<Resolved, String> {
+        let lookup: HashMap<usize, usize> = ids
+            .iter()
+            .enumerate()
+            .map(|(index, id)| (*id, index))
+            .collect();
+        let Some(routes) = self.routes else {
+            let (points, properties) =
+                resolve(self.points, self.ids, self.properties, &lookup, points)?;
+            return Ok(Resolved {
+                points,
+                routes: None,
+                properties,
+            });
+        };
+        let mut resolved = Resolved::default();
+        let mut split = vec![];
+        for route in routes {
+            let (route, mut properties) =
+                resolve(route.points, route.ids, route.properties, &lookup, points)?;
+            if route.is_empty() {
+                continue;
+            }
+            resolved.points.extend(route.iter().copied());
+            resolved.properties.append(&mut properties);
+            split.push(route);
+        }
+        resolved.routes = Some(split);
+        Ok(resolved)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn looks_up_ids() {
+        let points = vec![[1., 2.], [3., 4.], [5., 6.]];
+        let ids = [7, 3, 9];
+        let output =
+            Output::parse(r#"{ "version": 1, "ids": [9, 7, 3], "warnings": ["slow"] }"#).unwrap();
+        assert_eq!(output.warnings, vec!["slow".to_string()]);
+        assert_eq!(
+            output.into_points(&points, &ids).unwrap(),
+            vec![[5., 6.], [1., 2.], [3., 4.]]
+        );
+
+        let output = Output::parse(r#"{ "version": 1, "ids": [4] }"#).unwrap();
+        assert!(output.into_points(&points, &ids).is_err());
+    }
+
+    #[test]
+    fn splits_routes() {
+        let points = vec![[1., 2.], [3., 4.], [5., 6.]];
+        let ids = [7, 3, 9];
+        let output = Output::parse(
+            r#"{
+                "version": 1,
+                "routes": [
+                    { "ids": [9, 3], "properties": [{ "stop": 1 }, {}] },
+                    { "points": [[1, 2]] }
+                ]
+            }"#,
+        )
+        .unwrap();
+        let resolved = output.resolve(&points, &ids).unwrap();
+        assert_eq!(resolved.points, vec![[5., 6.], [3., 4.], [1., 2.]]);
+        assert_eq!(
+            resolved.routes,
+            Some(vec![vec![[5., 6.], [3., 4.]], vec![[1., 2.]]])
+        );
+        assert_eq!(resolved.properties.len(), 1);
+        assert_eq!(resolved.properties[0].0, [5., 6.]);
+
+        let output =
+            Output::parse(r#"{ "version": 1, "ids": [9, 3], "properties": [{}] }"#).unwrap();
+        assert!(output.resolve(&points, &ids).is_err());
+    }
+
+    #[test]
+    fn rejects_unknown_versions() {
+        assert!(Output::parse(r#"{ "version": 2 }"#).is_err());
+        assert!(Output::parse("1,2 3,4").is_err());
+    }
+}
@@ -0,0 +1,152 @@
+//! Limits that a plugin runs under and the capture of its stderr
+//!
+//! The timeout is enforced by the thread that watches the child process, the memory and CPU
+//! limits are set with `setrlimit` in the child before the plugin starts, on Linux only.
+use std::{
+    collections::VecDeque,
+    io::{BufRead, BufReader},
+    process::{ChildStderr, Command},
+    sync::{Arc, Mutex},
+    thread::JoinHandle,
+    time::Duration,
+};
+
+use super::manifest::Manifest;
+
+/// How many of the last stderr lines are kept for the errors
+const STDERR_LINES: usize = 20;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Limits {
+    pub timeout: Option<Duration>,
+    /// Megabytes of address space
+    pub memory: Option<u64>,
+    /// Seconds of CPU time
+    pub cpu: Option<u64>,
+}
+
+impl Limits {
+    /// The limits of the manifest, the timeout defaults to `PLUGIN_TIMEOUT` seconds when set
+    pub fn new(manifest: Option<&Manifest>) -> Self {
+        let default_timeout = std::env::var("PLUGIN_TIMEOUT")
+            .ok()
+            .and_then(|timeout| timeout.parse::<u64>().ok())
+            .filter(|timeout| *timeout > 0);
+        Self {
+            timeout: manifest
+                .and_then(|manifest| manifest.timeout)
+                .or(default_timeout)
+                .map(Duration::from_secs),
+            memory: manifest.and_then(|manifest| manifest.memory_limit),
+            cpu: manifest.and_then(|manifest| manifest.cpu_limit),
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    pub fn apply(&self, command: &mut Command, _plugin: &str) {
+        use std::os::unix::process::CommandExt;
+
+        if self.memory.is_none() && self.cpu.is_none() {
+            return;
+        }
+        let memory = self.memory.map(|megabytes| megabytes * 1024 * 1024);
+        let cpu = self.cpu;
+        // only async-signal-safe calls are allowed between fork and exec
+        unsafe {
+            command.pre_exec(move || {
+                // the type of the resource differs between libc implementations
+                let set_limit = |resource, value: u64| {
+                    let limit = libc::rlimit {
+                        rlim_cur: value as libc::rlim_t,
+                        rlim_max: value as libc::rlim_t,
+                    };
+                    if libc::setrlimit(resource, &limit) == 0 {
+                        Ok(())
+                    } else {
+                        Err(std::io::Error::last_os_error())
+                    }
+                };
+                if let Some(bytes) = memory {
+                    set_limit(libc::RLIMIT_AS, bytes)?;
+                }
+                if let Some(seconds) = cpu {
+                    set_limit(libc::RLIMIT_CPU, seconds)?;
+                }
+                Ok(())
+            });
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn apply(&self, _command: &mut Command, plugin: &str) {
+        if self.memory.is_some() || self.cpu.is_some() {
+            log::warn!(
+                "memory and CPU limits are only supported on Linux, {} runs without them",
+                plugin
+            );
+        }
+    }
+}
+
+/// Logs each stderr line of the plugin as it comes in and keeps the last ones for the errors
+pub struct StderrCapture {
+    lines: Arc<Mutex<VecDeque<String>>>,
+    handle: JoinHandle<()>,
+}
+
+impl StderrCapture {
+    pub fn new(plugin: String, stderr: ChildStderr) -> Self {
+        let lines = Arc::new(Mutex::new(VecDeque::with_capacity(STDERR_LINES)));
+        let handle = {
+            let lines = lines.clone();
+            std::thread::spawn(move || {
+                for line in BufReader::new(stderr).lines() {
+                    match line {
+                        Ok(line) => {
+                            log::info!("[{}] {}", plugin, line);
+                            if let Ok(mut lines) = lines.lock() {
+                                if lines.len() == STDERR_LINES {
+                                    lines.pop_front();
+                                }
+                                lines.push_back(line);
+                            }
+                        }
+                        Err(err) => {
+                            log::error!("failed to read stderr of {}: {}", plugin, err);
+                            break;
+                        }
+                    }
+                }
+            })
+        };
+        Self { lines, handle }
+    }
+
+    /// The last lines so far, for processes that keep running
+    pub fn tail(&self) -> String {
+        Self::join_lines(&self.lines)
+    }
+
+    /// Forgets the lines so far, so the tail of a worker only covers its current run
+    pub fn clear(&self) {
+        if let Ok(mut lines) = self.lines.lock() {
+            lines.clear();
+        }
+    }
+
+    /// Waits for stderr to be closed and returns the last lines
+    pub fn finish(self) -> String {
+        let Self { lines, handle } = self;
+        if handle.join().is_err() {
+            log::error!("stderr thread panicked");
+        }
+        Self::join_lines(&lines)
+    }
+
+    fn join_lines(lines: &Mutex<VecDeque<String>>) -> String {
+        lines
+            .lock()
+            .map(|lines| lines.iter().cloned().collect::<Vec<_>>().join("\n"))
+            .unwrap_or_default()
+    }
+}
@@ -0,0 +1,294 @@
+//! Long running plugin processes, for plugins whose manifest sets `workers`
+//!
+//! Workers are started on the first run of a plugin and kept around for the next ones, whatever
+//! their args, the args of each run are only sent in its input. Each run writes the JSON protocol
+//! input as a single line to the worker's stdin and reads a single line of output back from its
+//! stdout. Workers that time out, exit, or reply with something that can't be parsed are killed
+//! and replaced on a later run. Workers that sit idle for `PLUGIN_IDLE_TIMEOUT` seconds (default
+//! 300) are stopped, `0` keeps them running.
+use std::{
+    collections::HashMap,
+    io::{self, BufRead, BufReader, Write},
+    process::{Child, ChildStdin, ChildStdout},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Condvar, Mutex, OnceLock,
+    },
+    time::{Duration, Instant},
+};
+
+use model::api::single_vec::SingleVec;
+
+use super::{error::PluginError, protocol::Output, sandbox::StderrCapture, Plugin};
+
+const DEFAULT_IDLE_TIMEOUT: u64 = 300;
+
+/// Longest wait between two checks for idle workers
+const REAP_INTERVAL: Duration = Duration::from_secs(30);
+
+struct Worker {
+    child: Arc<Mutex<Child>>,
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+    stderr: StderrCapture,
+}
+
+impl Worker {
+    fn start(plugin: &Plugin) -> Result<Self, PluginError> {
+        log::info!("starting {} worker", plugin.plugin);
+        let mut child = plugin
+            .command()
+            .spawn()
+            .map_err(|err| PluginError::io(&plugin.plugin, err))?;
+        let missing = |pipe: &str| {
+            PluginError::io(
+                &plugin.plugin,
+                io::Error::new(io::ErrorKind::Other, format!("Could not capture {}", pipe)),
+            )
+        };
+        let stdin = child.stdin.take().ok_or_else(|| missing("stdin"))?;
+        let stdout = child.stdout.take().ok_or_else(|| missing("stdout"))?;
+        let stderr = child.stderr.take().ok_or_else(|| missing("stderr"))?;
+        Ok(Self {
+            child: Arc::new(Mutex::new(child)),
+            stdin,
+            stdout: BufReader::new(stdout),
+            stderr: StderrCapture::new(plugin.plugin.clone(), stderr),
+        })
+    }
+
+    /// Sends one line of input and reads one line of output, an empty line means it exited
+    fn exchange(&mut self, input: &str) -> io::Result<String> {
+        self.stdin.write_all(input.as_bytes())?;
+        self.stdin.write_all(b"\n")?;
+        self.stdin.flush()?;
+        let mut line = String::new();
+        self.stdout.read_line(&mut line)?;
+        Ok(line)
+    }
+
+    /// Kills the worker and returns its exit status along with the end of its stderr
+    fn kill(self) -> (Option<std::process::ExitStatus>, String) {
+        let status = self.child.lock().ok().and_then(|mut child| {
+            child.kill().ok();
+            child.wait().ok()
+        });
+        drop(self.stdin);
+        (status, self.stderr.finish())
+    }
+}
+
+#[derive(Default)]
+struct State {
+    /// Idle workers along with when they were put back
+    idle: Vec<(Worker, Instant)>,
+    /// Workers that were started and not killed yet, idle or not
+    running: usize,
+}
+
+struct Pool {
+    plugin: String,
+    size: usize,
+    state: Mutex<State>,
+    available: Condvar,
+}
+
+impl Pool {
+    /// Takes an idle worker, starts a new one if the pool isn't full, or waits for one
+    fn checkout(&self, plugin: &Plugin) -> Result<Worker, PluginError> {
+        let mut state = self.state.lock().map_err(|_| poisoned(&plugin.plugin))?;
+        loop {
+            if plugin.context.is_cancelled() {
+                return Err(PluginError::Cancelled {
+                    plugin: plugin.plugin.clone(),
+                });
+            }
+            if let Some((worker, _)) = state.idle.pop() {
+                return Ok(worker);
+            }
+            if state.running < self.size {
+                state.running += 1;
+                drop(state);
+                return Worker::start(plugin).map_err(|err| {
+                    self.release(None);
+                    err
+                });
+            }
+            state = self
+                .available
+                .wait_timeout(state, Duration::from_millis(100))
+                .map_err(|_| poisoned(&plugin.plugin))?
+                .0;
+        }
+    }
+
+    /// Puts the worker back, or frees its spot in the pool when it was killed
+    fn release(&self, worker: Option<Worker>) {
+        if let Ok(mut state) = self.state.lock() {
+            match worker {
+                Some(worker) => state.idle.push((worker, Instant::now())),
+                None => state.running -= 1,
+            }
+        }
+        self.available.notify_one();
+    }
+
+    /// Kills the workers that have been idle for longer than `timeout`
+    fn reap(&self, timeout: Duration) {
+        let expired = match self.state.lock() {
+            Ok(mut state) => {
+                let (expired, idle): (Vec<_>, Vec<_>) = std::mem::take(&mut state.idle)
+                    .into_iter()
+                    .partition(|(_, since)| since.elapsed() >= timeout);
+                state.idle = idle;
+                state.running -= expired.len();
+                expired
+            }
+            Err(_) => return,
+        };
+        if expired.is_empty() {
+            return;
+        }
+        log::info!("stopping {} idle {} workers", expired.len(), self.plugin);
+        for (worker, _) in expired {
+            worker.kill();
+        }
+        self.available.notify_all();
+    }
+}
+
+fn poisoned(plugin: &str) -> PluginError {
+    PluginError::io(
+        plugin,
+        io::Error::new(io::ErrorKind::Other, "worker pool lock was poisoned"),
+    )
+}
+
+fn idle_timeout() -> Option<Duration> {
+    let timeout = std::env::var("PLUGIN_IDLE_TIMEOUT")
+        .ok()
+        .and_then(|timeout| timeout.parse().ok())
+        .unwrap_or(DEFAULT_IDLE_TIMEOUT);
+    (timeout > 0).then(|| Duration::from_secs(timeout))
+}
+
+/// Every pool, the idle workers are reaped on their own thread once the first one is created
+fn pools() -> &'static Mutex<HashMap<String, Arc<Pool>>> {
+    static POOLS: OnceLock<Mutex<HashMap<String, Arc<Pool>>>> = OnceLock::new();
+    POOLS.get_or_init(|| {
+        if let Some(timeout) = idle_timeout() {
+            let spawned = std::thread::Builder::new()
+                .name("koji-plugin-workers".to_string())
+                .spawn(move || loop {
+                    std::thread::sleep(REAP_INTERVAL.min(timeout));
+                    let pools: Vec<Arc<Pool>> = match pools().lock() {
+                        Ok(pools) => pools.values().cloned().collect(),
+                        Err(poisoned) => poisoned.into_inner().values().cloned().collect(),
+                    };
+                    pools.iter().for_each(|pool| pool.reap(timeout));
+                });
+            if let Err(err) = spawned {
+                log::error!("unable to start the idle plugin worker reaper: {}", err);
+            }
+        }
+        Mutex::new(HashMap::new())
+    })
+}
+
+/// The pool of the plugin, the args aren't part of the key since they're sent with every run
+fn pool(plugin: &Plugin) -> Arc<Pool> {
+    let key = format!(
+        "{} {} {}",
+        plugin.folder, plugin.interpreter, plugin.plugin_path
+    );
+    let mut pools = match pools().lock() {
+        Ok(pools) => pools,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    pools
+        .entry(key)
+        .or_insert_with(|| {
+            Arc::new(Pool {
+                plugin: plugin.plugin.clone(),
+                size: plugin.workers,
+                state: Mutex::new(State::default()),
+                available: Condvar::new(),
+            })
+        })
+        .clone()
+}
+
+/// Runs the input on one of the plugin's workers, returns the points and the end of its stderr
+pub fn run(
+    plugin: &Plugin,
+    input: String,
+    points: &SingleVec,
+    ids: &[usize],
+) -> Result<(SingleVec, String), PluginError> {
+    let pool = pool(plugin);
+    let mut worker = pool.checkout(plugin)?;
+    worker.stderr.clear();
+    let time = Instant::now();
+
+    let finished = Arc::new(AtomicBool::new(false));
+    let watcher = plugin.watch(worker.child.clone(), finished.clone());
+    let line = worker.exchange(&input);
+    finished.store(true, Ordering::Relaxed);
+    let timed_out = watcher.join().unwrap_or_else(|_| {
+        log::error!("{} watcher thread panicked", plugin.plugin);
+        false
+    });
+
+    let name = plugin.plugin.clone();
+    let output = match line {
+        Ok(line) if !timed_out && !plugin.context.is_cancelled() && !line.trim().is_empty() => {
+            Output::parse(&line)
+        }
+        line => {
+            let (status, stderr) = worker.kill();
+            pool.release(None);
+            return Err(if plugin.context.is_cancelled() {
+                PluginError::Cancelled { plugin: name }
+            } else if timed_out {
+                PluginError::Timeout {
+                    plugin: name,
+                    after: time.elapsed(),
+                    stderr,
+                }
+            } else if let Err(err) = line {
+                PluginError::io(&name, err)
+            } else {
+                match status {
+                    Some(status) if !status.success() => PluginError::Exit {
+                        plugin: name,
+                        status,
+                        stderr,
+                    },
+                    _ => PluginError::MalformedOutput {
+                        plugin: name,
+                        message: "the worker stopped without a response".to_string(),
+                        stderr,
+                    },
+                }
+            });
+        }
+    };
+    let stderr = worker.stderr.tail();
+    match output {
+        Ok(output) => {
+            pool.release(Some(worker));
+            let points = plugin.parse_output(Ok(output), &stderr, points, ids)?;
+            Ok((points, stderr))
+        }
+        // the worker can't be trusted to be in sync with the requests anymore
+        Err(message) => {
+            let (_, stderr) = worker.kill();
+            pool.release(None);
+            Err(PluginError::MalformedOutput {
+                plugin: name,
+                message,
+                stderr,
+            })
+        }
+    }
+}
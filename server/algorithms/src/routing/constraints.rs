@@ -0,0 +1,255 @@
+//! Max hop distance and teleport cooldown constraints, applied to a route after it's been sorted
+//!
+//! Long hops are first removed by reversing segments of the route so the long hop is replaced
+//! with a hop to a nearby cluster. Any hop that is still too long can then be filled with
+//! intermediate points.
+use model::api::{
+    args::{CooldownModel, HopConstraints},
+    point_array::PointArray,
+    single_vec::SingleVec,
+    Precision,
+};
+use rstar::{primitives::GeomWithData, RTree};
+
+use super::tsp::haversine;
+
+/// Distance in kilometers and the cooldown in minutes that follows a hop of that length
+const COOLDOWN_TABLE: [(Precision, Precision); 20] = [
+    (0., 0.),
+    (1., 1.),
+    (2., 1.),
+    (4., 2.),
+    (10., 8.),
+    (12., 9.),
+    (15., 11.),
+    (20., 13.),
+    (25., 15.),
+    (30., 18.),
+    (40., 22.),
+    (45., 23.),
+    (60., 25.),
+    (80., 27.),
+    (100., 30.),
+    (250., 45.),
+    (500., 60.),
+    (750., 75.),
+    (1000., 90.),
+    (1350., 120.),
+];
+/// Number of nearby clusters that are tried as the new neighbor of a hop
+const NEIGHBORS: usize = 10;
+const MAX_PASSES: usize = 50;
+const EPSILON: Precision = 1e-6;
+
+/// Estimated cooldown in seconds after a hop of `distance` meters
+pub fn cooldown(model: &CooldownModel, speed: Precision, distance: Precision) -> Precision {
+    match model {
+        CooldownModel::Speed => distance / speed.max(EPSILON),
+        CooldownModel::Standard => {
+            let km = distance / 1000.;
+            let minutes = COOLDOWN_TABLE
+                .windows(2)
+                .find(|pair| km <= pair[1].0)
+                .map(|pair| {
+                    let ((d0, m0), (d1, m1)) = (pair[0], pair[1]);
+                    m0 + (m1 - m0) * (km - d0) / (d1 - d0)
+                })
+                .unwrap_or(COOLDOWN_TABLE[COOLDOWN_TABLE.len() - 1].1);
+            minutes * 60.
+        }
+    }
+}
+
+/// Cost of a single hop, the number of violations it adds and its cooldown
+fn hop_cost(constraints: &HopConstraints, a: &PointArray, b: &PointArray) -> (usize, Precision) {
+    let distance = haversine(a, b);
+    let violation = match constraints.max_distance {
+        Some(max) if distance > max + EPSILON => 1,
+        _ => 0,
+    };
+    let cooldown = match &constraints.cooldown {
+        Some(model) => cooldown(model, constraints.speed, distance),
+        None => 0.,
+    };
+    (violation, cooldown)
+}
+
+/// Returns the number of hops that are longer than the max distance
+/// and the estimated total cooldown time in seconds
+pub fn hop_stats(
+    route: &SingleVec,
+    constraints: &HopConstraints,
+    closed: bool,
+) -> (usize, Precision) {
+    let n = route.len();
+    let hops = if closed && n > 2 {
+        n
+    } else {
+        n.saturating_sub(1)
+    };
+    (0..hops)
+        .map(|i| hop_cost(constraints, &route[i], &route[(i + 1) % n]))
+        .fold((0, 0.), |(violations, total), (violation, cooldown)| {
+            (violations + violation, total + cooldown)
+        })
+}
+
+/// Reorders the route to avoid hops that break the constraints, then fills any that are left
+pub fn apply(route: SingleVec, constraints: &HopConstraints, closed: bool) -> SingleVec {
+    if route.len() < 4 {
+        return route;
+    }
+    let route = reorder(route, constraints, closed);
+    match constraints.max_distance {
+        Some(max) if constraints.fill => fill(route, max, closed),
+        _ => route,
+    }
+}
+
+/// 2-opt over the nearest neighbors of each cluster, a reversal is kept when it removes
+/// violations, or lowers the cooldown without adding any
+fn reorder(route: SingleVec, constraints: &HopConstraints, closed: bool) -> SingleVec {
+    let n = route.len();
+    let tree = RTree::bulk_load(
+        route
+            .iter()
+            .enumerate()
+            .map(|(i, point)| GeomWithData::new(*point, i))
+            .collect(),
+    );
+    let neighbors: Vec<Vec<usize>> = route
+        .iter()
+        .map(|point| {
+            tree.nearest_neighbor_iter(point)
+                .skip(1)
+                .take(NEIGHBORS)
+                .map(|neighbor| neighbor.data)
+                .collect()
+        })
+        .collect();
+    // ids are the original indexes, positions are where each id currently sits in the route
+    let mut ids: Vec<usize> = (0..n).collect();
+    let mut positions: Vec<usize> = (0..n).collect();
+    let point = |ids: &[usize], position: usize| &route[ids[position % n]];
+
+    for _ in 0..MAX_PASSES {
+        let mut improved = false;
+        for i in 0..n {
+            if !closed && i == n - 1 {
+                continue;
+            }
+            let (violation, _) = hop_cost(constraints, point(&ids, i), point(&ids, i + 1));
+            if violation == 0 && constraints.cooldown.is_none() {
+                continue;
+            }
+            for neighbor in neighbors[ids[i]].iter() {
+                let j = positions[*neighbor];
+                let (a, b) = if i < j { (i, j) } else { (j, i) };
+                if b - a < 2 || (closed && (b + 1) % n == a) || (!closed && b == n - 1) {
+                    continue;
+                }
+                let old = [
+                    hop_cost(constraints, point(&ids, a), point(&ids, a + 1)),
+                    hop_cost(constraints, point(&ids, b), point(&ids, b + 1)),
+                ];
+                let new = [
+                    hop_cost(constraints, point(&ids, a), point(&ids, b)),
+                    hop_cost(constraints, point(&ids, a + 1), point(&ids, b + 1)),
+                ];
+                let old_violations = old[0].0 + old[1].0;
+                let new_violations = new[0].0 + new[1].0;
+                let better = new_violations < old_violations
+                    || (new_violations == old_violations
+                        && new[0].1 + new[1].1 + EPSILON < old[0].1 + old[1].1);
+                if better {
+                    ids[a + 1..=b].reverse();
+                    for (position, id) in ids.iter().enumerate().take(b + 1).skip(a + 1) {
+                        positions[*id] = position;
+                    }
+                    improved = true;
+                    break;
+                }
+            }
+        }
+        if !improved {
+            break;
+        }
+    }
+    ids.iter().map(|id| route[*id]).collect()
+}
+
+/// Inserts evenly spaced points into every hop that is longer than `max` meters
+fn fill(route: SingleVec, max: Precision, closed: bool) -> SingleVec {
+    let n = route.len();
+    let mut filled = Vec::with_capacity(n);
+    for i in 0..n {
+        filled.push(route[i]);
+        if !closed && i == n - 1 {
+            break;
+        }
+        let next = route[(i + 1) % n];
+        let distance = haversine(&route[i], &next);
+        let steps = (distance / max).ceil() as usize;
+        for step in 1..steps {
+            let ratio = step as Precision / steps as Precision;
+            filled.push([
+                route[i][0] + (next[0] - route[i][0]) * ratio,
+                route[i][1] + (next[1] - route[i][1]) * ratio,
+            ]);
+        }
+    }
+    if filled.len() > n {
+        log::info!("inserted {} points to shorten long hops", filled.len() - n);
+    }
+    filled
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn constraints(max_distance: Option<Precision>, fill: bool) -> HopConstraints {
+        HopConstraints {
+            max_distance,
+            fill,
+            cooldown: None,
+            speed: 25.,
+        }
+    }
+
+    #[test]
+    fn standard_cooldown_follows_table() {
+        let model = CooldownModel::Standard;
+        assert_eq!(cooldown(&model, 25., 0.), 0.);
+        assert!((cooldown(&model, 25., 10_000.) - 480.).abs() < 1e-6);
+        assert!((cooldown(&model, 25., 7_000.) - 300.).abs() < 1e-6);
+        assert_eq!(cooldown(&model, 25., 5_000_000.), 7_200.);
+    }
+
+    #[test]
+    fn reorders_away_long_hops() {
+        // two rows of points visited in a zig-zag, every hop crosses between the rows
+        let route: SingleVec = (0..10)
+            .map(|i| [40. + (i % 2) as f64 * 0.01, -74. + (i / 2) as f64 * 0.001])
+            .collect();
+        let constraints = constraints(Some(500.), false);
+        let (before, _) = hop_stats(&route, &constraints, true);
+        let reordered = apply(route.clone(), &constraints, true);
+        let (after, _) = hop_stats(&reordered, &constraints, true);
+
+        assert_eq!(reordered.len(), route.len());
+        assert!(after < before);
+        assert_eq!(after, 2);
+    }
+
+    #[test]
+    fn fills_hops_that_are_left() {
+        let route: SingleVec = vec![[40., -74.], [40., -73.99], [40.05, -73.99], [40.05, -74.]];
+        let constraints = constraints(Some(1_000.), true);
+        let filled = apply(route, &constraints, true);
+        let (violations, _) = hop_stats(&filled, &constraints, true);
+
+        assert_eq!(violations, 0);
+        assert!(filled.len() > 4);
+    }
+}
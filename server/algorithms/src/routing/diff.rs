@@ -0,0 +1,144 @@
+//! Compares two routes cluster by cluster
+//!
+//! Clusters are matched by their level 20 S2 cell. Matched clusters that are out of order are
+//! reported as moved, using the longest run of matched clusters that kept their relative order
+//! as the reference so a single moved cluster doesn't mark the rest of the route as moved too.
+use hashbrown::HashMap;
+use model::api::{point_array::PointArray, single_vec::SingleVec};
+
+use crate::rtree::point::Point;
+
+#[derive(Debug, Default)]
+pub struct RouteDiff {
+    /// Clusters only in the new route, with their index in the new route
+    pub added: Vec<(usize, PointArray)>,
+    /// Clusters only in the old route, with their index in the old route
+    pub removed: Vec<(usize, PointArray)>,
+    /// Clusters in both routes that changed position, with their old and new index
+    pub moved: Vec<(usize, usize, PointArray)>,
+    pub unchanged: usize,
+}
+
+fn cell(point: &PointArray) -> u64 {
+    Point::new(0., 20, *point).cell_id.0
+}
+
+/// Indexes of the longest strictly increasing subsequence of `values`
+fn longest_increasing(values: &[usize]) -> Vec<usize> {
+    // tails[k] is the index of the smallest value that ends an increasing run of length k + 1
+    let mut tails: Vec<usize> = vec![];
+    let mut previous = vec![usize::MAX; values.len()];
+    for (i, value) in values.iter().enumerate() {
+        let position = tails.partition_point(|tail| values[*tail] < *value);
+        if position > 0 {
+            previous[i] = tails[position - 1];
+        }
+        if position == tails.len() {
+            tails.push(i);
+        } else {
+            tails[position] = i;
+        }
+    }
+    let mut run = vec![];
+    let mut current = tails.last().copied().unwrap_or(usize::MAX);
+    while current != usize::MAX {
+        run.push(current);
+        current = previous[current];
+    }
+    run.reverse();
+    run
+}
+
+/// Diffs `before` against `after`, `closed` routes are compared regardless of where the loop starts
+pub fn main(before: &SingleVec, after: &SingleVec, closed: bool) -> RouteDiff {
+    let mut lookup: HashMap<u64, Vec<usize>> = HashMap::new();
+    for (i, point) in before.iter().enumerate().rev() {
+        lookup.entry(cell(point)).or_default().push(i);
+    }
+
+    let mut diff = RouteDiff::default();
+    // (index in after, index in before) of every cluster found in both routes
+    let mut matched: Vec<(usize, usize)> = vec![];
+    for (i, point) in after.iter().enumerate() {
+        match lookup
+            .get_mut(&cell(point))
+            .and_then(|indexes| indexes.pop())
+        {
+            Some(before_index) => matched.push((i, before_index)),
+            None => diff.added.push((i, *point)),
+        }
+    }
+    let mut is_matched = vec![false; before.len()];
+    matched.iter().for_each(|(_, b)| is_matched[*b] = true);
+    diff.removed = before
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| !is_matched[*i])
+        .map(|(i, point)| (i, *point))
+        .collect();
+
+    let n = before.len().max(1);
+    let offset = if closed {
+        // the most common shift between the two routes is treated as a rotation of the loop
+        let mut shifts: HashMap<usize, usize> = HashMap::new();
+        for (rank, (_, b)) in matched.iter().enumerate() {
+            *shifts.entry((b + n - rank % n) % n).or_default() += 1;
+        }
+        shifts
+            .into_iter()
+            .max_by_key(|(shift, count)| (*count, usize::MAX - shift))
+            .map(|(shift, _)| shift)
+            .unwrap_or(0)
+    } else {
+        0
+    };
+    let normalized: Vec<usize> = matched.iter().map(|(_, b)| (b + n - offset) % n).collect();
+    let in_order = longest_increasing(&normalized);
+    let mut kept = vec![false; matched.len()];
+    in_order.into_iter().for_each(|i| kept[i] = true);
+
+    for (i, (a, b)) in matched.into_iter().enumerate() {
+        if kept[i] {
+            diff.unchanged += 1;
+        } else {
+            diff.moved.push((b, a, after[a]));
+        }
+    }
+    diff
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn line(size: usize) -> SingleVec {
+        (0..size).map(|i| [40., -74. + i as f64 * 0.01]).collect()
+    }
+
+    #[test]
+    fn finds_added_removed_and_moved() {
+        let before = line(6);
+        let mut after = before.clone();
+        after.remove(2);
+        let moved = after.remove(0);
+        after.insert(3, moved);
+        after.push([41., -74.]);
+
+        let diff = main(&before, &after, false);
+        assert_eq!(diff.added, vec![(5, [41., -74.])]);
+        assert_eq!(diff.removed, vec![(2, before[2])]);
+        assert_eq!(diff.moved, vec![(0, 3, before[0])]);
+        assert_eq!(diff.unchanged, 4);
+    }
+
+    #[test]
+    fn rotated_loop_is_unchanged() {
+        let before = line(8);
+        let mut after = before.clone();
+        after.rotate_left(3);
+
+        let diff = main(&before, &after, true);
+        assert!(diff.moved.is_empty());
+        assert_eq!(diff.unchanged, 8);
+    }
+}
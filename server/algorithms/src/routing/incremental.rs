@@ -0,0 +1,110 @@
+//! Updates an existing route when its data points change, without rerouting it from scratch
+//!
+//! Clusters that still cover a data point keep their place in the route, clusters that no longer
+//! cover anything are dropped, and the clusters made for newly uncovered data points are
+//! inserted where they add the least distance.
+use model::api::{point_array::PointArray, single_vec::SingleVec, Precision};
+use rayon::prelude::{IntoParallelIterator, ParallelIterator};
+
+use super::tsp::haversine;
+use crate::rtree;
+
+/// Splits the existing route into the clusters that still cover at least one data point,
+/// in their original order, and the indexes of the data points that none of them cover
+pub fn retain(
+    route: SingleVec,
+    data_points: &SingleVec,
+    radius: Precision,
+) -> (SingleVec, Vec<usize>) {
+    let point_tree = rtree::spawn(radius, data_points);
+    let before = route.len();
+    let route: SingleVec = route
+        .into_iter()
+        .filter(|cluster| point_tree.locate_at_point(cluster).is_some())
+        .collect();
+
+    let cluster_tree = rtree::spawn(radius, &route);
+    let uncovered: Vec<usize> = data_points
+        .iter()
+        .enumerate()
+        .filter(|(_, point)| cluster_tree.locate_at_point(point).is_none())
+        .map(|(i, _)| i)
+        .collect();
+    log::info!(
+        "kept {} of {} existing clusters, {} data points are uncovered",
+        route.len(),
+        before,
+        uncovered.len()
+    );
+    (route, uncovered)
+}
+
+/// Inserts each new cluster at the position where it adds the least distance to the route,
+/// `closed` also considers the leg from the last cluster back to the first
+pub fn insert(mut route: SingleVec, clusters: SingleVec, closed: bool) -> SingleVec {
+    for cluster in clusters {
+        let position = cheapest_position(&route, &cluster, closed);
+        route.insert(position, cluster);
+    }
+    route
+}
+
+/// Index that the cluster should be inserted at
+fn cheapest_position(route: &SingleVec, cluster: &PointArray, closed: bool) -> usize {
+    let n = route.len();
+    if n < 2 {
+        return n;
+    }
+    let legs = if closed { n } else { n - 1 };
+    let (mut best_cost, mut best) = (0..legs)
+        .into_par_iter()
+        .map(|i| {
+            let (a, b) = (&route[i], &route[(i + 1) % n]);
+            (
+                haversine(a, cluster) + haversine(cluster, b) - haversine(a, b),
+                i + 1,
+            )
+        })
+        .min_by(|a, b| a.0.total_cmp(&b.0))
+        .unwrap_or((Precision::MAX, n));
+    if !closed {
+        // an open path can also be extended at either end
+        for (cost, position) in [
+            (haversine(cluster, &route[0]), 0),
+            (haversine(&route[n - 1], cluster), n),
+        ] {
+            if cost < best_cost {
+                best_cost = cost;
+                best = position;
+            }
+        }
+    }
+    best
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_order_and_drops_empty_clusters() {
+        let route: SingleVec = (0..5).map(|i| [40., -74. + i as f64 * 0.01]).collect();
+        // the third cluster no longer covers a point and a new point shows up further north
+        let data_points: SingleVec = vec![route[0], route[1], route[3], route[4], [40.005, -73.98]];
+        let (kept, uncovered) = retain(route.clone(), &data_points, 70.);
+
+        assert_eq!(kept, vec![route[0], route[1], route[3], route[4]]);
+        assert_eq!(uncovered, vec![4]);
+    }
+
+    #[test]
+    fn inserts_at_cheapest_position() {
+        let route: SingleVec = vec![[40., -74.], [40., -73.98], [40.02, -73.98], [40.02, -74.]];
+        let new_cluster = [40., -73.99];
+        let closed = insert(route.clone(), vec![new_cluster], true);
+        assert_eq!(closed[1], new_cluster);
+
+        let open = insert(route.clone(), vec![[40.03, -74.]], false);
+        assert_eq!(open[4], [40.03, -74.]);
+    }
+}
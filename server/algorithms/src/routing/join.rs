@@ -8,7 +8,8 @@ use std::collections::HashMap;
 use std::time::Instant;
 
 pub fn join(plugin: &Plugin, input: Vec<SingleVec>) -> SingleVec {
-    if plugin.split_level == 0 {
+    // cells that were split into routes are kept as separate routes
+    if plugin.split_level == 0 || plugin.split_routes() {
         return input.into_iter().flatten().collect();
     }
     let time = Instant::now();
@@ -26,16 +27,41 @@ pub fn join(plugin: &Plugin, input: Vec<SingleVec>) -> SingleVec {
         centroids.push(center);
         point_map.insert(get_cell_id(center), points.clone());
     }
-    let clusters: Vec<SingleVec> = plugin
-        .run_multi::<JoinFunction>(&centroids, None)
-        .unwrap_or(vec![])
-        .into_iter()
-        .filter_map(|c| {
-            let hash = get_cell_id(c);
-            point_map.remove(&hash)
-        })
-        .collect();
+    let clusters: Vec<SingleVec> =
+        match plugin.without_routes(|| plugin.run_multi::<JoinFunction>(&centroids, None)) {
+            Ok(centroids) => centroids
+                .into_iter()
+                .filter_map(|c| {
+                    let hash = get_cell_id(c);
+                    point_map.remove(&hash)
+                })
+                .collect(),
+            Err(err) => {
+                // the cells are still routed, they're just joined in no particular order
+                log::error!("Error while joining the cells: {}", err);
+                plugin.warn(format!(
+                    "the cells were joined in their original order: {}",
+                    err
+                ));
+                input
+            }
+        };
+
+    let final_routes = stitch(clusters);
 
+    log::info!(
+        "joined {} routes in {}ms",
+        final_routes.len(),
+        time.elapsed().as_millis()
+    );
+    final_routes
+}
+
+/// Concatenates already ordered routes, rotating each one based on its closest point to the next route
+pub fn stitch(clusters: Vec<SingleVec>) -> SingleVec {
+    if clusters.is_empty() {
+        return vec![];
+    }
     let mut final_routes: SingleVec = vec![];
 
     let last = clusters.len() - 1;
@@ -46,12 +72,12 @@ pub fn join(plugin: &Plugin, input: Vec<SingleVec>) -> SingleVec {
             clusters[i + 1].as_ref()
         };
 
-        let mut shortest = std::f64::MAX;
+        let mut shortest = f64::MAX;
         let mut shortest_current_index = 0;
 
         for (current_index, current_point) in current.iter().enumerate() {
             let current_point = Point::new(current_point[1], current_point[0]);
-            for (_next_index, next_point) in next.iter().enumerate() {
+            for next_point in next.iter() {
                 let next_point = Point::new(next_point[1], next_point[0]);
                 let distance = current_point.haversine_distance(&next_point);
                 if distance < shortest {
@@ -63,10 +89,5 @@ pub fn join(plugin: &Plugin, input: Vec<SingleVec>) -> SingleVec {
         current.rotate_left(shortest_current_index);
         final_routes.append(current);
     }
-    log::info!(
-        "joined {} routes in {}ms",
-        final_routes.len(),
-        time.elapsed().as_millis()
-    );
     final_routes
 }
@@ -1,17 +1,30 @@
 use std::time::Instant;
 
-use model::api::{single_vec::SingleVec, sort_by::SortBy};
+use model::api::{
+    args::{HopConstraints, OpenPath},
+    single_vec::SingleVec,
+    sort_by::SortBy,
+};
 
-use self::sorting::{SortGeohash, SortLatLng, SortPointCount, SortRandom, SortS2};
+use self::{
+    sorting::{SortGeohash, SortLatLng, SortPointCount, SortRandom, SortS2},
+    time_aware::SpawnSchedule,
+};
 use crate::{
+    context::Context,
     plugin::{Folder, Plugin},
     stats::Stats,
     utils,
 };
 
+pub mod constraints;
+pub mod diff;
+pub mod incremental;
 mod join;
 pub mod sorting;
-// pub mod vrp;
+pub mod time_aware;
+pub mod tsp;
+pub mod vrp;
 
 pub fn main(
     data_points: &SingleVec,
@@ -21,6 +34,11 @@ pub fn main(
     radius: f64,
     stats: &mut Stats,
     routing_args: &str,
+    routing_time: u64,
+    open_path: Option<&OpenPath>,
+    schedule: Option<&SpawnSchedule>,
+    constraints: Option<&HopConstraints>,
+    context: &Context,
 ) -> SingleVec {
     let route_time = Instant::now();
     let clusters = match sort_by {
@@ -30,27 +48,82 @@ pub fn main(
         SortBy::S2Cell => clusters.sort_s2(),
         SortBy::Random => clusters.sort_random(),
         SortBy::Unset => clusters,
+        SortBy::Tsp => tsp::run(clusters, route_split_level, routing_time, open_path),
+        SortBy::TimeAware => match schedule {
+            Some(schedule) => time_aware::run(
+                clusters,
+                schedule,
+                radius,
+                route_split_level,
+                routing_time,
+                open_path,
+            ),
+            None => {
+                log::warn!("Time-aware routing requires spawnpoints with known despawn times, falling back to TSP");
+                tsp::run(clusters, route_split_level, routing_time, open_path)
+            }
+        },
         SortBy::Custom(plugin) => {
             let clusters = clusters.sort_s2();
             match Plugin::new(plugin, Folder::Routing, route_split_level, routing_args) {
-                Ok(plugin_manager) => match plugin_manager.run_multi(&clusters, Some(join::join)) {
-                    Ok(sorted_clusters) => sorted_clusters,
-                    Err(e) => {
-                        log::error!("Error while running plugin: {}", e);
-                        clusters
+                Ok(mut plugin_manager) => {
+                    let result = plugin_manager
+                        .set_context(context)
+                        .set_option("radius", radius)
+                        .set_option("routing_time", routing_time)
+                        .set_option("open_path", open_path)
+                        .set_option(
+                            "max_hop_distance",
+                            constraints.and_then(|constraints| constraints.max_distance),
+                        )
+                        .run_multi(&clusters, Some(join::join));
+                    plugin_manager.report_to(stats);
+                    match result {
+                        Ok(sorted_clusters) => sorted_clusters,
+                        Err(e) => {
+                            log::error!("Error while running plugin: {}", e);
+                            if !e.is_cancelled() {
+                                stats.plugin_errors.push(e.to_string());
+                            }
+                            // routes of the cells that did finish don't cover every cluster
+                            stats.plugin_routes.clear();
+                            clusters
+                        }
                     }
-                },
+                }
                 Err(e) => {
                     log::error!("Plugin not found: {}", e);
+                    stats.plugin_errors.push(e.to_string());
                     clusters
                 }
             }
         }
     };
-    let clusters = utils::rotate_to_best(clusters, stats);
+    // the plugin already split and sorted the routes, their stats are generated separately
+    if !stats.plugin_routes.is_empty() {
+        stats.set_route_time(route_time);
+        return clusters;
+    }
+    let clusters = match open_path {
+        Some(_) if matches!(sort_by, SortBy::Tsp | SortBy::TimeAware) => clusters,
+        Some(open_path) => utils::open_path(clusters, open_path),
+        // the time-aware search already picked the best starting point
+        None if *sort_by == SortBy::TimeAware && schedule.is_some() => clusters,
+        None => utils::rotate_to_best(clusters, stats),
+    };
+    let clusters = match constraints {
+        Some(constraints) => constraints::apply(clusters, constraints, open_path.is_none()),
+        None => clusters,
+    };
+    if let Some(schedule) = schedule {
+        (stats.timed_spawns, stats.timed_spawns_hit) = schedule.hits(radius, &clusters, open_path);
+    }
 
     stats.set_route_time(route_time);
-    stats.distance_stats(&clusters);
+    stats.distance_stats(&clusters, open_path.is_none());
+    if let Some(constraints) = constraints {
+        stats.hop_stats(&clusters, constraints, open_path.is_none());
+    }
 
     clusters
 }
@@ -60,11 +133,25 @@ pub fn routing_plugins() -> Vec<String> {
 }
 
 pub fn all_routing_options() -> Vec<String> {
-    let mut options = routing_plugins();
+    // `tsp` is the native solver, the OR-Tools plugin of the same name is listed as `ortools`
+    let mut options: Vec<String> = routing_plugins()
+        .into_iter()
+        .map(|plugin| {
+            if plugin == "tsp" {
+                "ortools".to_string()
+            } else {
+                plugin
+            }
+        })
+        .collect();
+    options.push("tsp".to_string());
+    options.push("time_aware".to_string());
     options.push("point_count".to_string());
     options.push("latlon".to_string());
     options.push("geohash".to_string());
     options.push("s2".to_string());
     options.push("random".to_string());
+    let mut seen = std::collections::HashSet::new();
+    options.retain(|option| seen.insert(option.clone()));
     options
 }
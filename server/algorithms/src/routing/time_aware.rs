@@ -0,0 +1,362 @@
+//! Time-aware spawnpoint routing backing [SortBy::TimeAware](model::api::sort_by::SortBy::TimeAware)
+//!
+//! The clusters are first routed with the TSP solver, then the loop is rotated and locally
+//! rearranged so that as many spawnpoints as possible are visited while they are active.
+//! Arrival times are estimated from the travel speed and the second of the hour that the
+//! route starts at.
+use std::time::{Duration, Instant};
+
+use hashbrown::HashMap;
+use model::api::{args::OpenPath, point_array::PointArray, single_vec::SingleVec, Precision};
+use rstar::{primitives::GeomWithData, RTree};
+use serde::Serialize;
+
+use super::tsp::{self, haversine};
+use crate::rtree;
+
+/// Number of seconds that a spawn is active for before it despawns
+const SPAWN_DURATION: u32 = 1_800;
+const HOUR: Precision = 3_600.;
+/// Number of nearby clusters that a segment can be moved next to
+const NEIGHBORS: usize = 10;
+/// Longest segment that is relocated during the local search
+const SEGMENT_MAX: usize = 3;
+
+/// Spawnpoints with a known despawn time and the pace the route is travelled at
+#[derive(Debug, Clone, Serialize)]
+pub struct SpawnSchedule {
+    spawns: SingleVec,
+    despawn_secs: Vec<u32>,
+    /// Travel speed in meters per second
+    speed: Precision,
+    /// Second of the hour that the route starts at
+    start_offset: u32,
+}
+
+impl SpawnSchedule {
+    /// `start_offset` is expected to be within the hour, from 0 to 3599
+    pub fn new(spawns: Vec<(PointArray, u16)>, speed: Precision, start_offset: u32) -> Self {
+        let (spawns, despawn_secs) = spawns
+            .into_iter()
+            .map(|(point, despawn_sec)| (point, despawn_sec as u32 % 3_600))
+            .unzip();
+        Self {
+            spawns,
+            despawn_secs,
+            speed: if speed > 0. { speed } else { 1. },
+            start_offset,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.spawns.is_empty()
+    }
+
+    /// Whether a spawn is active at `time`, in seconds since the start of the hour
+    fn is_active(&self, spawn: usize, time: Precision) -> bool {
+        let despawn = self.despawn_secs[spawn] as Precision;
+        let since_despawn = (time - despawn).rem_euclid(HOUR);
+        since_despawn >= HOUR - SPAWN_DURATION as Precision
+    }
+
+    /// Seconds since the start of the hour that each cluster is reached at
+    fn arrival_times(
+        &self,
+        route: &SingleVec,
+        order: &[usize],
+        start: Option<PointArray>,
+    ) -> Vec<Precision> {
+        let mut time = self.start_offset as Precision;
+        let mut previous = start;
+        order
+            .iter()
+            .map(|index| {
+                if let Some(previous) = previous {
+                    time += haversine(&previous, &route[*index]) / self.speed;
+                }
+                previous = Some(route[*index]);
+                time
+            })
+            .collect()
+    }
+
+    /// Indexes of the clusters that cover each spawn
+    fn coverage(&self, radius: Precision, route: &SingleVec) -> Vec<Vec<usize>> {
+        let tree = rtree::spawn(radius, route);
+        let mut indexes: HashMap<u64, Vec<usize>> = HashMap::new();
+        for (i, cluster) in route.iter().enumerate() {
+            let cell = rtree::point::Point::new(radius, 20, *cluster).cell_id.0;
+            indexes.entry(cell).or_default().push(i);
+        }
+        self.spawns
+            .iter()
+            .map(|spawn| {
+                tree.locate_all_at_point(spawn)
+                    .flat_map(|cluster| {
+                        indexes.get(&cluster.cell_id.0).cloned().unwrap_or_default()
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// Returns the number of spawns that are covered by the route
+    /// and the number of those that are reached while they are active
+    pub fn hits(
+        &self,
+        radius: Precision,
+        route: &SingleVec,
+        open_path: Option<&OpenPath>,
+    ) -> (usize, usize) {
+        let order: Vec<usize> = (0..route.len()).collect();
+        let times = self.arrival_times(route, &order, open_path.and_then(|path| path.start));
+        let coverage = self.coverage(radius, route);
+        let covered = coverage.iter().filter(|c| !c.is_empty()).count();
+        let hit = coverage
+            .iter()
+            .enumerate()
+            .filter(|(spawn, clusters)| {
+                clusters
+                    .iter()
+                    .any(|cluster| self.is_active(*spawn, times[*cluster]))
+            })
+            .count();
+        (covered, hit)
+    }
+}
+
+/// Spawns attributed to each cluster while optimizing, each spawn only counts towards the
+/// first cluster that covers it so moves can be scored without tracking every visit
+struct Search<'a> {
+    schedule: &'a SpawnSchedule,
+    route: &'a SingleVec,
+    spawns: Vec<Vec<usize>>,
+    start: Option<PointArray>,
+    /// Open paths keep their first and last cluster in place
+    open: bool,
+}
+
+impl<'a> Search<'a> {
+    fn new(
+        schedule: &'a SpawnSchedule,
+        radius: Precision,
+        route: &'a SingleVec,
+        open_path: Option<&OpenPath>,
+    ) -> Self {
+        let mut spawns = vec![vec![]; route.len()];
+        for (spawn, clusters) in schedule.coverage(radius, route).into_iter().enumerate() {
+            if let Some(cluster) = clusters.into_iter().min() {
+                spawns[cluster].push(spawn);
+            }
+        }
+        Self {
+            schedule,
+            route,
+            spawns,
+            start: open_path.and_then(|path| path.start),
+            open: open_path.is_some(),
+        }
+    }
+
+    fn score(&self, order: &[usize]) -> usize {
+        self.schedule
+            .arrival_times(self.route, order, self.start)
+            .into_iter()
+            .zip(order.iter())
+            .map(|(time, cluster)| {
+                self.spawns[*cluster]
+                    .iter()
+                    .filter(|spawn| self.schedule.is_active(**spawn, time))
+                    .count()
+            })
+            .sum()
+    }
+
+    /// Tries every rotation of the loop in both directions
+    fn best_rotation(&self, order: Vec<usize>) -> (Vec<usize>, usize) {
+        let mut best_score = self.score(&order);
+        let mut best = order.clone();
+        let mut reversed = order.clone();
+        reversed.reverse();
+        for candidate in [order, reversed] {
+            for shift in 0..candidate.len() {
+                let mut rotated = candidate.clone();
+                rotated.rotate_left(shift);
+                let score = self.score(&rotated);
+                if score > best_score {
+                    best_score = score;
+                    best = rotated;
+                }
+            }
+        }
+        (best, best_score)
+    }
+
+    /// Moves short segments next to nearby clusters while that hits more spawns
+    fn relocate(&self, mut order: Vec<usize>, mut score: usize, deadline: Instant) -> Vec<usize> {
+        let tree = RTree::bulk_load(
+            self.route
+                .iter()
+                .enumerate()
+                .map(|(i, point)| GeomWithData::new(*point, i))
+                .collect(),
+        );
+        let neighbors: Vec<Vec<usize>> = self
+            .route
+            .iter()
+            .map(|point| {
+                tree.nearest_neighbor_iter(point)
+                    .skip(1)
+                    .take(NEIGHBORS)
+                    .map(|neighbor| neighbor.data)
+                    .collect()
+            })
+            .collect();
+
+        let mut improved = true;
+        while improved {
+            improved = false;
+            for first in 0..self.route.len() {
+                for length in 1..=SEGMENT_MAX.min(order.len().saturating_sub(2)) {
+                    if Instant::now() > deadline {
+                        log::debug!("time-aware search ran out of time");
+                        return order;
+                    }
+                    if let Some((candidate, candidate_score)) =
+                        self.move_segment(&order, first, length, &neighbors[first], score)
+                    {
+                        order = candidate;
+                        score = candidate_score;
+                        improved = true;
+                        break;
+                    }
+                }
+            }
+        }
+        order
+    }
+
+    /// Returns the first order that hits more spawns by moving the `length` clusters starting
+    /// at `first` next to one of its neighbours
+    fn move_segment(
+        &self,
+        order: &[usize],
+        first: usize,
+        length: usize,
+        neighbors: &[usize],
+        score: usize,
+    ) -> Option<(Vec<usize>, usize)> {
+        let start = order.iter().position(|i| *i == first)?;
+        if start + length > order.len()
+            || (self.open && (start == 0 || start + length == order.len()))
+        {
+            return None;
+        }
+        let mut rest = order.to_vec();
+        let segment: Vec<usize> = rest.drain(start..start + length).collect();
+
+        for neighbor in neighbors.iter() {
+            let Some(at) = rest.iter().position(|i| i == neighbor) else {
+                continue;
+            };
+            for insert in [at, at + 1] {
+                if self.open && (insert == 0 || insert == rest.len()) {
+                    continue;
+                }
+                let mut candidate = rest.clone();
+                candidate.splice(insert..insert, segment.iter().copied());
+                let candidate_score = self.score(&candidate);
+                if candidate_score > score {
+                    return Some((candidate, candidate_score));
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Routes the clusters so the covered spawnpoints are reached while they are active.
+///
+/// `routing_time` is the time budget in seconds, `0` scales it by the number of clusters.
+/// Half of the budget goes to the TSP solver and the rest to the time-aware search.
+pub fn run(
+    clusters: SingleVec,
+    schedule: &SpawnSchedule,
+    radius: Precision,
+    split_level: u64,
+    routing_time: u64,
+    open_path: Option<&OpenPath>,
+) -> SingleVec {
+    let time = Instant::now();
+    let time_budget = if routing_time == 0 {
+        tsp::auto_time_budget(clusters.len())
+    } else {
+        Duration::from_secs(routing_time)
+    };
+    let route = tsp::run(
+        clusters,
+        split_level,
+        (time_budget.as_secs() / 2).max(1),
+        open_path,
+    );
+    if route.len() < 3 || schedule.is_empty() {
+        return route;
+    }
+
+    let search = Search::new(schedule, radius, &route, open_path);
+    let order: Vec<usize> = (0..route.len()).collect();
+    let initial_score = search.score(&order);
+    let (order, score) = if open_path.is_some() {
+        (order, initial_score)
+    } else {
+        search.best_rotation(order)
+    };
+    let order = search.relocate(order, score, time + time_budget);
+    let final_score = search.score(&order);
+    log::info!(
+        "time-aware routing improved active spawns from {} to {} in {:.2}s",
+        initial_score,
+        final_score,
+        time.elapsed().as_secs_f32()
+    );
+    order.into_iter().map(|i| route[i]).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn line(size: usize) -> SingleVec {
+        (0..size).map(|i| [40., -74. + i as f64 * 0.01]).collect()
+    }
+
+    #[test]
+    fn spawn_is_active_before_despawn() {
+        let schedule = SpawnSchedule::new(vec![([40., -74.], 600)], 25., 0);
+        assert!(schedule.is_active(0, 0.));
+        assert!(schedule.is_active(0, 3_500.));
+        assert!(!schedule.is_active(0, 601.));
+        assert!(!schedule.is_active(0, 2_000.));
+    }
+
+    #[test]
+    fn hits_at_least_as_many_spawns_as_tsp() {
+        let clusters = line(12);
+        // at 1 m/s each hop takes ~850s, so only some orders reach every spawn in time
+        let spawns = clusters
+            .iter()
+            .enumerate()
+            .map(|(i, point)| (*point, ((12 - i) * 850 % 3_600) as u16))
+            .collect();
+        let schedule = SpawnSchedule::new(spawns, 1., 0);
+
+        let tsp_route = tsp::run(clusters.clone(), 0, 1, None);
+        let (_, tsp_hit) = schedule.hits(70., &tsp_route, None);
+        let route = run(clusters.clone(), &schedule, 70., 0, 2, None);
+        let (covered, hit) = schedule.hits(70., &route, None);
+
+        assert_eq!(route.len(), clusters.len());
+        assert_eq!(covered, clusters.len());
+        assert!(hit >= tsp_hit);
+    }
+}
@@ -0,0 +1,658 @@
+//! In-process TSP solver backing [SortBy::Tsp](model::api::sort_by::SortBy::Tsp)
+//!
+//! An initial tour is built with nearest neighbour construction, then improved with 2-opt and
+//! Or-opt moves restricted to each point's closest neighbours. Once the tour is at a local
+//! optimum it is repeatedly perturbed and re-optimized until the time budget runs out.
+//!
+//! Open paths are solved as a loop with two extra nodes for the start and end of the path,
+//! the edge between them is heavily rewarded so it is never broken.
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use geo::{HaversineDistance, Point};
+use model::api::{args::OpenPath, point_array::PointArray, single_vec::SingleVec};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use rayon::prelude::{IntoParallelIterator, IntoParallelRefIterator, ParallelIterator};
+use rstar::{primitives::GeomWithData, RTree};
+
+use super::join;
+use crate::{s2::create_cell_map, utils};
+
+/// Above this many points distances are calculated on demand instead of being stored
+const MATRIX_LIMIT: usize = 2_500;
+/// Number of nearest neighbours that are considered for each local search move
+const NEIGHBORS: usize = 10;
+/// Longest segment that an Or-opt move will relocate
+const OR_OPT_MAX: usize = 3;
+/// Longest segment swapped by a perturbation
+const KICK_MAX: usize = 50;
+const EPSILON: f64 = 1e-7;
+/// Reward for keeping the start and end of an open path connected
+const FIXED_EDGE: f64 = 1e9;
+
+type IndexedPoint = GeomWithData<[f64; 2], usize>;
+
+/// Haversine distances between every pair of points
+///
+/// When solving an open path, the nodes after the points are the start and the end of the path
+struct DistanceMatrix {
+    points: SingleVec,
+    matrix: Vec<f64>,
+    ends: Option<[Option<PointArray>; 2]>,
+}
+
+impl DistanceMatrix {
+    fn new(points: SingleVec, ends: Option<[Option<PointArray>; 2]>) -> Self {
+        let matrix = if points.len() > MATRIX_LIMIT {
+            vec![]
+        } else {
+            points
+                .par_iter()
+                .flat_map_iter(|a| points.iter().map(|b| haversine(a, b)))
+                .collect()
+        };
+        Self {
+            points,
+            matrix,
+            ends,
+        }
+    }
+
+    fn get(&self, a: usize, b: usize) -> f64 {
+        let n = self.points.len();
+        match (a >= n, b >= n) {
+            (false, false) => {
+                if self.matrix.is_empty() {
+                    haversine(&self.points[a], &self.points[b])
+                } else {
+                    self.matrix[a * n + b]
+                }
+            }
+            (true, true) => {
+                if a == b {
+                    0.
+                } else {
+                    -FIXED_EDGE
+                }
+            }
+            (true, false) => self.end_distance(a - n, b),
+            (false, true) => self.end_distance(b - n, a),
+        }
+    }
+
+    /// Distance from a point to the start or end of the path, free ends are free to reach
+    fn end_distance(&self, end: usize, point: usize) -> f64 {
+        match self.ends.and_then(|ends| ends[end]) {
+            Some(end) => haversine(&end, &self.points[point]),
+            None => 0.,
+        }
+    }
+}
+
+pub(crate) fn haversine(a: &PointArray, b: &PointArray) -> f64 {
+    Point::new(a[1], a[0]).haversine_distance(&Point::new(b[1], b[0]))
+}
+
+/// Projects points onto a roughly equidistant plane so they can be indexed by an [RTree]
+fn project(points: &[PointArray]) -> Vec<IndexedPoint> {
+    let mean_lat = points.iter().map(|p| p[0]).sum::<f64>() / points.len().max(1) as f64;
+    let scale = mean_lat.to_radians().cos();
+    points
+        .iter()
+        .enumerate()
+        .map(|(i, p)| IndexedPoint::new([p[0], p[1] * scale], i))
+        .collect()
+}
+
+#[derive(Debug, Clone)]
+struct Tour {
+    order: Vec<usize>,
+    pos: Vec<usize>,
+    cost: f64,
+}
+
+impl Tour {
+    fn new(order: Vec<usize>, distances: &DistanceMatrix) -> Self {
+        let mut tour = Self {
+            pos: vec![0; order.len()],
+            order,
+            cost: 0.,
+        };
+        tour.update_positions();
+        tour.cost = (0..tour.order.len())
+            .map(|i| distances.get(tour.order[i], tour.next(tour.order[i])))
+            .sum();
+        tour
+    }
+
+    fn len(&self) -> usize {
+        self.order.len()
+    }
+
+    fn update_positions(&mut self) {
+        for (i, node) in self.order.iter().enumerate() {
+            self.pos[*node] = i;
+        }
+    }
+
+    fn next(&self, node: usize) -> usize {
+        self.order[(self.pos[node] + 1) % self.len()]
+    }
+
+    fn prev(&self, node: usize) -> usize {
+        self.order[(self.pos[node] + self.len() - 1) % self.len()]
+    }
+
+    /// Whether `node` is one of the `length` nodes that follow (and include) `start`
+    fn in_segment(&self, node: usize, start: usize, length: usize) -> bool {
+        (self.pos[node] + self.len() - self.pos[start]) % self.len() < length
+    }
+
+    /// Reverses the path running forward from `from` to `to`, both inclusive
+    fn reverse(&mut self, from: usize, to: usize) {
+        let n = self.len();
+        let (mut i, mut j) = (self.pos[from], self.pos[to]);
+        let mut length = (j + n - i) % n + 1;
+        if length * 2 > n {
+            // reversing the complement results in the same cycle with less work
+            (i, j) = ((j + 1) % n, (i + n - 1) % n);
+            length = n - length;
+        }
+        for _ in 0..length / 2 {
+            self.order.swap(i, j);
+            self.pos[self.order[i]] = i;
+            self.pos[self.order[j]] = j;
+            i = (i + 1) % n;
+            j = (j + n - 1) % n;
+        }
+    }
+
+    /// Moves the `length` nodes starting at `start` so they directly follow `after`
+    fn move_segment(&mut self, start: usize, length: usize, after: usize, reversed: bool) {
+        let n = self.len();
+        let first = self.pos[start];
+        let mut segment: Vec<usize> = (0..length).map(|k| self.order[(first + k) % n]).collect();
+        if reversed {
+            segment.reverse();
+        }
+        let mut order: Vec<usize> = (length..n).map(|k| self.order[(first + k) % n]).collect();
+        let index = order.iter().position(|node| *node == after).unwrap_or(0);
+        order.splice(index + 1..index + 1, segment);
+        self.order = order;
+        self.update_positions();
+    }
+}
+
+pub struct Tsp {
+    distances: DistanceMatrix,
+    neighbors: Vec<Vec<usize>>,
+}
+
+impl Tsp {
+    /// Solver for a closed loop
+    pub fn new(points: &[PointArray]) -> Self {
+        Self::build(points.to_vec(), None)
+    }
+
+    /// Solver for an open path, the returned orders run from `start` to `end`
+    pub fn open(points: &[PointArray], open_path: &OpenPath) -> Self {
+        Self::build(points.to_vec(), Some([open_path.start, open_path.end]))
+    }
+
+    fn build(points: SingleVec, ends: Option<[Option<PointArray>; 2]>) -> Self {
+        let n = points.len();
+        // anchored ends are indexed alongside the points, free ends are a neighbour of every point
+        let mut nodes = points.clone();
+        let mut anchored_ends = vec![];
+        let mut free_ends = vec![];
+        if let Some(ends) = ends {
+            for (i, end) in ends.into_iter().enumerate() {
+                match end {
+                    Some(end) => {
+                        nodes.push(end);
+                        anchored_ends.push(n + i);
+                    }
+                    None => free_ends.push(n + i),
+                }
+            }
+        }
+        let mut projected = project(&nodes);
+        for (point, index) in projected.iter_mut().skip(n).zip(anchored_ends) {
+            point.data = index;
+        }
+
+        let distances = DistanceMatrix::new(points, ends);
+        let tree = RTree::bulk_load(projected.clone());
+        let mut neighbors: Vec<Vec<usize>> = vec![vec![]; n + if ends.is_some() { 2 } else { 0 }];
+        let nearest: Vec<(usize, Vec<usize>)> = projected
+            .into_par_iter()
+            .map(|point| {
+                let mut nearest: Vec<usize> = free_ends.clone();
+                nearest.extend(
+                    tree.nearest_neighbor_iter(point.geom())
+                        .filter(|neighbor| neighbor.data != point.data)
+                        .take(NEIGHBORS)
+                        .map(|neighbor| neighbor.data),
+                );
+                nearest.sort_by(|a, b| {
+                    distances
+                        .get(point.data, *a)
+                        .total_cmp(&distances.get(point.data, *b))
+                });
+                (point.data, nearest)
+            })
+            .collect();
+        for (node, nearest) in nearest {
+            neighbors[node] = nearest;
+        }
+        Self {
+            distances,
+            neighbors,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.neighbors.len()
+    }
+
+    /// Number of points being routed, excluding the ends of an open path
+    fn points(&self) -> usize {
+        self.distances.points.len()
+    }
+
+    /// Length of the tour, without the reward for the fixed edge of an open path
+    fn distance(&self, tour: &Tour) -> f64 {
+        if self.distances.ends.is_some() {
+            tour.cost + FIXED_EDGE
+        } else {
+            tour.cost
+        }
+    }
+
+    /// Converts a tour into the order the points should be visited in
+    fn to_path(&self, order: Vec<usize>) -> Vec<usize> {
+        let n = self.points();
+        if self.distances.ends.is_none() {
+            return order;
+        }
+        let start = order.iter().position(|node| *node == n).unwrap_or(0);
+        let forward = order[(start + 1) % order.len()] != n + 1;
+        (1..order.len())
+            .map(|k| {
+                if forward {
+                    order[(start + k) % order.len()]
+                } else {
+                    order[(start + order.len() - k) % order.len()]
+                }
+            })
+            .filter(|node| *node < n)
+            .collect()
+    }
+
+    fn dist(&self, a: usize, b: usize) -> f64 {
+        self.distances.get(a, b)
+    }
+
+    /// Returns the indexes of the points in the order they should be visited
+    pub fn solve(&self, time_budget: Duration) -> Vec<usize> {
+        if self.points() == 0 {
+            return vec![];
+        }
+        self.improve(self.nearest_neighbor(), time_budget)
+    }
+
+    /// Improves an existing order instead of building a new one
+    pub fn improve(&self, mut order: Vec<usize>, time_budget: Duration) -> Vec<usize> {
+        if self.distances.ends.is_some() {
+            order.extend([self.points() + 1, self.points()]);
+        }
+        if self.len() <= 3 {
+            return self.to_path(order);
+        }
+        let deadline = Instant::now() + time_budget;
+        let tour = Tour::new(order, &self.distances);
+        self.to_path(self.iterated_local_search(tour, deadline))
+    }
+
+    fn iterated_local_search(&self, mut tour: Tour, deadline: Instant) -> Vec<usize> {
+        let n = self.len();
+        self.optimize(&mut tour, 0..n, deadline);
+        log::debug!(
+            "local optimum found with a distance of {:.2}m",
+            self.distance(&tour)
+        );
+
+        if n >= 8 {
+            let mut rng = StdRng::seed_from_u64(n as u64);
+            let stall_limit = n * 2 + 100;
+            let mut stalled = 0;
+            while stalled < stall_limit && Instant::now() < deadline {
+                let mut candidate = tour.clone();
+                let touched = self.perturb(&mut candidate, &mut rng);
+                self.optimize(&mut candidate, touched, deadline);
+                if candidate.cost + EPSILON < tour.cost {
+                    tour = candidate;
+                    stalled = 0;
+                } else {
+                    stalled += 1;
+                }
+            }
+        }
+        log::debug!("final tour distance: {:.2}m", self.distance(&tour));
+        tour.order
+    }
+
+    fn nearest_neighbor(&self) -> Vec<usize> {
+        let points = project(&self.distances.points);
+        let first = match self.distances.ends.and_then(|[start, _]| start) {
+            Some(start) => (0..points.len())
+                .min_by(|a, b| {
+                    haversine(&start, &self.distances.points[*a])
+                        .total_cmp(&haversine(&start, &self.distances.points[*b]))
+                })
+                .unwrap_or(0),
+            None => 0,
+        };
+        let mut current = points[first];
+        let mut tree = RTree::bulk_load(points);
+        tree.remove(&current);
+
+        let mut order = Vec::with_capacity(self.len());
+        order.push(current.data);
+        while let Some(next) = tree.nearest_neighbor(current.geom()).copied() {
+            tree.remove(&next);
+            order.push(next.data);
+            current = next;
+        }
+        order
+    }
+
+    fn optimize<I>(&self, tour: &mut Tour, nodes: I, deadline: Instant)
+    where
+        I: IntoIterator<Item = usize>,
+    {
+        let mut active = vec![false; self.len()];
+        let mut queue = VecDeque::new();
+        for node in nodes {
+            if !active[node] {
+                active[node] = true;
+                queue.push_back(node);
+            }
+        }
+        while let Some(node) = queue.pop_front() {
+            active[node] = false;
+            if Instant::now() >= deadline {
+                break;
+            }
+            if let Some(touched) = self.two_opt(tour, node).or_else(|| self.or_opt(tour, node)) {
+                for node in touched {
+                    if !active[node] {
+                        active[node] = true;
+                        queue.push_back(node);
+                    }
+                }
+            }
+        }
+    }
+
+    fn two_opt(&self, tour: &mut Tour, a: usize) -> Option<Vec<usize>> {
+        // a -> b ... c -> d becomes a -> c ... b -> d
+        let b = tour.next(a);
+        let ab = self.dist(a, b);
+        for &c in self.neighbors[a].iter() {
+            let ac = self.dist(a, c);
+            if ac >= ab {
+                break;
+            }
+            let d = tour.next(c);
+            if c == b || d == a {
+                continue;
+            }
+            let gain = ab + self.dist(c, d) - ac - self.dist(b, d);
+            if gain > EPSILON {
+                tour.reverse(b, c);
+                tour.cost -= gain;
+                return Some(vec![a, b, c, d]);
+            }
+        }
+        // b -> a ... d -> c becomes b -> d ... a -> c
+        let b = tour.prev(a);
+        let ab = self.dist(b, a);
+        for &c in self.neighbors[a].iter() {
+            let ac = self.dist(a, c);
+            if ac >= ab {
+                break;
+            }
+            let d = tour.prev(c);
+            if c == b || d == a {
+                continue;
+            }
+            let gain = ab + self.dist(d, c) - ac - self.dist(b, d);
+            if gain > EPSILON {
+                tour.reverse(a, d);
+                tour.cost -= gain;
+                return Some(vec![a, b, c, d]);
+            }
+        }
+        None
+    }
+
+    fn or_opt(&self, tour: &mut Tour, first: usize) -> Option<Vec<usize>> {
+        let n = tour.len();
+        for length in 1..=OR_OPT_MAX.min(n.saturating_sub(3)) {
+            let last = tour.order[(tour.pos[first] + length - 1) % n];
+            let prev = tour.prev(first);
+            let next = tour.next(last);
+            let removal_gain =
+                self.dist(prev, first) + self.dist(last, next) - self.dist(prev, next);
+            if removal_gain <= EPSILON {
+                continue;
+            }
+            for &c in self.neighbors[first]
+                .iter()
+                .chain(self.neighbors[last].iter())
+            {
+                if tour.in_segment(c, first, length) {
+                    continue;
+                }
+                for (x, y) in [(c, tour.next(c)), (tour.prev(c), c)] {
+                    if tour.in_segment(x, first, length) || tour.in_segment(y, first, length) {
+                        continue;
+                    }
+                    let base = self.dist(x, y);
+                    let forward = self.dist(x, first) + self.dist(last, y) - base;
+                    let reversed = self.dist(x, last) + self.dist(first, y) - base;
+                    let insertion_cost = forward.min(reversed);
+                    if removal_gain - insertion_cost > EPSILON {
+                        tour.move_segment(first, length, x, reversed < forward);
+                        tour.cost -= removal_gain - insertion_cost;
+                        return Some(vec![prev, next, x, y, first, last]);
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    /// Swaps two short neighbouring segments, a localized double bridge move
+    fn perturb(&self, tour: &mut Tour, rng: &mut StdRng) -> Vec<usize> {
+        let n = tour.len();
+        let max_length = KICK_MAX.min((n - 2) / 2).max(1);
+        let start = rng.gen_range(0..n);
+        let first_length = rng.gen_range(1..=max_length);
+        let second_length = rng.gen_range(1..=max_length);
+
+        let at = |offset: usize| tour.order[(start + offset) % n];
+        let a = at(0);
+        let b = at(1);
+        let c = at(first_length);
+        let d = at(first_length + 1);
+        let e = at(first_length + second_length);
+        let f = at(first_length + second_length + 1);
+
+        // a -> [b..c] -> [d..e] -> f becomes a -> [d..e] -> [b..c] -> f
+        let delta = self.dist(a, d) + self.dist(e, b) + self.dist(c, f)
+            - self.dist(a, b)
+            - self.dist(c, d)
+            - self.dist(e, f);
+        tour.move_segment(d, second_length, a, false);
+        tour.cost += delta;
+        vec![a, b, c, d, e, f]
+    }
+}
+
+/// Default time budget, scaled by the number of points
+pub(crate) fn auto_time_budget(points: usize) -> Duration {
+    Duration::from_secs_f64((points as f64 / 1000.).powi(2).clamp(1., 60.))
+}
+
+fn solve_points(
+    points: &SingleVec,
+    time_budget: Duration,
+    open_path: Option<&OpenPath>,
+) -> SingleVec {
+    let tsp = match open_path {
+        Some(open_path) => Tsp::open(points, open_path),
+        None => Tsp::new(points),
+    };
+    tsp.solve(time_budget)
+        .into_iter()
+        .map(|i| points[i])
+        .collect()
+}
+
+/// Solves the route in process, splitting it by S2 cells first when `split_level` is set.
+///
+/// `routing_time` is the time budget in seconds, `0` scales it by the number of points
+pub fn run(
+    points: SingleVec,
+    split_level: u64,
+    routing_time: u64,
+    open_path: Option<&OpenPath>,
+) -> SingleVec {
+    let time = Instant::now();
+    let time_budget = if routing_time == 0 {
+        auto_time_budget(points.len())
+    } else {
+        Duration::from_secs(routing_time)
+    };
+    log::info!(
+        "starting tsp solver for {} points with a {:.2}s time budget",
+        points.len(),
+        time_budget.as_secs_f32()
+    );
+
+    let route = if split_level == 0 || points.len() <= 3 {
+        solve_points(&points, time_budget, open_path)
+    } else {
+        let cells: Vec<SingleVec> = create_cell_map(&points, split_level)
+            .into_values()
+            .collect();
+        let threads = rayon::current_num_threads() as f64;
+        let cell_budget = time_budget.mul_f64((threads / cells.len() as f64).min(1.) / 2.);
+        let routes: Vec<SingleVec> = cells
+            .into_par_iter()
+            .map(|cell| solve_points(&cell, cell_budget, None))
+            .collect();
+
+        let centroids: SingleVec = routes.iter().map(utils::centroid).collect();
+        let mut routes: Vec<Option<SingleVec>> = routes.into_iter().map(Some).collect();
+        let ordered = Tsp::new(&centroids)
+            .solve(cell_budget)
+            .into_iter()
+            .filter_map(|i| routes[i].take())
+            .collect();
+        let joined = join::stitch(ordered);
+
+        // smooth out the seams between the cells
+        let remaining = time_budget.saturating_sub(time.elapsed());
+        let (joined, tsp) = match open_path {
+            Some(open_path) => {
+                let joined = utils::open_path(joined, open_path);
+                let tsp = Tsp::open(&joined, open_path);
+                (joined, tsp)
+            }
+            None => {
+                let tsp = Tsp::new(&joined);
+                (joined, tsp)
+            }
+        };
+        tsp.improve((0..joined.len()).collect(), remaining)
+            .into_iter()
+            .map(|i| joined[i])
+            .collect()
+    };
+
+    log::info!(
+        "tsp solver finished routing {} points in {:.2}s",
+        route.len(),
+        time.elapsed().as_secs_f32()
+    );
+    route
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn grid(size: usize) -> SingleVec {
+        (0..size)
+            .flat_map(|x| (0..size).map(move |y| [x as f64 * 0.001, y as f64 * 0.001]))
+            .collect()
+    }
+
+    fn length(points: &SingleVec) -> f64 {
+        (0..points.len())
+            .map(|i| haversine(&points[i], &points[(i + 1) % points.len()]))
+            .sum()
+    }
+
+    #[test]
+    fn visits_every_point_once() {
+        let points = grid(12);
+        let mut route = run(points.clone(), 0, 1, None);
+        assert_eq!(route.len(), points.len());
+        route.sort_by(|a, b| a[0].total_cmp(&b[0]).then(a[1].total_cmp(&b[1])));
+        let mut expected = points;
+        expected.sort_by(|a, b| a[0].total_cmp(&b[0]).then(a[1].total_cmp(&b[1])));
+        assert_eq!(route, expected);
+    }
+
+    #[test]
+    fn finds_optimal_grid_tour() {
+        // an even sized grid has an optimal tour that only uses edges between adjacent points
+        let points = grid(8);
+        let edge = haversine(&points[0], &points[1]);
+        let route = run(points.clone(), 0, 1, None);
+        assert!(length(&route) <= points.len() as f64 * edge * 1.05);
+    }
+
+    #[test]
+    fn handles_tiny_inputs() {
+        assert!(run(vec![], 0, 1, None).is_empty());
+        assert_eq!(run(vec![[1., 1.]], 0, 1, None), vec![[1., 1.]]);
+        assert_eq!(run(vec![[1., 1.], [2., 2.]], 0, 1, None).len(), 2);
+    }
+
+    #[test]
+    fn open_path_runs_between_ends() {
+        let line: SingleVec = (0..20).map(|i| [0., i as f64 * 0.001]).collect();
+        let mut shuffled = line.clone();
+        shuffled.rotate_left(7);
+        shuffled.swap(2, 11);
+
+        let open_path = OpenPath {
+            start: Some([0.0005, 0.]),
+            end: Some([0.0005, 0.019]),
+        };
+        assert_eq!(run(shuffled.clone(), 0, 1, Some(&open_path)), line);
+
+        let open_path = OpenPath {
+            start: Some([0.0005, 0.019]),
+            end: None,
+        };
+        let mut reversed = line.clone();
+        reversed.reverse();
+        assert_eq!(run(shuffled, 0, 1, Some(&open_path)), reversed);
+    }
+}
@@ -1,205 +1,341 @@
-// This file is currently unused but remains in the repo in case someone comes back to it
-// You must uncomment vrp-pragmatic in the Cargo.toml file to use it
-// This should be mostly functional, it's just unreliable and a little slow
-use std::{cmp::Ordering::Less, sync::Arc};
-use vrp_pragmatic::core::{
-    model::{Problem as CoreProblem, Solution as CoreSolution},
-    solver::{create_default_config_builder, get_default_telemetry_mode, Solver},
-    utils::Environment,
+//! Splits a route into multiple loops so it can be shared between devices
+//!
+//! The routed clusters are first cut into contiguous segments, either by hop count or by
+//! distance. The segments are then made more compact by reassigning clusters to the closest
+//! segment centroid while keeping each segment's size, and finally each segment is solved as
+//! its own loop. When balancing by distance, clusters are moved out of the longest loop for
+//! as long as that shortens it without making another loop longer.
+use std::time::{Duration, Instant};
+
+use model::api::{
+    args::{DeviceBalance, HopConstraints, OpenPath},
+    point_array::PointArray,
+    single_vec::SingleVec,
 };
-use vrp_pragmatic::format::{
-    problem::{
-        Fleet, Job, JobPlace, JobTask, Matrix, MatrixProfile, Objective, Plan, PragmaticProblem,
-        Problem, ShiftStart, VehicleCosts, VehicleLimits, VehicleProfile, VehicleShift,
-        VehicleType,
-    },
-    solution::{create_solution, Solution},
-    Location,
+use rayon::prelude::{IntoParallelIterator, ParallelIterator, ParallelSliceMut};
+
+use super::{
+    constraints,
+    tsp::{self, haversine, Tsp},
 };
+use crate::{stats::Stats, utils};
 
-trait ToLocation {
-    fn to_loc(self) -> Location;
+/// Max number of reassignment rounds used to compact the segments
+const REFINE_ROUNDS: usize = 25;
+/// Number of neighbouring loops that clusters can be moved to when balancing by distance
+const BALANCE_NEIGHBORS: usize = 3;
+/// Moves that shorten the longest loop by less than this many meters are ignored
+const EPSILON: f64 = 1.;
+
+fn loop_length(route: &SingleVec) -> f64 {
+    (0..route.len())
+        .map(|i| haversine(&route[i], &route[(i + 1) % route.len()]))
+        .sum()
 }
 
-impl ToLocation for (f64, f64) {
-    fn to_loc(self) -> Location {
-        let (lat, lng) = self;
-        Location::new_coordinate(lat, lng)
+/// Assigns an equal number of consecutive clusters to each device
+fn split_by_hops(points: usize, devices: usize) -> Vec<usize> {
+    let size = points / devices;
+    let remainder = points % devices;
+    (0..devices)
+        .flat_map(|device| vec![device; size + usize::from(device < remainder)])
+        .collect()
+}
+
+/// Cuts the loop into consecutive segments of roughly equal length
+fn split_by_distance(clusters: &SingleVec, devices: usize) -> Vec<usize> {
+    let legs: Vec<f64> = (0..clusters.len())
+        .map(|i| haversine(&clusters[i], &clusters[(i + 1) % clusters.len()]))
+        .collect();
+    let target = legs.iter().sum::<f64>() / devices as f64;
+
+    let mut assignment = Vec::with_capacity(clusters.len());
+    let mut device = 0;
+    let mut length = 0.;
+    for (i, leg) in legs.into_iter().enumerate() {
+        assignment.push(device);
+        length += leg;
+        let remaining_points = clusters.len() - i - 1;
+        let remaining_devices = devices - device - 1;
+        if remaining_devices > 0
+            && (length >= target * (device + 1) as f64 || remaining_points == remaining_devices)
+        {
+            device += 1;
+        }
     }
+    assignment
+}
+
+/// Planar distance that is only used for comparisons
+fn rough_distance(a: &PointArray, b: &PointArray, scale: f64) -> f64 {
+    let lat = a[0] - b[0];
+    let lon = (a[1] - b[1]) * scale;
+    lat * lat + lon * lon
 }
 
-fn create_problem(services: SingleArray, devices: Vec<String>) -> Problem {
-    let tour_size = services.len() / devices.len();
-    let shifts: Vec<VehicleShift> = devices
+/// Reassigns clusters to the nearest segment centroid without changing the size of any segment
+fn refine(clusters: &SingleVec, mut assignment: Vec<usize>, devices: usize) -> Vec<SingleVec> {
+    let mut capacities = vec![0; devices];
+    assignment
         .iter()
-        .enumerate()
-        .map(|(i, _d)| {
-            let hour = i / 60;
-            let minute = i % 60;
-            VehicleShift {
-                start: ShiftStart {
-                    earliest: format!(
-                        "2022-05-29T{}:{}:10Z",
-                        if hour < 10 {
-                            format!("0{}", hour)
-                        } else {
-                            hour.to_string()
-                        },
-                        if minute < 10 {
-                            format!("0{}", minute)
-                        } else {
-                            minute.to_string()
-                        },
-                    ),
-                    latest: None,
-                    location: (services[tour_size * i][0], services[tour_size * i][1]).to_loc(),
-                },
-                end: None,
-                dispatch: None,
-                breaks: None,
-                reloads: None,
+        .for_each(|device| capacities[*device] += 1);
+    let mean_lat = clusters.iter().map(|p| p[0]).sum::<f64>() / clusters.len() as f64;
+    let scale = mean_lat.to_radians().cos();
+
+    for _ in 0..REFINE_ROUNDS {
+        let mut groups = vec![SingleVec::new(); devices];
+        for (point, device) in clusters.iter().zip(assignment.iter()) {
+            groups[*device].push(*point);
+        }
+        let centroids: SingleVec = groups.iter().map(utils::centroid).collect();
+
+        let mut pairs: Vec<(f64, usize, usize)> = clusters
+            .iter()
+            .enumerate()
+            .flat_map(|(i, point)| {
+                centroids
+                    .iter()
+                    .enumerate()
+                    .map(move |(device, c)| (rough_distance(point, c, scale), i, device))
+            })
+            .collect();
+        pairs.par_sort_unstable_by(|a, b| a.0.total_cmp(&b.0));
+
+        let mut remaining = capacities.clone();
+        let mut new_assignment = vec![usize::MAX; clusters.len()];
+        for (_, i, device) in pairs {
+            if new_assignment[i] == usize::MAX && remaining[device] > 0 {
+                new_assignment[i] = device;
+                remaining[device] -= 1;
             }
-        })
-        .collect();
+        }
+        if new_assignment == assignment {
+            break;
+        }
+        assignment = new_assignment;
+    }
 
-    Problem {
-        plan: Plan {
-            clustering: None,
-            jobs: services
-                .clone()
-                .into_iter()
-                .enumerate()
-                .map(|(i, [lat, lon])| Job {
-                    services: Some(vec![JobTask {
-                        places: vec![JobPlace {
-                            times: None,
-                            location: (lat, lon).to_loc(),
-                            duration: 120.,
-                            tag: None,
-                        }],
-                        demand: None,
-                        order: None,
-                    }]),
-                    id: format!("{}", i).to_string(),
-                    pickups: None,
-                    deliveries: None,
-                    replacements: None,
-                    skills: None,
-                    value: None,
-                    group: None,
-                    compatibility: None,
-                })
-                .collect::<Vec<_>>(),
-            relations: None,
-            areas: None,
-        },
-        // objectives: None,
-        objectives: Some(vec![vec![
-            Objective::MinimizeDistance,
-            Objective::MinimizeUnassignedJobs { breaks: None },
-            // Objective::MinimizeDuration,
-            Objective::MinimizeCost,
-        ]]),
-        fleet: Fleet {
-            resources: None,
-            profiles: vec![MatrixProfile {
-                name: "normal_car".to_string(),
-                speed: None,
-            }],
-            vehicles: vec![VehicleType {
-                shifts,
-                capacity: vec![1],
-                type_id: "vehicle".to_string(),
-                vehicle_ids: devices.clone(),
-                costs: VehicleCosts {
-                    fixed: Some(22.0),
-                    distance: 0.0002,
-                    time: 0.004806,
-                },
-                profile: VehicleProfile {
-                    matrix: "normal_car".to_string(),
-                    scale: None,
-                },
-                skills: None,
-                limits: Some(VehicleLimits {
-                    max_distance: None,
-                    shift_time: None,
-                    tour_size: Some(tour_size + 1),
-                    areas: None,
-                }),
-            }],
-        },
+    let mut groups = vec![SingleVec::new(); devices];
+    for (point, device) in clusters.iter().zip(assignment) {
+        groups[device].push(*point);
     }
+    groups
 }
 
-fn get_core_problem(problem: Problem, matrices: Option<Vec<Matrix>>) -> Arc<CoreProblem> {
-    Arc::new(
-        if let Some(matrices) = matrices {
-            (problem, matrices).read_pragmatic()
-        } else {
-            problem.read_pragmatic()
-        }
-        .unwrap(),
-    )
+fn solve(route: SingleVec, time_budget: Duration) -> SingleVec {
+    let tsp = Tsp::new(&route);
+    tsp.solve(time_budget)
+        .into_iter()
+        .map(|i| route[i])
+        .collect()
+}
+
+fn improve(route: SingleVec, time_budget: Duration) -> SingleVec {
+    let tsp = Tsp::new(&route);
+    tsp.improve((0..route.len()).collect(), time_budget)
+        .into_iter()
+        .map(|i| route[i])
+        .collect()
+}
+
+/// Returns the cheapest position to insert the point at and the added length
+fn cheapest_insertion(route: &SingleVec, point: &PointArray) -> (usize, f64) {
+    (0..route.len())
+        .map(|i| {
+            let a = &route[i];
+            let b = &route[(i + 1) % route.len()];
+            (
+                i + 1,
+                haversine(a, point) + haversine(point, b) - haversine(a, b),
+            )
+        })
+        .min_by(|a, b| a.1.total_cmp(&b.1))
+        .unwrap_or((0, 0.))
 }
 
-fn get_core_solution<F: Fn(Arc<CoreProblem>) -> CoreSolution>(
-    problem: Problem,
-    matrices: Option<Vec<Matrix>>,
-    solve_func: F,
-) -> Solution {
-    let core_problem = get_core_problem(problem, matrices);
-    let core_solution = solve_func(core_problem.clone());
+/// Moves clusters from the longest loop into neighbouring loops until it can't be shortened
+fn balance_distance(routes: &mut [SingleVec], deadline: Instant) {
+    let mut lengths: Vec<f64> = routes.iter().map(loop_length).collect();
+    let max_moves: usize = routes.iter().map(|route| route.len()).sum();
 
-    let core_solution = sort_all_data(create_solution(&core_problem, &core_solution, None));
+    for _ in 0..max_moves {
+        if Instant::now() > deadline {
+            log::warn!("ran out of time while balancing routes");
+            break;
+        }
+        let longest = (0..routes.len())
+            .max_by(|a, b| lengths[*a].total_cmp(&lengths[*b]))
+            .unwrap_or(0);
+        let route = &routes[longest];
+        if route.len() < 2 {
+            break;
+        }
 
-    sort_all_data(core_solution)
+        let centroids: SingleVec = routes.iter().map(utils::centroid).collect();
+        let mut neighbors: Vec<usize> = (0..routes.len()).filter(|i| *i != longest).collect();
+        neighbors.sort_by(|a, b| {
+            haversine(&centroids[longest], &centroids[*a])
+                .total_cmp(&haversine(&centroids[longest], &centroids[*b]))
+        });
+        neighbors.truncate(BALANCE_NEIGHBORS);
+
+        // (new longest length, index in the longest route, target route, insert position)
+        let mut best: Option<(f64, usize, usize, usize)> = None;
+        for (i, point) in route.iter().enumerate() {
+            let prev = &route[(i + route.len() - 1) % route.len()];
+            let next = &route[(i + 1) % route.len()];
+            let removed = lengths[longest]
+                - (haversine(prev, point) + haversine(point, next) - haversine(prev, next));
+            for target in neighbors.iter() {
+                let (position, added) = cheapest_insertion(&routes[*target], point);
+                let new_max = removed.max(lengths[*target] + added);
+                let improves = match best {
+                    Some((length, ..)) => new_max < length,
+                    None => true,
+                };
+                if improves {
+                    best = Some((new_max, i, *target, position));
+                }
+            }
+        }
+
+        match best {
+            Some((new_max, i, target, position)) if new_max < lengths[longest] - EPSILON => {
+                let point = routes[longest].remove(i);
+                routes[target].insert(position, point);
+                lengths[longest] = loop_length(&routes[longest]);
+                lengths[target] = loop_length(&routes[target]);
+            }
+            _ => break,
+        }
+    }
 }
 
-fn sort_all_data(solution: Solution) -> Solution {
-    let mut solution = solution;
+/// Splits the routed clusters into `devices` loops, each loop is opened up when `open_path` is set.
+///
+/// `routing_time` is the time budget in seconds, `0` scales it by the number of clusters
+pub fn main(
+    clusters: SingleVec,
+    devices: usize,
+    balance: &DeviceBalance,
+    routing_time: u64,
+    open_path: Option<&OpenPath>,
+    constraints: Option<&HopConstraints>,
+    stats: &mut Stats,
+) -> Vec<SingleVec> {
+    let time = Instant::now();
+    let devices = devices.min(clusters.len());
+    if devices < 2 {
+        return vec![clusters];
+    }
+    let time_budget = if routing_time == 0 {
+        tsp::auto_time_budget(clusters.len())
+    } else {
+        Duration::from_secs(routing_time)
+    };
+    log::info!(
+        "splitting {} clusters between {} devices by {:?}",
+        clusters.len(),
+        devices,
+        balance
+    );
 
-    solution
-        .tours
-        .sort_by(|a, b| a.vehicle_id.partial_cmp(&b.vehicle_id).unwrap_or(Less));
+    let assignment = match balance {
+        DeviceBalance::Hops => split_by_hops(clusters.len(), devices),
+        DeviceBalance::Distance => split_by_distance(&clusters, devices),
+    };
+    let groups = refine(&clusters, assignment, devices);
 
-    if let Some(ref mut unassigned) = solution.unassigned {
-        unassigned.sort_by(|a, b| a.job_id.partial_cmp(&b.job_id).unwrap_or(Less));
+    let threads = rayon::current_num_threads() as f64;
+    let route_budget = time_budget.mul_f64((threads / devices as f64).min(1.));
+    let mut routes: Vec<SingleVec> = match balance {
+        DeviceBalance::Hops => groups
+            .into_par_iter()
+            .map(|group| solve(group, route_budget))
+            .collect(),
+        DeviceBalance::Distance => {
+            let mut routes: Vec<SingleVec> = groups
+                .into_par_iter()
+                .map(|group| solve(group, route_budget / 2))
+                .collect();
+            balance_distance(&mut routes, time + time_budget.mul_f64(0.75));
+            routes
+                .into_par_iter()
+                .map(|route| improve(route, route_budget / 4))
+                .collect()
+        }
+    };
+    routes.retain(|route| !route.is_empty());
+    if let Some(open_path) = open_path {
+        routes = routes
+            .into_iter()
+            .map(|route| utils::open_path(route, open_path))
+            .collect();
+    }
+    if let Some(constraints) = constraints {
+        routes = routes
+            .into_iter()
+            .map(|route| constraints::apply(route, constraints, open_path.is_none()))
+            .collect();
     }
 
-    solution
+    stats.route_time += time.elapsed().as_secs_f64();
+    log::info!(
+        "split clusters into {} routes in {:.2}s",
+        routes.len(),
+        time.elapsed().as_secs_f32()
+    );
+    routes
 }
 
-pub fn solve(services: SingleVec, generations: usize, devices: usize) -> Solution {
-    let device_strings: Vec<String> = (0..devices).map(|i| format!("device_{}", i)).collect();
-    let problem = create_problem(services, device_strings);
-    get_core_solution(problem, None, |problem: Arc<CoreProblem>| {
-        let environment = Arc::new(Environment::default());
-        let telemetry_mode = get_default_telemetry_mode(environment.logger.clone());
-        let (solution, _, _) =
-            create_default_config_builder(problem.clone(), environment, telemetry_mode)
-                .with_max_generations(Some(generations))
-                .with_max_time(Some(60))
-                .build()
-                .map(|config| Solver::new(problem, config))
-                .unwrap_or_else(|err| panic!("cannot build solver: {}", err))
-                .solve()
-                .unwrap_or_else(|err| panic!("cannot solve the problem: {}", err));
-        solution
-    })
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-// old VRP routing
-pub fn route(clusters: SingleVec) -> Vec<Vec<(f64, f64)>> {
-    let circles = solve(clusters, generations, devices);
-    let mapped_circles: Vec<Vec<(f64, f64)>> = circles
-        .tours
-        .iter()
-        .map(|p| {
-            p.stops
-                .iter()
-                .map(|x| x.clone().to_point().location.to_lat_lng())
-                .collect()
-        })
-        .collect();
+    fn grid(size: usize) -> SingleVec {
+        (0..size * size)
+            .map(|i| {
+                [
+                    40. + (i / size) as f64 * 0.001,
+                    -74. + (i % size) as f64 * 0.001,
+                ]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn splits_by_hops_evenly() {
+        let clusters = tsp::run(grid(10), 0, 1, None);
+        let mut stats = Stats::new("test".to_string(), 1);
+        let routes = main(clusters, 3, &DeviceBalance::Hops, 1, None, None, &mut stats);
+
+        let mut sizes: Vec<usize> = routes.iter().map(|route| route.len()).collect();
+        sizes.sort();
+        assert_eq!(sizes, vec![33, 33, 34]);
+    }
+
+    #[test]
+    fn splits_by_distance_without_losing_clusters() {
+        let mut clusters = grid(8);
+        // a sparse tail that makes equal hop counts uneven in length
+        clusters.extend((1..=8).map(|i| [40.02 + i as f64 * 0.005, -74.]));
+        let total = clusters.len();
+        let clusters = tsp::run(clusters, 0, 1, None);
+        let mut stats = Stats::new("test".to_string(), 1);
+        let routes = main(
+            clusters,
+            2,
+            &DeviceBalance::Distance,
+            1,
+            None,
+            None,
+            &mut stats,
+        );
+
+        assert_eq!(routes.len(), 2);
+        assert_eq!(routes.iter().map(|route| route.len()).sum::<usize>(), total);
+        let lengths: Vec<f64> = routes.iter().map(loop_length).collect();
+        let (short, long) = (lengths[0].min(lengths[1]), lengths[0].max(lengths[1]));
+        assert!(long / short < 1.5, "unbalanced loops: {:?}", lengths);
+    }
 }
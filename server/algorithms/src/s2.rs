@@ -5,6 +5,7 @@ use std::{
 };
 
 use geo::{HaversineDestination, Intersects};
+use geojson::{Feature, Geometry, Value};
 use model::api::{point_array::PointArray, single_vec::SingleVec};
 use rayon::prelude::{IntoParallelIterator, ParallelIterator};
 use s2::{
@@ -430,17 +431,45 @@ pub fn from_cell_id_to_array(cell_id: CellID) -> PointArray {
     [center.latitude().deg(), center.longitude().deg()]
 }
 
-pub fn create_cell_map(points: &SingleVec, split_level: u64) -> HashMap<u64, SingleVec> {
-    let s20cells: Vec<CellID> = points
+/// The cells at `level` that contain the points, as a single MultiPolygon feature
+pub fn cells_feature(points: &SingleVec, level: u8) -> Feature {
+    let cells: HashSet<u64> = points
         .iter()
-        .map(|point| from_array_to_cell_id(point, 20))
+        .map(|point| from_array_to_cell_id(point, level as u64).0)
+        .collect();
+    let polygons = cells
+        .into_iter()
+        .map(|cell| {
+            let mut ring: Vec<Vec<f64>> = get_polygon(&CellID(cell))
+                .iter()
+                .map(|[lat, lon]| vec![*lon, *lat])
+                .collect();
+            ring.push(ring[0].clone());
+            vec![ring]
+        })
         .collect();
+    Feature {
+        geometry: Some(Geometry::new(Value::MultiPolygon(polygons))),
+        ..Feature::default()
+    }
+}
+
+pub fn create_cell_map(points: &SingleVec, split_level: u64) -> HashMap<u64, SingleVec> {
+    create_cell_index_map(points, split_level)
+        .into_iter()
+        .map(|(cell, indexes)| (cell, indexes.into_iter().map(|i| points[i]).collect()))
+        .collect()
+}
+
+/// Same as [create_cell_map], with the indexes of the points instead of the points
+pub fn create_cell_index_map(points: &SingleVec, split_level: u64) -> HashMap<u64, Vec<usize>> {
     let mut cell_maps = HashMap::new();
-    for (i, cell) in s20cells.into_iter().enumerate() {
-        let handler = cell_maps
+    for (i, point) in points.iter().enumerate() {
+        let cell = from_array_to_cell_id(point, 20);
+        cell_maps
             .entry(cell.parent(split_level).0)
-            .or_insert(Vec::new());
-        handler.push(points[i]);
+            .or_insert(Vec::new())
+            .push(i);
     }
     cell_maps
 }
@@ -5,7 +5,7 @@ mod utils;
 
 use std::time::Instant;
 
-use model::api::{single_vec::SingleVec, Precision};
+use model::api::{point_array::PointArray, single_vec::SingleVec, Precision};
 use rayon::{
     iter::{IntoParallelRefIterator, ParallelIterator},
     slice::ParallelSliceMut,
@@ -13,6 +13,22 @@ use rayon::{
 
 use crate::rtree::{self, SortDedupe};
 
+/// Center of the smallest circle around `points`, if it fits within the radius
+pub fn enclose(
+    radius: Precision,
+    points: &[PointArray],
+    max_attempts: usize,
+) -> Option<PointArray> {
+    match sec::multi_attempt(
+        points.iter().map(|p| geo::Point::new(p[1], p[0])),
+        radius,
+        max_attempts,
+    ) {
+        sec::SmallestEnclosingCircle::Centered(center) => Some([center.y(), center.x()]),
+        _ => None,
+    }
+}
+
 pub fn with_data(radius: Precision, points: &SingleVec, clusters: &SingleVec) -> SingleVec {
     let time = Instant::now();
     log::info!("centering clusters on their points");
@@ -1,33 +1,106 @@
 use std::{ops::AddAssign, time::Instant};
 
 use geo::{HaversineDistance, Point};
-use hashbrown::HashSet;
-use model::api::{single_vec::SingleVec, Precision};
-use serde::{ser::SerializeStruct, Serialize};
+use hashbrown::{HashMap, HashSet};
+use model::api::{args::HopConstraints, point_array::PointArray, single_vec::SingleVec, Precision};
+use rayon::prelude::{IndexedParallelIterator, IntoParallelRefIterator, ParallelIterator};
+use rstar::{primitives::GeomWithData, RTree};
+use serde::{ser::SerializeStruct, Deserialize, Serialize};
 
-use crate::rtree::{self, cluster::Cluster, cluster_info, point};
+use crate::{
+    routing::constraints,
+    rtree::{self, cluster::Cluster, cluster_info, point},
+};
 
 const WIDTH: &str = "=======================================================================";
+/// Lower bounds of the hop distance histogram bins, in meters
+const HOP_BINS: [Precision; 9] = [0., 50., 100., 250., 500., 1_000., 2_500., 5_000., 10_000.];
 
-#[derive(Debug, Clone)]
+/// Stats for a single cluster, only generated when detailed stats are requested
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClusterDetail {
+    pub center: PointArray,
+    /// Data points within the radius of the cluster
+    pub point_count: usize,
+    /// Data points that no other cluster covers
+    pub unique_points: usize,
+    /// Meters to the next cluster of the route
+    pub next_distance: Precision,
+    /// Token of the level 20 S2 cell that the cluster is in
+    pub cell_id: String,
+}
+
+/// Properties that a plugin returned for one of its points
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PointProperties {
+    pub point: PointArray,
+    pub properties: serde_json::Map<String, serde_json::Value>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistogramBin {
+    pub min: Precision,
+    pub max: Precision,
+    pub count: usize,
+}
+
+#[derive(Debug, Clone, Deserialize)]
 pub struct Stats {
+    #[serde(skip)]
     stats_start_time: Option<Instant>,
+    #[serde(skip)]
     label: String,
+    #[serde(skip)]
     min_points: usize,
 
     pub best_clusters: SingleVec,
     pub best_cluster_point_count: usize,
     pub worst_cluster_point_count: usize,
+    #[serde(default)]
     pub worst_cluster_count: usize,
     pub cluster_time: Precision,
     pub route_time: Precision,
     pub stats_time: Precision,
     pub total_points: usize,
     pub points_covered: usize,
+    pub total_weight: Precision,
+    pub covered_weight: Precision,
+    pub timed_spawns: usize,
+    pub timed_spawns_hit: usize,
+    pub hop_violations: usize,
+    pub total_cooldown: Precision,
     pub total_clusters: usize,
+    /// Fewest clusters that could cover the data points, only set by the optimal cluster mode
+    pub lower_bound: usize,
+    /// How far `total_clusters` may be from optimal, as a fraction of `total_clusters`
+    pub optimality_gap: Precision,
+    /// Clusters removed by the local search pass
+    pub clusters_eliminated: usize,
+    /// Whether the clusters and routes came from the result cache, `None` when it wasn't used
+    #[serde(default)]
+    pub cache_hit: Option<bool>,
+    /// Warnings returned by plugins using the JSON protocol and cells that a plugin failed on
+    #[serde(default)]
+    pub plugin_warnings: Vec<String>,
+    /// Plugin runs that failed, e.g. timed out or exited with an error, along with their stderr
+    #[serde(default)]
+    pub plugin_errors: Vec<String>,
+    /// Stats returned by plugins using the JSON protocol, one entry per plugin run
+    #[serde(default)]
+    pub plugin_stats: Vec<serde_json::Value>,
+    /// Properties that plugins using the JSON protocol returned for their points
+    #[serde(default)]
+    pub plugin_properties: Vec<PointProperties>,
+    /// Routes that a routing plugin split the clusters into, they're moved to the response
+    #[serde(skip)]
+    pub plugin_routes: Vec<SingleVec>,
     pub total_distance: Precision,
     pub longest_distance: Precision,
     pub mygod_score: usize,
+    pub routes: Vec<Stats>,
+    pub cluster_details: Vec<ClusterDetail>,
+    pub size_histogram: Vec<HistogramBin>,
+    pub distance_histogram: Vec<HistogramBin>,
 }
 
 impl Stats {
@@ -42,16 +115,45 @@ impl Stats {
             stats_time: 0.,
             total_points: 0,
             points_covered: 0,
+            total_weight: 0.,
+            covered_weight: 0.,
+            timed_spawns: 0,
+            timed_spawns_hit: 0,
+            hop_violations: 0,
+            total_cooldown: 0.,
             total_clusters: 0,
+            lower_bound: 0,
+            optimality_gap: 0.,
+            clusters_eliminated: 0,
+            cache_hit: None,
+            plugin_warnings: vec![],
+            plugin_errors: vec![],
+            plugin_stats: vec![],
+            plugin_properties: vec![],
+            plugin_routes: vec![],
             total_distance: 0.,
             longest_distance: 0.,
             mygod_score: 0,
+            routes: vec![],
+            cluster_details: vec![],
+            size_histogram: vec![],
+            distance_histogram: vec![],
             stats_start_time: None,
             label,
             min_points,
         }
     }
 
+    /// Replaces the stats with the ones saved alongside a cached result
+    pub fn restore(&mut self, cached: Stats) {
+        *self = Stats {
+            stats_start_time: None,
+            label: self.label.clone(),
+            min_points: self.min_points,
+            ..cached
+        };
+    }
+
     pub fn get_score(&self) -> usize {
         self.total_clusters * self.min_points + (self.total_points - self.points_covered)
     }
@@ -78,7 +180,7 @@ impl Stats {
             )
         };
         log::info!(
-            "\n{}{}{}{}{}{}{}{}  {}==\n",
+            "\n{}{}{}{}{}{}{}{}{}{}{}{}{}{}  {}==\n",
             get_row("[STATS] ".to_string(), false),
             if let Some(area) = area {
                 if area.is_empty() {
@@ -96,6 +198,66 @@ impl Stats {
                 ),
                 true
             ),
+            if self.total_weight > 0. {
+                get_row(
+                    format!(
+                        "|| [WEIGHT] Total: {:.2} | Covered: {:.2}",
+                        self.total_weight, self.covered_weight,
+                    ),
+                    true,
+                )
+            } else {
+                "".to_string()
+            },
+            if self.timed_spawns > 0 {
+                get_row(
+                    format!(
+                        "|| [TIMED SPAWNS] Covered: {} | Hit While Active: {}",
+                        self.timed_spawns, self.timed_spawns_hit,
+                    ),
+                    true,
+                )
+            } else {
+                "".to_string()
+            },
+            if self.hop_violations > 0 || self.total_cooldown > 0. {
+                get_row(
+                    format!(
+                        "|| [HOPS] Violations: {} | Cooldown: {:.0}s",
+                        self.hop_violations, self.total_cooldown,
+                    ),
+                    true,
+                )
+            } else {
+                "".to_string()
+            },
+            if self.lower_bound > 0 {
+                get_row(
+                    format!(
+                        "|| [OPTIMAL] Lower Bound: {} | Gap: {:.2}%",
+                        self.lower_bound,
+                        self.optimality_gap * 100.,
+                    ),
+                    true,
+                )
+            } else {
+                "".to_string()
+            },
+            if self.clusters_eliminated > 0 {
+                get_row(
+                    format!("|| [LOCAL SEARCH] Eliminated: {}", self.clusters_eliminated),
+                    true,
+                )
+            } else {
+                "".to_string()
+            },
+            match self.cache_hit {
+                Some(hit) => get_row(
+                    format!("|| [CACHE] {}", if hit { "Hit" } else { "Miss" }),
+                    true,
+                ),
+                None => "".to_string(),
+            },
             get_row(
                 format!(
                     "|| [CLUSTERS] Total: {} | Avg Points: {}",
@@ -140,15 +302,22 @@ impl Stats {
             ),
             get_row(format!("|| [MYGOD_SCORE] {}", self.mygod_score,), true),
             WIDTH,
-        )
+        );
+        for route in self.routes.iter() {
+            route.log(None);
+        }
     }
 
-    pub fn distance_stats(&mut self, clusters: &SingleVec) {
+    /// Distance stats for the route, `closed` includes the leg from the last cluster back to the first
+    pub fn distance_stats(&mut self, clusters: &SingleVec, closed: bool) {
         self.start_timer();
         log::info!("generating distance stats for {} points", clusters.len());
         self.total_distance = 0.;
         self.longest_distance = 0.;
         for (i, point) in clusters.iter().enumerate() {
+            if !closed && i == clusters.len() - 1 {
+                break;
+            }
             let point = Point::new(point[1], point[0]);
             let point2 = if i == clusters.len() - 1 {
                 Point::new(clusters[0][1], clusters[0][0])
@@ -168,6 +337,230 @@ impl Stats {
         self.stop_timer();
     }
 
+    /// Sets the lower bound from an exact clustering run, call after `cluster_stats`
+    pub fn set_lower_bound(&mut self, lower_bound: usize) {
+        self.lower_bound = lower_bound;
+        self.set_optimality_gap();
+    }
+
+    fn set_optimality_gap(&mut self) {
+        self.optimality_gap = if self.lower_bound > 0 && self.total_clusters > 0 {
+            self.total_clusters.saturating_sub(self.lower_bound) as Precision
+                / self.total_clusters as Precision
+        } else {
+            0.
+        };
+    }
+
+    /// Counts the hops that break the max hop distance and estimates the total cooldown time
+    pub fn hop_stats(&mut self, clusters: &SingleVec, constraints: &HopConstraints, closed: bool) {
+        (self.hop_violations, self.total_cooldown) =
+            constraints::hop_stats(clusters, constraints, closed);
+    }
+
+    /// Generates stats for each route when the clusters were split between multiple devices.
+    /// Data points are attributed to the route of their closest cluster.
+    pub fn route_stats(
+        &mut self,
+        radius: Precision,
+        points: &SingleVec,
+        routes: &[SingleVec],
+        closed: bool,
+        constraints: Option<&HopConstraints>,
+    ) {
+        let tree = RTree::bulk_load(
+            routes
+                .iter()
+                .enumerate()
+                .flat_map(|(i, route)| route.iter().map(move |c| GeomWithData::new(*c, i)))
+                .collect(),
+        );
+        let mut route_points = vec![SingleVec::new(); routes.len()];
+        for point in points.iter() {
+            if let Some(nearest) = tree.nearest_neighbor(point) {
+                route_points[nearest.data].push(*point);
+            }
+        }
+
+        self.routes = routes
+            .iter()
+            .zip(route_points.iter())
+            .enumerate()
+            .map(|(i, (route, points))| {
+                let mut stats =
+                    Stats::new(format!("{} | Route {}", self.label, i + 1), self.min_points);
+                stats.route_time = self.route_time;
+                stats.cluster_stats(radius, points, route);
+                stats.distance_stats(route, closed);
+                if let Some(constraints) = constraints {
+                    stats.hop_stats(route, constraints, closed);
+                }
+                stats.set_score();
+                stats
+            })
+            .collect();
+        self.sum_routes();
+    }
+
+    /// Sets the stats of routes that were generated separately and totals them
+    pub fn set_routes(&mut self, routes: Vec<Stats>) {
+        self.routes = routes;
+        self.sum_routes();
+        self.timed_spawns = self.routes.iter().map(|r| r.timed_spawns).sum();
+        self.timed_spawns_hit = self.routes.iter().map(|r| r.timed_spawns_hit).sum();
+    }
+
+    fn sum_routes(&mut self) {
+        self.total_distance = self.routes.iter().map(|r| r.total_distance).sum();
+        self.longest_distance = self
+            .routes
+            .iter()
+            .map(|r| r.longest_distance)
+            .fold(0., Precision::max);
+        self.hop_violations = self.routes.iter().map(|r| r.hop_violations).sum();
+        self.total_cooldown = self.routes.iter().map(|r| r.total_cooldown).sum();
+    }
+
+    /// Generates a record for every cluster and histograms of the cluster sizes and hop distances.
+    /// The next hop of each cluster stays within its own route, `closed` routes loop back to their first cluster
+    pub fn cluster_details(
+        &mut self,
+        radius: Precision,
+        points: &SingleVec,
+        routes: &[SingleVec],
+        closed: bool,
+    ) {
+        self.start_timer();
+        let tree = rtree::spawn(radius, points);
+        let covered: Vec<Vec<&point::Point>> = routes
+            .iter()
+            .flat_map(|route| route.iter())
+            .map(|cluster| tree.locate_all_at_point(cluster).collect())
+            .collect();
+        let mut cover_counts: HashMap<&point::Point, usize> = HashMap::new();
+        covered
+            .iter()
+            .flatten()
+            .for_each(|point| *cover_counts.entry(*point).or_default() += 1);
+
+        let mut covered = covered.into_iter();
+        self.cluster_details = routes
+            .iter()
+            .flat_map(|route| {
+                route.iter().enumerate().map(move |(i, cluster)| {
+                    let next_distance = if i + 1 < route.len() {
+                        Some(route[i + 1])
+                    } else if closed && route.len() > 1 {
+                        Some(route[0])
+                    } else {
+                        None
+                    }
+                    .map(|next| {
+                        Point::new(cluster[1], cluster[0])
+                            .haversine_distance(&Point::new(next[1], next[0]))
+                    })
+                    .unwrap_or(0.);
+                    (*cluster, next_distance)
+                })
+            })
+            .map(|(center, next_distance)| {
+                let points = covered.next().unwrap_or_default();
+                ClusterDetail {
+                    center,
+                    point_count: points.len(),
+                    unique_points: points
+                        .iter()
+                        .filter(|point| cover_counts.get(*point) == Some(&1))
+                        .count(),
+                    next_distance,
+                    cell_id: point::Point::new(radius, 20, center).cell_id.to_token(),
+                }
+            })
+            .collect();
+        self.set_histograms();
+        log::info!(
+            "cluster details complete {:.4}s",
+            self.stats_start_time.unwrap().elapsed().as_secs_f32()
+        );
+        self.stop_timer();
+    }
+
+    fn set_histograms(&mut self) {
+        let max_size = self
+            .cluster_details
+            .iter()
+            .map(|detail| detail.point_count)
+            .max()
+            .unwrap_or(0);
+        // the bins double in width, `0`, `1`, `2-3`, `4-7`, ...
+        let mut size_histogram = vec![];
+        let mut min = 0;
+        while min <= max_size {
+            let max = if min == 0 { 0 } else { min * 2 - 1 };
+            size_histogram.push(HistogramBin {
+                min: min as Precision,
+                max: max as Precision,
+                count: self
+                    .cluster_details
+                    .iter()
+                    .filter(|detail| detail.point_count >= min && detail.point_count <= max)
+                    .count(),
+            });
+            min = max + 1;
+        }
+        self.size_histogram = size_histogram;
+
+        let longest = self
+            .cluster_details
+            .iter()
+            .map(|detail| detail.next_distance)
+            .fold(0., Precision::max);
+        self.distance_histogram = HOP_BINS
+            .iter()
+            .enumerate()
+            .map(|(i, min)| {
+                let max = HOP_BINS.get(i + 1).copied().unwrap_or(longest.max(*min));
+                HistogramBin {
+                    min: *min,
+                    max,
+                    count: self
+                        .cluster_details
+                        .iter()
+                        .filter(|detail| {
+                            detail.next_distance >= *min
+                                && (detail.next_distance < max || i + 1 == HOP_BINS.len())
+                        })
+                        .count(),
+                }
+            })
+            .collect();
+    }
+
+    /// Totals the weight of the data points and the weight covered by the clusters,
+    /// `weights` is expected to line up with `points`
+    pub fn weight_stats(
+        &mut self,
+        radius: Precision,
+        points: &SingleVec,
+        weights: &[Precision],
+        clusters: &SingleVec,
+    ) {
+        self.start_timer();
+        let tree = rtree::spawn(radius, clusters);
+        self.total_weight = weights.iter().sum();
+        self.covered_weight = points
+            .par_iter()
+            .zip(weights.par_iter())
+            .filter(|(point, _)| tree.locate_at_point(point).is_some())
+            .map(|(_, weight)| weight)
+            .sum();
+        log::info!(
+            "weight stats complete {:.4}s",
+            self.stats_start_time.unwrap().elapsed().as_secs_f32()
+        );
+        self.stop_timer();
+    }
+
     pub fn set_cluster_time(&mut self, time: Instant) {
         self.cluster_time = time.elapsed().as_secs_f64();
         log::debug!("Cluster Time: {}s", self.cluster_time as Precision);
@@ -265,19 +658,38 @@ impl Serialize for Stats {
     where
         S: serde::Serializer,
     {
-        let mut state = serializer.serialize_struct("Stats", 11)?;
+        let mut state = serializer.serialize_struct("Stats", 31)?;
         state.serialize_field("best_clusters", &self.best_clusters)?;
         state.serialize_field("best_cluster_point_count", &self.best_cluster_point_count)?;
         state.serialize_field("worst_cluster_point_count", &self.worst_cluster_point_count)?;
+        state.serialize_field("worst_cluster_count", &self.worst_cluster_count)?;
         state.serialize_field("cluster_time", &self.cluster_time)?;
         state.serialize_field("route_time", &self.route_time)?;
         state.serialize_field("stats_time", &self.stats_time)?;
         state.serialize_field("total_points", &self.total_points)?;
         state.serialize_field("points_covered", &self.points_covered)?;
+        state.serialize_field("total_weight", &self.total_weight)?;
+        state.serialize_field("covered_weight", &self.covered_weight)?;
+        state.serialize_field("timed_spawns", &self.timed_spawns)?;
+        state.serialize_field("timed_spawns_hit", &self.timed_spawns_hit)?;
+        state.serialize_field("hop_violations", &self.hop_violations)?;
+        state.serialize_field("total_cooldown", &self.total_cooldown)?;
         state.serialize_field("total_clusters", &self.total_clusters)?;
+        state.serialize_field("lower_bound", &self.lower_bound)?;
+        state.serialize_field("optimality_gap", &self.optimality_gap)?;
+        state.serialize_field("clusters_eliminated", &self.clusters_eliminated)?;
+        state.serialize_field("cache_hit", &self.cache_hit)?;
+        state.serialize_field("plugin_warnings", &self.plugin_warnings)?;
+        state.serialize_field("plugin_errors", &self.plugin_errors)?;
+        state.serialize_field("plugin_stats", &self.plugin_stats)?;
+        state.serialize_field("plugin_properties", &self.plugin_properties)?;
         state.serialize_field("total_distance", &self.total_distance)?;
         state.serialize_field("longest_distance", &self.longest_distance)?;
         state.serialize_field("mygod_score", &self.mygod_score)?;
+        state.serialize_field("routes", &self.routes)?;
+        state.serialize_field("cluster_details", &self.cluster_details)?;
+        state.serialize_field("size_histogram", &self.size_histogram)?;
+        state.serialize_field("distance_histogram", &self.distance_histogram)?;
         state.end()
     }
 }
@@ -295,9 +707,27 @@ impl<'a> AddAssign<&'a Self> for Stats {
         self.stats_time += rhs.stats_time;
         self.total_points += rhs.total_points;
         self.points_covered += rhs.points_covered;
+        self.total_weight += rhs.total_weight;
+        self.covered_weight += rhs.covered_weight;
+        self.timed_spawns += rhs.timed_spawns;
+        self.timed_spawns_hit += rhs.timed_spawns_hit;
+        self.hop_violations += rhs.hop_violations;
+        self.total_cooldown += rhs.total_cooldown;
         self.total_clusters += rhs.total_clusters;
+        self.lower_bound += rhs.lower_bound;
+        self.clusters_eliminated += rhs.clusters_eliminated;
+        self.set_optimality_gap();
         self.total_distance += rhs.total_distance;
         self.longest_distance += rhs.longest_distance;
+        self.routes.extend(rhs.routes.clone());
+        self.plugin_warnings.extend(rhs.plugin_warnings.clone());
+        self.plugin_errors.extend(rhs.plugin_errors.clone());
+        self.plugin_stats.extend(rhs.plugin_stats.clone());
+        self.plugin_properties.extend(rhs.plugin_properties.clone());
+        if !rhs.cluster_details.is_empty() {
+            self.cluster_details.extend(rhs.cluster_details.clone());
+            self.set_histograms();
+        }
         self.set_score();
     }
 }
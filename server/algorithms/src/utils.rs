@@ -6,11 +6,12 @@ use std::io::Write;
 use std::path::Path;
 
 use colored::Colorize;
-use geo::Coord;
+use geo::{Coord, HaversineDistance};
 use geohash::encode;
 use hashbrown::HashSet;
-use model::api::{point_array::PointArray, single_vec::SingleVec};
+use model::api::{args::OpenPath, point_array::PointArray, single_vec::SingleVec};
 
+use crate::plugin::manifest;
 use crate::rtree::cluster::Cluster;
 use crate::stats::Stats;
 
@@ -143,6 +144,47 @@ pub fn rotate_to_best(clusters: SingleVec, stats: &Stats) -> SingleVec {
     final_clusters.into()
 }
 
+/// Cuts a loop into an open path, picking the edge to remove and the direction to travel in
+/// so the path best fits its start and end points
+pub fn open_path(clusters: SingleVec, open_path: &OpenPath) -> SingleVec {
+    let n = clusters.len();
+    if n < 2 {
+        return clusters;
+    }
+    let distance = |a: &PointArray, b: &PointArray| {
+        geo::Point::new(a[1], a[0]).haversine_distance(&geo::Point::new(b[1], b[0]))
+    };
+    let to_end = |end: &Option<PointArray>, point: &PointArray| match end {
+        Some(end) => distance(end, point),
+        None => 0.,
+    };
+
+    // (added distance, cut after index, reversed)
+    let mut best = (f64::MAX, 0, false);
+    for i in 0..n {
+        let a = &clusters[i];
+        let b = &clusters[(i + 1) % n];
+        let removed = distance(a, b);
+        let forward = to_end(&open_path.start, b) + to_end(&open_path.end, a) - removed;
+        let reversed = to_end(&open_path.start, a) + to_end(&open_path.end, b) - removed;
+        if forward < best.0 {
+            best = (forward, i, false);
+        }
+        if reversed < best.0 {
+            best = (reversed, i, true);
+        }
+    }
+
+    let (_, cut, reversed) = best;
+    let mut path: VecDeque<PointArray> = clusters.into();
+    path.rotate_left((cut + 1) % n);
+    let mut path: SingleVec = path.into();
+    if reversed {
+        path.reverse();
+    }
+    path
+}
+
 pub fn get_plugin_list(path: &str) -> std::io::Result<Vec<String>> {
     let path = Path::new(path);
 
@@ -151,7 +193,7 @@ pub fn get_plugin_list(path: &str) -> std::io::Result<Vec<String>> {
         .filter_map(|path| {
             if let Ok(ext) = path {
                 let plugin = ext.split("/").last().unwrap_or("").to_string();
-                if plugin == ".gitkeep" {
+                if plugin == ".gitkeep" || plugin.ends_with(manifest::SUFFIX) {
                     None
                 } else {
                     Some(Ok(plugin))
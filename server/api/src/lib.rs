@@ -31,6 +31,9 @@ pub async fn start() -> io::Result<()> {
         Err(err) => log::error!("Migration Error {:?}", err),
     };
 
+    algorithms::manifest::all();
+    utils::jobs::spawn_runner(databases.clone())?;
+
     let path = || {
         if is_docker().is_ok() {
             "./dist"
@@ -125,8 +128,11 @@ pub async fn start() -> io::Result<()> {
                                 .service(public::v1::calculate::bootstrap)
                                 .service(public::v1::calculate::route_stats)
                                 .service(public::v1::calculate::route_stats_category)
+                                .service(public::v1::calculate::route_diff)
+                                .service(public::v1::calculate::coverage_gaps)
                                 .service(public::v1::calculate::reroute)
                                 .service(public::v1::calculate::calculate_area)
+                                .service(public::v1::calculate::cluster_stream)
                                 .service(public::v1::calculate::cluster),
                         )
                         .service(
@@ -166,7 +172,21 @@ pub async fn start() -> io::Result<()> {
                                 .service(public::v1::s2::cell_polygons)
                                 .service(public::v1::s2::s2_cells),
                         )
-                        .service(web::scope("/info").service(public::v1::info::main)),
+                        .service(
+                            web::scope("/info")
+                                .service(public::v1::info::main)
+                                .service(public::v1::info::plugins)
+                                .service(public::v1::info::validate_plugin),
+                        )
+                        .service(
+                            web::scope("/jobs")
+                                .service(public::v1::jobs::bootstrap)
+                                .service(public::v1::jobs::cluster)
+                                .service(public::v1::jobs::status)
+                                .service(public::v1::jobs::result)
+                                .service(public::v1::jobs::result_return_type)
+                                .service(public::v1::jobs::cancel),
+                        ),
                 ),
             )
             .service(
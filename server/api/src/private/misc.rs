@@ -8,7 +8,7 @@ use crate::{
 use actix_session::Session;
 use actix_web::http::header;
 
-use algorithms::{bootstrap, clustering, routing};
+use algorithms::{bootstrap, clustering, manifest, routing};
 use geojson::Value;
 use model::{api::args::Auth, KojiDb};
 use serde_json::json;
@@ -44,6 +44,7 @@ async fn config(conn: web::Data<KojiDb>, session: Session) -> Result<HttpRespons
         route_plugins,
         clustering_plugins,
         bootstrap_plugins,
+        plugin_manifests: manifest::all(),
     }))
 }
 
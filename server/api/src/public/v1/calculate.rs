@@ -1,33 +1,91 @@
-use crate::utils::{request, response::Response};
+use std::time::Instant;
+
+use crate::utils::{
+    cache,
+    error::CalculationError,
+    events::{self, Event},
+    jobs::{self, Tracker},
+    request,
+    response::Response,
+};
 
 use super::*;
 
-use algorithms::{self, clustering, routing, stats::Stats};
+use algorithms::{
+    self, clustering, context::Context, coverage, partition, routing,
+    routing::time_aware::SpawnSchedule, s2, stats::Stats,
+};
 use geo::{ChamberlainDuquetteArea, MultiPolygon, Polygon};
 
 use geojson::Value;
 use model::{
     api::{
-        args::{Args, ArgsUnwrapped},
+        args::{
+            ApiQueryArgs, Args, ArgsUnwrapped, DataPointsArg, HopConstraints, ReturnTypeArg,
+            RouteArg, RouteDiffArgs,
+        },
+        calc_mode::CalculationMode,
+        collection,
+        point_array::PointArray,
+        single_vec::SingleVec,
         sort_by::SortBy,
-        FeatureHelpers, GeoFormats, ToCollection, ToFeature, ToSingleVec,
+        FeatureHelpers, GeoFormats, Precision, ToCollection, ToFeature, ToSingleVec,
+    },
+    db::{
+        area, geofence, instance, route,
+        sea_orm_active_enums::{JobPhase, Type},
+        spawnpoint,
     },
-    db::{area, geofence, instance, route, sea_orm_active_enums::Type},
     KojiDb, ScannerType,
 };
 use serde_json::json;
 
+/// Result of a calculation, before it's converted to the requested return type
+pub struct Calculation {
+    pub collection: FeatureCollection,
+    pub stats: Stats,
+    pub instance: String,
+}
+
 #[post("/bootstrap")]
 async fn bootstrap(
     conn: web::Data<KojiDb>,
     payload: web::Json<Args>,
 ) -> Result<HttpResponse, Error> {
+    let args = payload.into_inner().init(Some("bootstrap"));
+    let return_type = args.return_type.clone();
+    let benchmark_mode = args.benchmark_mode;
+
+    let conn = conn.get_ref().clone();
+    let Calculation {
+        collection,
+        stats,
+        instance,
+    } = jobs::run_detached(move |context| async move {
+        run_bootstrap(&conn, args, &Tracker::new(context)).await
+    })
+    .await?;
+
+    Ok(utils::response::send(
+        collection,
+        return_type,
+        Some(stats),
+        benchmark_mode,
+        Some(instance),
+    ))
+}
+
+/// Bootstraps the requested area, stops early once the tracker is cancelled
+pub async fn run_bootstrap(
+    conn: &KojiDb,
+    args: ArgsUnwrapped,
+    tracker: &Tracker<'_>,
+) -> Result<Calculation, CalculationError> {
+    tracker.context.set_args(&args);
     let ArgsUnwrapped {
         area,
-        benchmark_mode,
         instance,
         radius,
-        return_type,
         save_to_db,
         save_to_scanner,
         calculation_mode,
@@ -37,20 +95,23 @@ async fn bootstrap(
         sort_by,
         route_split_level,
         routing_args,
+        routing_time,
         bootstrapping_args,
+        fixed_grid,
+        neighbors,
         ..
-    } = payload.into_inner().init(Some("bootstrap"));
+    } = args;
 
     if area.features.is_empty() && instance.is_empty() && parent.is_none() {
-        return Ok(
-            HttpResponse::BadRequest().json(Response::send_error("no_area_and_empty_instance"))
-        );
+        return Err(CalculationError::BadRequest("no_area_and_empty_instance"));
     }
 
-    let area = utils::create_or_find_collection(&instance, &conn, area, &parent, &vec![])
+    tracker.enter(JobPhase::FetchingPoints).await?;
+    let area = utils::create_or_find_collection(&instance, conn, area, &parent, &vec![])
         .await
-        .map_err(actix_web::error::ErrorInternalServerError)?;
+        .map_err(CalculationError::internal)?;
 
+    tracker.enter(JobPhase::Clustering).await?;
     let mut stats = Stats::new(format!("Bootstrap | {:?}", calculation_mode), 1);
 
     let mut features: Vec<Feature> = algorithms::bootstrap::main(
@@ -63,9 +124,15 @@ async fn bootstrap(
         route_split_level,
         &mut stats,
         &routing_args,
+        routing_time,
         &bootstrapping_args,
+        fixed_grid,
+        &neighbors,
+        &tracker.context,
     );
+    CalculationError::check_plugins(&stats)?;
 
+    tracker.enter(JobPhase::Stats).await?;
     if parent.is_some() {
         let mut condensed = vec![];
         features
@@ -87,7 +154,7 @@ async fn bootstrap(
     let instance = if let Some(parent) = parent {
         let model = geofence::Query::get_one(&conn.koji, parent.to_string())
             .await
-            .map_err(actix_web::error::ErrorInternalServerError)?;
+            .map_err(CalculationError::internal)?;
         model.name
     } else {
         instance
@@ -109,7 +176,7 @@ async fn bootstrap(
         if save_to_db {
             route::Query::upsert_from_geometry(&conn.koji, GeoFormats::Feature(feat.clone()))
                 .await
-                .map_err(actix_web::error::ErrorInternalServerError)?;
+                .map_err(CalculationError::internal)?;
         }
         if save_to_scanner {
             if conn.scanner_type == ScannerType::Unown {
@@ -126,17 +193,45 @@ async fn bootstrap(
                 )
                 .await
             }
-            .map_err(actix_web::error::ErrorInternalServerError)?;
+            .map_err(CalculationError::internal)?;
         }
     }
     if save_to_scanner {
-        request::update_project_api(&conn, Some(&conn.scanner_type))
+        request::update_project_api(conn, Some(&conn.scanner_type))
             .await
-            .map_err(actix_web::error::ErrorInternalServerError)?;
+            .map_err(CalculationError::internal)?;
     }
 
+    Ok(Calculation {
+        collection: features.to_collection(Some(instance.clone()), None),
+        stats,
+        instance,
+    })
+}
+
+#[post("/{mode}/{category}")]
+async fn cluster(
+    conn: web::Data<KojiDb>,
+    url: actix_web::web::Path<(String, String)>,
+    payload: web::Json<Args>,
+) -> Result<HttpResponse, Error> {
+    let (mode, category) = url.into_inner();
+    let args = payload.into_inner().init(Some(&mode));
+    let return_type = args.return_type.clone();
+    let benchmark_mode = args.benchmark_mode;
+
+    let conn = conn.get_ref().clone();
+    let Calculation {
+        collection,
+        stats,
+        instance,
+    } = jobs::run_detached(move |context| async move {
+        run_cluster(&conn, &mode, &category, args, &Tracker::new(context)).await
+    })
+    .await?;
+
     Ok(utils::response::send(
-        features.to_collection(Some(instance.clone()), None),
+        collection,
         return_type,
         Some(stats),
         benchmark_mode,
@@ -144,24 +239,48 @@ async fn bootstrap(
     ))
 }
 
-#[post("/{mode}/{category}")]
-async fn cluster(
+/// Same as [cluster], but streams the progress of the calculation as server-sent events
+#[post("/{mode}/{category}/stream")]
+async fn cluster_stream(
     conn: web::Data<KojiDb>,
     url: actix_web::web::Path<(String, String)>,
     payload: web::Json<Args>,
 ) -> Result<HttpResponse, Error> {
     let (mode, category) = url.into_inner();
+    let args = payload.into_inner().init(Some(&mode));
+    let return_type = args.return_type.clone();
+    let benchmark_mode = args.benchmark_mode;
+
+    let conn = conn.get_ref().clone();
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .streaming(events::stream(
+            return_type,
+            benchmark_mode,
+            move |tracker| async move {
+                run_cluster(&conn, &mode, &category, args, &tracker).await
+            },
+        )))
+}
 
+/// Clusters and routes the data points of the requested area, stops early once the tracker is
+/// cancelled
+pub async fn run_cluster(
+    conn: &KojiDb,
+    mode: &str,
+    category: &str,
+    args: ArgsUnwrapped,
+    tracker: &Tracker<'_>,
+) -> Result<Calculation, CalculationError> {
+    tracker.context.set_args(&args);
     let ArgsUnwrapped {
         area,
-        benchmark_mode,
         cluster_mode,
         cluster_split_level,
         data_points,
         instance,
         min_points,
         radius,
-        return_type,
         save_to_db,
         save_to_scanner,
         last_seen,
@@ -169,24 +288,44 @@ async fn cluster(
         tth,
         route_split_level,
         routing_args,
+        routing_time,
         calculation_mode,
         s2_level,
         s2_size,
         parent,
+        split_by_child,
         max_clusters,
         clustering_args,
+        clustering_time,
+        local_search_time,
+        fixed_grid,
         center_clusters,
+        devices,
+        device_balance,
+        open_path,
+        data_point_weights,
+        weights,
+        route_speed,
+        route_start_offset,
+        hop_constraints,
+        incremental,
+        clusters,
+        detailed_stats,
+        no_cache,
         ..
-    } = payload.into_inner().init(Some(&mode));
+    } = args;
 
     if area.features.is_empty() && instance.is_empty() && data_points.is_empty() && parent.is_none()
     {
-        return Ok(
-            HttpResponse::BadRequest().json(Response::send_error("no_area_instance_data_points"))
-        );
+        return Err(CalculationError::BadRequest("no_area_instance_data_points"));
+    }
+    if route_start_offset >= 3_600 {
+        return Err(CalculationError::BadRequest(
+            "route_start_offset_out_of_range",
+        ));
     }
     let sort_by = if mode.eq("route") && sort_by == SortBy::Unset {
-        SortBy::Custom(String::from("tsp"))
+        SortBy::Tsp
     } else {
         sort_by
     };
@@ -211,64 +350,390 @@ async fn cluster(
         }
     };
 
-    let area = utils::create_or_find_collection(&instance, &conn, area, &parent, &data_points)
+    tracker.enter(JobPhase::FetchingPoints).await?;
+    let area = utils::create_or_find_collection(&instance, conn, area, &parent, &data_points)
         .await
-        .map_err(actix_web::error::ErrorInternalServerError)?;
+        .map_err(CalculationError::internal)?;
 
-    let data_points = if data_points.is_empty() {
-        utils::points_from_area(&area, &category, &conn, last_seen, tth)
+    let (data_points, data_point_weights) = if data_points.is_empty() {
+        let points = utils::points_from_area(&area, &category.to_string(), conn, last_seen, tth)
             .await
-            .map_err(actix_web::error::ErrorInternalServerError)?
-            .to_single_vec()
+            .map_err(CalculationError::internal)?;
+        let data_point_weights = if let Some(weights) = weights {
+            points.iter().map(|point| weights.get(&point.i)).collect()
+        } else {
+            vec![]
+        };
+        (points.to_single_vec(), data_point_weights)
     } else {
-        data_points
+        (data_points, data_point_weights)
     };
+    if !data_point_weights.is_empty() && data_point_weights.len() != data_points.len() {
+        return Err(CalculationError::BadRequest(
+            "data_point_weights_do_not_match_data_points",
+        ));
+    }
 
     log::debug!(
         "[{}] Found Data Points: {}",
         mode.to_uppercase(),
         data_points.len()
     );
+    tracker.emit(|| Event::Points {
+        count: data_points.len(),
+    });
 
-    let clusters = clustering::main(
-        &data_points,
-        cluster_mode,
-        radius,
-        min_points,
-        &mut stats,
-        cluster_split_level,
-        max_clusters,
-        calculation_mode,
-        s2_level,
-        s2_size,
-        area,
-        &clustering_args,
-        center_clusters,
-    );
-    let clusters = routing::main(
-        &data_points,
-        clusters,
-        &sort_by,
-        route_split_level,
-        radius,
-        &mut stats,
-        &routing_args,
-    );
+    // children of the parent that the clusters are split between, each gets its own route
+    let children = match parent.as_ref() {
+        Some(parent) if split_by_child && !incremental => {
+            if devices > 1 {
+                log::warn!("devices is ignored when splitting a parent by its children");
+            }
+            // the internal properties carry the names of the children to their routes
+            let args = ApiQueryArgs {
+                internal: Some(true),
+                ..collection::Default::default()
+            };
+            Some(
+                geofence::Query::by_parent_with_args(&conn.koji, parent, &args)
+                    .await
+                    .map_err(CalculationError::internal)?,
+            )
+        }
+        _ => None,
+    };
 
-    let mut feature = clusters
-        .to_feature(Some(enum_type.clone()))
-        .remove_last_coord();
+    let schedule = if sort_by == SortBy::TimeAware && category == "spawnpoint" {
+        let spawns = spawnpoint::Query::area_tth(&conn.scanner, &area, last_seen)
+            .await
+            .map_err(CalculationError::internal)?;
+        Some(SpawnSchedule::new(
+            spawns
+                .into_iter()
+                .filter_map(|spawn| Some(([spawn.lat, spawn.lon], spawn.despawn_sec?)))
+                .collect(),
+            route_speed,
+            route_start_offset,
+        ))
+    } else {
+        None
+    };
+
+    let cache_key = if no_cache || incremental {
+        None
+    } else {
+        cache::Key::new(&area, &data_points, &data_point_weights)
+            .arg(mode)
+            .arg(category)
+            .arg(&cluster_mode)
+            .arg(radius)
+            .arg(min_points)
+            .arg(cluster_split_level)
+            .arg(max_clusters)
+            .arg(&calculation_mode)
+            .arg(s2_level)
+            .arg(s2_size)
+            .arg(&clustering_args)
+            .arg(clustering_time)
+            .arg(local_search_time)
+            .arg(center_clusters)
+            .arg(fixed_grid)
+            .arg(&sort_by)
+            .arg(route_split_level)
+            .arg(&routing_args)
+            .arg(routing_time)
+            .arg(devices)
+            .arg(&device_balance)
+            .arg(&open_path)
+            .arg(&hop_constraints)
+            .arg(children.is_some())
+            .arg(&schedule)
+            .finish()
+    };
+    let cached = match cache_key.as_ref() {
+        Some(key) => cache::get(conn, key).await,
+        None => None,
+    };
+
+    let (routes, route_names) = if let Some(cached) = cached {
+        log::info!("[{}] Using cached clusters and routes", mode.to_uppercase());
+        stats.restore(cached.stats);
+        stats.cache_hit = Some(true);
+        (cached.routes, cached.route_names)
+    } else {
+        tracker.enter(JobPhase::Clustering).await?;
+        let clusters = if incremental {
+            if devices > 1 {
+                return Err(CalculationError::BadRequest(
+                    "devices_not_supported_with_incremental",
+                ));
+            }
+            let existing = if clusters.is_empty() {
+                match route::Query::feature_from_name(&conn.koji, instance.clone(), false).await {
+                    Ok(feature) => feature.to_single_vec(),
+                    Err(_) => return Err(CalculationError::BadRequest("no_existing_route_found")),
+                }
+            } else {
+                clusters
+            };
+            let (existing, uncovered) =
+                routing::incremental::retain(existing, &data_points, radius);
+            let uncovered_points = uncovered.iter().map(|i| data_points[*i]).collect();
+            let uncovered_weights: Vec<_> = if data_point_weights.is_empty() {
+                vec![]
+            } else {
+                uncovered.iter().map(|i| data_point_weights[*i]).collect()
+            };
+            // only the cells of the uncovered points, not the whole area, are bootstrapped
+            let uncovered_area = if matches!(calculation_mode, CalculationMode::S2) {
+                s2::cells_feature(&uncovered_points, s2_level).to_collection(None, None)
+            } else {
+                area
+            };
+            let new_clusters = clustering::main(
+                &uncovered_points,
+                &uncovered_weights,
+                cluster_mode,
+                radius,
+                min_points,
+                &mut stats,
+                cluster_split_level,
+                max_clusters,
+                calculation_mode,
+                s2_level,
+                s2_size,
+                uncovered_area,
+                &clustering_args,
+                clustering_time,
+                local_search_time,
+                center_clusters,
+                fixed_grid,
+                &tracker.context,
+            );
+            tracker.emit(|| Event::Clusters {
+                count: new_clusters.len(),
+                cluster_time: stats.cluster_time,
+            });
+            tracker.enter(JobPhase::Routing).await?;
+            let route_time = Instant::now();
+            let clusters =
+                routing::incremental::insert(existing, new_clusters, open_path.is_none());
+            stats.set_route_time(route_time);
+
+            stats.cluster_stats(radius, &data_points, &clusters);
+            if !data_point_weights.is_empty() {
+                stats.weight_stats(radius, &data_points, &data_point_weights, &clusters);
+            }
+            // the lower bound only covers the new clusters, not the existing route
+            stats.set_lower_bound(0);
+            stats.set_score();
+            stats.distance_stats(&clusters, open_path.is_none());
+            if let Some(hop_constraints) = hop_constraints.as_ref() {
+                stats.hop_stats(&clusters, hop_constraints, open_path.is_none());
+            }
+            clusters
+        } else {
+            let clusters = clustering::main(
+                &data_points,
+                &data_point_weights,
+                cluster_mode,
+                radius,
+                min_points,
+                &mut stats,
+                cluster_split_level,
+                max_clusters,
+                calculation_mode,
+                s2_level,
+                s2_size,
+                area,
+                &clustering_args,
+                clustering_time,
+                local_search_time,
+                center_clusters,
+                fixed_grid,
+                &tracker.context,
+            );
+            tracker.emit(|| Event::Clusters {
+                count: clusters.len(),
+                cluster_time: stats.cluster_time,
+            });
+            if children.is_some() {
+                clusters
+            } else {
+                tracker.enter(JobPhase::Routing).await?;
+                routing::main(
+                    &data_points,
+                    clusters,
+                    &sort_by,
+                    route_split_level,
+                    radius,
+                    &mut stats,
+                    &routing_args,
+                    routing_time,
+                    open_path.as_ref(),
+                    schedule.as_ref(),
+                    hop_constraints.as_ref(),
+                    &tracker.context,
+                )
+            }
+        };
+        CalculationError::check_plugins(&stats)?;
+        let mut route_names = vec![];
+        let routes = if let Some(children) = children.as_ref() {
+            tracker.enter(JobPhase::Routing).await?;
+            let point_owners = partition::assign(&data_points, children);
+            let cluster_owners = partition::assign(&clusters, children);
+            let route_time = Instant::now();
+            let mut routes = vec![];
+            let mut child_stats = vec![];
+            for (i, child) in children.features.iter().enumerate() {
+                let child_clusters: SingleVec = clusters
+                    .iter()
+                    .zip(cluster_owners.iter())
+                    .filter(|(_, owner)| **owner == Some(i))
+                    .map(|(point, _)| *point)
+                    .collect();
+                if child_clusters.is_empty() {
+                    continue;
+                }
+                let child_points: SingleVec = data_points
+                    .iter()
+                    .zip(point_owners.iter())
+                    .filter(|(_, owner)| **owner == Some(i))
+                    .map(|(point, _)| *point)
+                    .collect();
+                let name = child
+                    .property("__name")
+                    .and_then(|name| name.as_str())
+                    .map(|name| name.to_string())
+                    .unwrap_or_else(|| format!("child_{}", i + 1));
+
+                let mut child_route_stats = Stats::new(name.clone(), min_points);
+                child_route_stats.cluster_stats(radius, &child_points, &child_clusters);
+                let route = routing::main(
+                    &child_points,
+                    child_clusters,
+                    &sort_by,
+                    route_split_level,
+                    radius,
+                    &mut child_route_stats,
+                    &routing_args,
+                    routing_time,
+                    open_path.as_ref(),
+                    schedule.as_ref(),
+                    hop_constraints.as_ref(),
+                    &tracker.context,
+                );
+                CalculationError::check_plugins(&child_route_stats)?;
+                let plugin_routes = take_plugin_routes(
+                    &mut child_route_stats,
+                    radius,
+                    &child_points,
+                    open_path.is_none(),
+                    hop_constraints.as_ref(),
+                );
+                child_route_stats.set_score();
+                tracker.emit(|| Event::Child {
+                    index: i,
+                    name: name.clone(),
+                    feature: route_feature(route.clone(), name.clone(), &enum_type),
+                    stats: child_route_stats.clone(),
+                });
+                child_stats.push(child_route_stats);
+                match plugin_routes {
+                    Some(plugin_routes) => {
+                        for (j, plugin_route) in plugin_routes.into_iter().enumerate() {
+                            route_names.push(format!("{}_{}", name, j + 1));
+                            routes.push(plugin_route);
+                        }
+                    }
+                    None => {
+                        route_names.push(name);
+                        routes.push(route);
+                    }
+                }
+            }
+            stats.set_route_time(route_time);
+            stats.set_routes(child_stats);
+            routes
+        } else if let Some(routes) = take_plugin_routes(
+            &mut stats,
+            radius,
+            &data_points,
+            open_path.is_none(),
+            hop_constraints.as_ref(),
+        ) {
+            if devices > 1 {
+                log::warn!("the routing plugin split the routes itself, devices is ignored");
+            }
+            routes
+        } else if devices > 1 {
+            tracker.enter(JobPhase::Routing).await?;
+            let routes = routing::vrp::main(
+                clusters,
+                devices,
+                &device_balance,
+                routing_time,
+                open_path.as_ref(),
+                hop_constraints.as_ref(),
+                &mut stats,
+            );
+            stats.route_stats(
+                radius,
+                &data_points,
+                &routes,
+                open_path.is_none(),
+                hop_constraints.as_ref(),
+            );
+            routes
+        } else {
+            vec![clusters]
+        };
+        // a cancelled calculation only has partial results
+        if let Some(key) = cache_key.filter(|_| !tracker.context.is_cancelled()) {
+            stats.cache_hit = Some(false);
+            let entry = cache::Entry {
+                routes: routes.clone(),
+                route_names: route_names.clone(),
+                stats: stats.clone(),
+            };
+            cache::save(conn, key, entry).await;
+        }
+        (routes, route_names)
+    };
+    tracker.emit(|| Event::Route {
+        routes: routes.len(),
+        route_time: stats.route_time,
+    });
+    tracker.enter(JobPhase::Stats).await?;
+    if detailed_stats {
+        stats.cluster_details(radius, &data_points, &routes, open_path.is_none());
+    }
 
     let instance = if let Some(parent) = parent {
         let model = geofence::Query::get_one(&conn.koji, parent.to_string())
             .await
-            .map_err(actix_web::error::ErrorInternalServerError)?;
+            .map_err(CalculationError::internal)?;
         model.name
     } else {
         instance
     };
-    feature.add_instance_properties(Some(instance.to_string()), Some(enum_type));
-    let feature = feature.to_collection(Some(instance.clone()), None);
+    let route_count = routes.len();
+    let features: Vec<Feature> = routes
+        .into_iter()
+        .enumerate()
+        .map(|(i, route)| {
+            let name = if let Some(name) = route_names.get(i) {
+                name.clone()
+            } else if route_count > 1 {
+                format!("{}_{}", instance, i + 1)
+            } else {
+                instance.to_string()
+            };
+            route_feature(route, name, &enum_type)
+        })
+        .collect();
+    let feature = features.to_collection(Some(instance.clone()), None);
 
     if !instance.is_empty() && save_to_db {
         route::Query::upsert_from_geometry(
@@ -276,7 +741,7 @@ async fn cluster(
             GeoFormats::FeatureCollection(feature.clone()),
         )
         .await
-        .map_err(actix_web::error::ErrorInternalServerError)?;
+        .map_err(CalculationError::internal)?;
     }
     if save_to_scanner {
         if conn.scanner_type == ScannerType::Unown {
@@ -293,24 +758,50 @@ async fn cluster(
             )
             .await
         }
-        .map_err(actix_web::error::ErrorInternalServerError)?;
+        .map_err(CalculationError::internal)?;
 
-        request::update_project_api(&conn, Some(&conn.scanner_type))
+        request::update_project_api(conn, Some(&conn.scanner_type))
             .await
-            .map_err(actix_web::error::ErrorInternalServerError)?;
+            .map_err(CalculationError::internal)?;
     }
 
-    Ok(utils::response::send(
-        feature,
-        return_type,
-        Some(stats),
-        benchmark_mode,
-        Some(instance),
-    ))
+    Ok(Calculation {
+        collection: feature,
+        stats,
+        instance,
+    })
+}
+
+/// Moves the routes that a routing plugin split the clusters into out of the stats and generates
+/// the stats of each, `None` when the plugin returned a single route
+fn take_plugin_routes(
+    stats: &mut Stats,
+    radius: Precision,
+    data_points: &SingleVec,
+    closed: bool,
+    constraints: Option<&HopConstraints>,
+) -> Option<Vec<SingleVec>> {
+    if stats.plugin_routes.is_empty() {
+        return None;
+    }
+    let routes = std::mem::take(&mut stats.plugin_routes);
+    stats.route_stats(radius, data_points, &routes, closed, constraints);
+    Some(routes)
+}
+
+fn route_feature(route: SingleVec, name: String, enum_type: &Type) -> Feature {
+    let mut feature = route
+        .to_feature(Some(enum_type.clone()))
+        .remove_last_coord();
+    feature.add_instance_properties(Some(name), Some(enum_type.clone()));
+    feature
 }
 
 #[post("/reroute")]
 async fn reroute(payload: web::Json<Args>) -> Result<HttpResponse, Error> {
+    let args = payload.into_inner().init(Some("reroute"));
+    let context = Context::default();
+    context.set_args(&args);
     let ArgsUnwrapped {
         benchmark_mode,
         data_points,
@@ -322,8 +813,12 @@ async fn reroute(payload: web::Json<Args>) -> Result<HttpResponse, Error> {
         sort_by,
         radius,
         routing_args,
+        routing_time,
+        open_path,
+        hop_constraints,
+        detailed_stats,
         ..
-    } = payload.into_inner().init(Some("reroute"));
+    } = args;
     let mut stats = Stats::new(String::from("Reroute"), 1);
 
     // For legacy compatibility
@@ -342,10 +837,39 @@ async fn reroute(payload: web::Json<Args>) -> Result<HttpResponse, Error> {
         radius,
         &mut stats,
         &routing_args,
+        routing_time,
+        open_path.as_ref(),
+        None,
+        hop_constraints.as_ref(),
+        &context,
     );
+    CalculationError::check_plugins(&stats)?;
+    let routes = take_plugin_routes(
+        &mut stats,
+        radius,
+        &data_points,
+        open_path.is_none(),
+        hop_constraints.as_ref(),
+    )
+    .unwrap_or_else(|| vec![clusters]);
+    if detailed_stats {
+        stats.cluster_details(radius, &data_points, &routes, open_path.is_none());
+    }
 
-    let feature = clusters.to_feature(Some(mode.clone())).remove_last_coord();
-    let feature = feature.to_collection(Some(instance.clone()), Some(mode));
+    let feature = if routes.len() == 1 {
+        routes[0]
+            .clone()
+            .to_feature(Some(mode.clone()))
+            .remove_last_coord()
+            .to_collection(Some(instance.clone()), Some(mode))
+    } else {
+        routes
+            .into_iter()
+            .enumerate()
+            .map(|(i, route)| route_feature(route, format!("{}_{}", instance, i + 1), &mode))
+            .collect::<Vec<Feature>>()
+            .to_collection(Some(instance.clone()), Some(mode))
+    };
 
     Ok(utils::response::send(
         feature,
@@ -365,6 +889,10 @@ async fn route_stats(payload: web::Json<Args>) -> Result<HttpResponse, Error> {
         radius,
         mode,
         min_points,
+        open_path,
+        hop_constraints,
+        detailed_stats,
+        return_type,
         ..
     } = payload.into_inner().init(Some("route-stats"));
 
@@ -374,20 +902,36 @@ async fn route_stats(payload: web::Json<Args>) -> Result<HttpResponse, Error> {
     }
     let mut stats = Stats::new(format!("Route Stats | {:?}", mode), min_points);
 
-    stats.distance_stats(&clusters);
+    stats.distance_stats(&clusters, open_path.is_none());
+    if let Some(hop_constraints) = hop_constraints {
+        stats.hop_stats(&clusters, &hop_constraints, open_path.is_none());
+    }
     if !data_points.is_empty() {
         stats.cluster_stats(radius, &data_points, &clusters);
         stats.set_score();
     }
+    if detailed_stats {
+        stats.cluster_details(
+            radius,
+            &data_points,
+            &[clusters.clone()],
+            open_path.is_none(),
+        );
+    }
+    let cluster_stats = matches!(return_type, ReturnTypeArg::ClusterStats);
 
     let feature = clusters.to_feature(Some(mode.clone())).remove_last_coord();
     let feature = feature.to_collection(Some(instance.clone()), Some(mode));
 
     Ok(utils::response::send(
         feature,
-        model::api::args::ReturnTypeArg::Feature,
+        if cluster_stats {
+            return_type
+        } else {
+            ReturnTypeArg::Feature
+        },
         Some(stats),
-        true,
+        !cluster_stats,
         Some(instance),
     ))
 }
@@ -409,6 +953,10 @@ async fn route_stats_category(
         last_seen,
         tth,
         min_points,
+        open_path,
+        hop_constraints,
+        detailed_stats,
+        return_type,
         ..
     } = payload.into_inner().init(Some("route-stats"));
     let category = url.into_inner();
@@ -433,24 +981,225 @@ async fn route_stats_category(
 
     let mut stats = Stats::new(format!("Route Stats | {:?}", mode), min_points);
 
-    stats.distance_stats(&clusters);
+    stats.distance_stats(&clusters, open_path.is_none());
+    if let Some(hop_constraints) = hop_constraints {
+        stats.hop_stats(&clusters, &hop_constraints, open_path.is_none());
+    }
     if !data_points.is_empty() {
         stats.cluster_stats(radius, &data_points, &clusters);
         stats.set_score();
     }
+    if detailed_stats {
+        stats.cluster_details(
+            radius,
+            &data_points,
+            &[clusters.clone()],
+            open_path.is_none(),
+        );
+    }
+    let cluster_stats = matches!(return_type, ReturnTypeArg::ClusterStats);
 
     let feature = clusters.to_feature(Some(mode.clone())).remove_last_coord();
     let feature = feature.to_collection(Some(instance.clone()), Some(mode));
 
     Ok(utils::response::send(
         feature,
-        model::api::args::ReturnTypeArg::Feature,
+        if cluster_stats {
+            return_type
+        } else {
+            ReturnTypeArg::Feature
+        },
         Some(stats),
-        true,
+        !cluster_stats,
         Some(instance),
     ))
 }
 
+#[post("/coverage-gaps/{category}")]
+async fn coverage_gaps(
+    conn: web::Data<KojiDb>,
+    url: actix_web::web::Path<String>,
+    payload: web::Json<Args>,
+) -> Result<HttpResponse, Error> {
+    let ArgsUnwrapped {
+        clusters,
+        data_points,
+        instance,
+        radius,
+        area,
+        parent,
+        last_seen,
+        tth,
+        min_points,
+        ..
+    } = payload.into_inner().init(Some("coverage-gaps"));
+    let category = url.into_inner();
+
+    let clusters = if clusters.is_empty() && !instance.is_empty() {
+        match route::Query::feature_from_name(&conn.koji, instance.clone(), false).await {
+            Ok(feature) => feature.to_single_vec(),
+            Err(_) => {
+                return Ok(HttpResponse::BadRequest().json(Response::send_error("route_not_found")))
+            }
+        }
+    } else {
+        clusters
+    };
+
+    let area = utils::create_or_find_collection(&instance, &conn, area, &parent, &data_points)
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+
+    let data_points = if !data_points.is_empty() {
+        data_points
+    } else {
+        utils::points_from_area(&area, &category, &conn, last_seen, tth)
+            .await
+            .map_err(actix_web::error::ErrorInternalServerError)?
+            .to_single_vec()
+    };
+
+    if clusters.is_empty() || data_points.is_empty() {
+        return Ok(HttpResponse::BadRequest()
+            .json(Response::send_error("no_clusters_or_data_points_found")));
+    }
+
+    let mut stats = Stats::new(format!("Coverage Gaps | {}", category), min_points);
+    stats.cluster_stats(radius, &data_points, &clusters);
+    stats.set_score();
+
+    let gaps = coverage::gaps(radius, &data_points, &clusters, min_points);
+    log::info!(
+        "[COVERAGE-GAPS] {} uncovered points, {} clusters with fewer than {} points",
+        gaps.uncovered.len(),
+        gaps.clusters.len(),
+        min_points
+    );
+    let features: Vec<Feature> = gaps
+        .uncovered
+        .into_iter()
+        .map(|point| {
+            let mut feature = point.to_feature(None);
+            feature.set_property("gap", "uncovered_point");
+            feature
+        })
+        .chain(gaps.clusters.into_iter().map(|(point, count)| {
+            let mut feature = point.to_feature(None);
+            feature.set_property("gap", "cluster");
+            feature.set_property("point_count", count);
+            feature
+        }))
+        .collect();
+
+    Ok(utils::response::send(
+        features.to_collection(Some(instance.clone()), None),
+        ReturnTypeArg::FeatureCollection,
+        Some(stats),
+        false,
+        Some(instance),
+    ))
+}
+
+#[post("/route-diff")]
+async fn route_diff(
+    conn: web::Data<KojiDb>,
+    payload: web::Json<RouteDiffArgs>,
+) -> Result<HttpResponse, Error> {
+    let RouteDiffArgs {
+        before,
+        after,
+        data_points,
+        radius,
+        min_points,
+        open_path,
+    } = payload.into_inner();
+    let radius = radius.unwrap_or(70.);
+    let min_points = min_points.unwrap_or(1);
+    let closed = !open_path.unwrap_or(false);
+    let data_points = data_points
+        .map(|data_points| data_points.to_single_vec())
+        .unwrap_or_default();
+
+    let mut routes = vec![];
+    for route_arg in [before, after] {
+        routes.push(match route_arg {
+            RouteArg::Gpx(route) => DataPointsArg::Gpx(route).to_single_vec(),
+            RouteArg::Inline(points) => points.to_single_vec(),
+            RouteArg::Stored(id) => {
+                match route::Query::get_one_feature(&conn.koji, id.to_string(), false).await {
+                    Ok(feature) => feature.to_single_vec(),
+                    Err(_) => {
+                        return Ok(HttpResponse::BadRequest()
+                            .json(Response::send_error("route_not_found")))
+                    }
+                }
+            }
+        });
+    }
+    let after = routes.pop().unwrap_or_default();
+    let before = routes.pop().unwrap_or_default();
+
+    let get_stats = |label: &str, route: &SingleVec| {
+        let mut stats = Stats::new(format!("Route Diff | {}", label), min_points);
+        stats.distance_stats(route, closed);
+        if data_points.is_empty() {
+            stats.total_clusters = route.len();
+        } else {
+            stats.cluster_stats(radius, &data_points, route);
+            stats.set_score();
+        }
+        stats.log(None);
+        stats
+    };
+    let before_stats = get_stats("Before", &before);
+    let after_stats = get_stats("After", &after);
+
+    let diff = routing::diff::main(&before, &after, closed);
+    let to_feature =
+        |point: PointArray, status: &str, before: Option<usize>, after: Option<usize>| {
+            let mut feature = point.to_feature(None);
+            feature.set_property("status", status);
+            feature.set_property("before_index", before);
+            feature.set_property("after_index", after);
+            feature
+        };
+    let features: Vec<Feature> = diff
+        .added
+        .into_iter()
+        .map(|(i, point)| to_feature(point, "added", None, Some(i)))
+        .chain(
+            diff.removed
+                .into_iter()
+                .map(|(i, point)| to_feature(point, "removed", Some(i), None)),
+        )
+        .chain(
+            diff.moved
+                .into_iter()
+                .map(|(b, a, point)| to_feature(point, "moved", Some(b), Some(a))),
+        )
+        .collect();
+
+    Ok(HttpResponse::Ok().json(Response {
+        message: "Success".to_string(),
+        status: "ok".to_string(),
+        status_code: 200,
+        data: Some(json!({
+            "diff": features.to_collection(None, None),
+            "unchanged": diff.unchanged,
+            "delta": {
+                "total_clusters": after_stats.total_clusters as i64 - before_stats.total_clusters as i64,
+                "points_covered": after_stats.points_covered as i64 - before_stats.points_covered as i64,
+                "total_distance": after_stats.total_distance - before_stats.total_distance,
+                "longest_distance": after_stats.longest_distance - before_stats.longest_distance,
+                "mygod_score": after_stats.mygod_score as i64 - before_stats.mygod_score as i64,
+            },
+            "before": before_stats,
+            "after": after_stats,
+        })),
+        stats: None,
+    }))
+}
+
 #[post("/area")]
 async fn calculate_area(payload: web::Json<Args>) -> Result<HttpResponse, Error> {
     let ArgsUnwrapped { area, .. } = payload.into_inner().init(Some("calculate_area"));
@@ -1,8 +1,19 @@
+use crate::utils::response::Response;
+
 use super::*;
 
-use algorithms::{bootstrap, clustering, routing};
+use algorithms::{bootstrap, clustering, manifest, routing};
+use serde::Deserialize;
 use serde_json::json;
 
+#[derive(Debug, Clone, Deserialize)]
+struct ValidatePluginArgs {
+    folder: String,
+    plugin: String,
+    #[serde(default)]
+    args: String,
+}
+
 #[get("/")]
 async fn main() -> Result<HttpResponse, Error> {
     Ok(HttpResponse::Ok().json(json!({
@@ -11,3 +22,28 @@ async fn main() -> Result<HttpResponse, Error> {
         "bootstrap": bootstrap::all_bootstrap_options(),
     })))
 }
+
+#[get("/plugins")]
+async fn plugins() -> Result<HttpResponse, Error> {
+    Ok(HttpResponse::Ok().json(manifest::all()))
+}
+
+#[post("/plugins/validate")]
+async fn validate_plugin(payload: web::Json<ValidatePluginArgs>) -> Result<HttpResponse, Error> {
+    let ValidatePluginArgs {
+        folder,
+        plugin,
+        args,
+    } = payload.into_inner();
+
+    match manifest::validate(&folder, &plugin, &args) {
+        Ok(args) => Ok(HttpResponse::Ok().json(Response {
+            data: Some(json!(args)),
+            message: "Success".to_string(),
+            status: "ok".to_string(),
+            stats: None,
+            status_code: 200,
+        })),
+        Err(err) => Ok(HttpResponse::BadRequest().json(Response::send_error(&err))),
+    }
+}
@@ -0,0 +1,158 @@
+use crate::utils::response::{self, Response};
+
+use super::*;
+
+use serde_json::json;
+
+use model::{
+    api::args::{get_return_type, Args, ReturnTypeArg},
+    db::{job, sea_orm_active_enums::JobStatus},
+    error::ModelError,
+    KojiDb,
+};
+
+async fn submit(
+    conn: &KojiDb,
+    endpoint: String,
+    payload: serde_json::Value,
+) -> Result<HttpResponse, Error> {
+    if let Err(err) = serde_json::from_value::<Args>(payload.clone()) {
+        return Ok(HttpResponse::BadRequest().json(Response::send_error(&err.to_string())));
+    }
+    let job = job::Query::create(&conn.koji, endpoint, payload)
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+
+    log::info!("[JOBS] Queued job {} ({})", job.id, job.endpoint);
+    Ok(HttpResponse::Ok().json(Response {
+        data: Some(json!({ "id": job.id, "status": job.status })),
+        message: "Success".to_string(),
+        status: "ok".to_string(),
+        stats: None,
+        status_code: 200,
+    }))
+}
+
+#[post("/bootstrap")]
+async fn bootstrap(
+    conn: web::Data<KojiDb>,
+    payload: web::Json<serde_json::Value>,
+) -> Result<HttpResponse, Error> {
+    submit(&conn, "bootstrap".to_string(), payload.into_inner()).await
+}
+
+#[post("/{mode}/{category}")]
+async fn cluster(
+    conn: web::Data<KojiDb>,
+    url: actix_web::web::Path<(String, String)>,
+    payload: web::Json<serde_json::Value>,
+) -> Result<HttpResponse, Error> {
+    let (mode, category) = url.into_inner();
+    submit(
+        &conn,
+        format!("{}/{}", mode, category),
+        payload.into_inner(),
+    )
+    .await
+}
+
+#[get("/{id}")]
+async fn status(
+    conn: web::Data<KojiDb>,
+    id: actix_web::web::Path<u32>,
+) -> Result<HttpResponse, Error> {
+    match job::Query::get_status(&conn.koji, id.into_inner()).await {
+        Ok(job) => Ok(HttpResponse::Ok().json(Response {
+            data: Some(json!(job)),
+            message: "Success".to_string(),
+            status: "ok".to_string(),
+            stats: None,
+            status_code: 200,
+        })),
+        Err(ModelError::Job(_)) => {
+            Ok(HttpResponse::NotFound().json(Response::send_error("job_not_found")))
+        }
+        Err(err) => Err(actix_web::error::ErrorInternalServerError(err)),
+    }
+}
+
+async fn send_result(
+    conn: &KojiDb,
+    id: u32,
+    return_type: Option<String>,
+) -> Result<HttpResponse, Error> {
+    let job = match job::Query::get_one(&conn.koji, id).await {
+        Ok(job) => job,
+        Err(ModelError::Job(_)) => {
+            return Ok(HttpResponse::NotFound().json(Response::send_error("job_not_found")))
+        }
+        Err(err) => return Err(actix_web::error::ErrorInternalServerError(err)),
+    };
+    let (JobStatus::Completed, Some(output)) = (&job.status, job.result) else {
+        return Ok(HttpResponse::BadRequest().json(Response::send_error("job_not_completed")));
+    };
+
+    // defaults to the return type that was requested when the job was submitted
+    let default_return_type = serde_json::from_value::<Args>(job.args)
+        .map(|args| args.init(None).return_type)
+        .unwrap_or(ReturnTypeArg::FeatureCollection);
+    let return_type = match return_type {
+        Some(return_type) => get_return_type(return_type, &default_return_type),
+        None => default_return_type,
+    };
+    let output = match (&return_type, job.details) {
+        (ReturnTypeArg::ClusterStats, Some(details)) => details,
+        _ => output,
+    };
+    let collection = serde_json::from_value::<FeatureCollection>(output)
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Ok().json(Response {
+        data: Some(json!(response::convert(collection, return_type, None))),
+        message: "Success".to_string(),
+        status: "ok".to_string(),
+        stats: None,
+        status_code: 200,
+    }))
+}
+
+#[get("/{id}/result")]
+async fn result(
+    conn: web::Data<KojiDb>,
+    id: actix_web::web::Path<u32>,
+) -> Result<HttpResponse, Error> {
+    send_result(&conn, id.into_inner(), None).await
+}
+
+#[get("/{id}/result/{return_type}")]
+async fn result_return_type(
+    conn: web::Data<KojiDb>,
+    url: actix_web::web::Path<(u32, String)>,
+) -> Result<HttpResponse, Error> {
+    let (id, return_type) = url.into_inner();
+    send_result(&conn, id, Some(return_type)).await
+}
+
+#[delete("/{id}")]
+async fn cancel(
+    conn: web::Data<KojiDb>,
+    id: actix_web::web::Path<u32>,
+) -> Result<HttpResponse, Error> {
+    let id = id.into_inner();
+    let cancelled = job::Query::cancel(&conn.koji, id)
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+
+    if cancelled {
+        log::info!("[JOBS] Cancelled job {}", id);
+        Ok(HttpResponse::Ok().json(Response {
+            data: Some(json!({ "id": id, "status": JobStatus::Cancelled })),
+            message: "Success".to_string(),
+            status: "ok".to_string(),
+            stats: None,
+            status_code: 200,
+        }))
+    } else {
+        Ok(HttpResponse::BadRequest().json(Response::send_error("job_not_cancellable")))
+    }
+}
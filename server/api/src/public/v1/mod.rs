@@ -4,6 +4,7 @@ pub mod calculate;
 pub mod convert;
 pub mod geofence;
 pub mod info;
+pub mod jobs;
 pub mod project;
 pub mod route;
 pub mod s2;
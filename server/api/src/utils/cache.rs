@@ -0,0 +1,215 @@
+//! Cache for the clusters and routes of a calculation
+//!
+//! Results are keyed by a SHA-256 digest of the resolved area, the data points, and every arg that
+//! changes the clusters or routes, each serialized as JSON. The most recently used results are kept
+//! in memory, `CACHE_SIZE` of them (default 20), and they're also saved to the Kōji database when
+//! `PERSIST_CACHE` is set. Saved results expire after `PERSIST_CACHE_MAX_AGE` hours (default 168)
+//! and only the newest `PERSIST_CACHE_MAX_ROWS` (default 1000) are kept, `0` lifts either limit.
+//! The table is pruned whenever a result is saved.
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Mutex, OnceLock},
+};
+
+use algorithms::stats::Stats;
+use geojson::FeatureCollection;
+use model::{
+    api::{single_vec::SingleVec, Precision},
+    db::result_cache,
+    KojiDb,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+const DEFAULT_CAPACITY: usize = 20;
+/// Hours that a saved result is kept for
+const DEFAULT_MAX_AGE: u64 = 168;
+const DEFAULT_MAX_ROWS: u64 = 1_000;
+
+/// Clusters and routes of a calculation, along with the stats from clustering and routing them
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry {
+    pub routes: Vec<SingleVec>,
+    pub route_names: Vec<String>,
+    pub stats: Stats,
+}
+
+/// Builds the digest that a result is cached under
+pub struct Key {
+    digest: Sha256,
+    /// Whether every arg could be serialized, results aren't cached otherwise
+    complete: bool,
+}
+
+impl Key {
+    pub fn new(area: &FeatureCollection, data_points: &SingleVec, weights: &[Precision]) -> Self {
+        let mut key = Self {
+            digest: Sha256::new(),
+            complete: true,
+        };
+        key.update(area.to_string().as_bytes());
+        key.digest.update((data_points.len() as u64).to_le_bytes());
+        for point in data_points.iter() {
+            key.digest.update(point[0].to_le_bytes());
+            key.digest.update(point[1].to_le_bytes());
+        }
+        key.digest.update((weights.len() as u64).to_le_bytes());
+        for weight in weights.iter() {
+            key.digest.update(weight.to_le_bytes());
+        }
+        key
+    }
+
+    /// Length prefixed so that the boundaries between the parts can't shift
+    fn update(&mut self, bytes: &[u8]) {
+        self.digest.update((bytes.len() as u64).to_le_bytes());
+        self.digest.update(bytes);
+    }
+
+    /// Adds an arg that changes the clusters or routes to the key
+    pub fn arg(mut self, value: impl Serialize) -> Self {
+        match serde_json::to_vec(&value) {
+            Ok(bytes) => self.update(&bytes),
+            Err(err) => {
+                log::error!("[CACHE] Unable to serialize an arg of the key: {}", err);
+                self.complete = false;
+            }
+        }
+        self
+    }
+
+    pub fn finish(self) -> Option<String> {
+        if self.complete {
+            Some(format!("{:x}", self.digest.finalize()))
+        } else {
+            None
+        }
+    }
+}
+
+struct Lru {
+    capacity: usize,
+    entries: HashMap<String, Entry>,
+    /// Least recently used first
+    order: VecDeque<String>,
+}
+
+impl Lru {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    fn touch(&mut self, key: &str) {
+        if let Some(index) = self.order.iter().position(|k| k == key) {
+            if let Some(key) = self.order.remove(index) {
+                self.order.push_back(key);
+            }
+        }
+    }
+
+    fn get(&mut self, key: &str) -> Option<Entry> {
+        let entry = self.entries.get(key).cloned();
+        if entry.is_some() {
+            self.touch(key);
+        }
+        entry
+    }
+
+    fn insert(&mut self, key: String, entry: Entry) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.entries.insert(key.clone(), entry).is_some() {
+            self.touch(&key);
+            return;
+        }
+        self.order.push_back(key);
+        while self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.entries.remove(&oldest);
+            }
+        }
+    }
+}
+
+fn memory() -> &'static Mutex<Lru> {
+    static CACHE: OnceLock<Mutex<Lru>> = OnceLock::new();
+    CACHE.get_or_init(|| {
+        let capacity = std::env::var("CACHE_SIZE")
+            .ok()
+            .and_then(|size| size.parse().ok())
+            .unwrap_or(DEFAULT_CAPACITY);
+        Mutex::new(Lru::new(capacity))
+    })
+}
+
+fn persist() -> bool {
+    std::env::var("PERSIST_CACHE").is_ok()
+}
+
+/// Reads a limit of the saved results, `None` when it's set to `0`
+fn limit(var: &str, default: u64) -> Option<u64> {
+    let limit = std::env::var(var)
+        .ok()
+        .and_then(|limit| limit.parse().ok())
+        .unwrap_or(default);
+    (limit > 0).then_some(limit)
+}
+
+fn max_age() -> Option<u64> {
+    limit("PERSIST_CACHE_MAX_AGE", DEFAULT_MAX_AGE)
+}
+
+pub async fn get(conn: &KojiDb, key: &str) -> Option<Entry> {
+    if let Some(entry) = memory().lock().ok().and_then(|mut cache| cache.get(key)) {
+        return Some(entry);
+    }
+    if !persist() {
+        return None;
+    }
+    match result_cache::Query::get(&conn.koji, key, max_age()).await {
+        Ok(Some(result)) => match serde_json::from_value::<Entry>(result) {
+            Ok(entry) => {
+                if let Ok(mut cache) = memory().lock() {
+                    cache.insert(key.to_string(), entry.clone());
+                }
+                Some(entry)
+            }
+            Err(err) => {
+                log::warn!("[CACHE] Unable to read cached result {}: {}", key, err);
+                None
+            }
+        },
+        Ok(None) => None,
+        Err(err) => {
+            log::error!("[CACHE] Unable to fetch cached result {}: {:?}", key, err);
+            None
+        }
+    }
+}
+
+pub async fn save(conn: &KojiDb, key: String, entry: Entry) {
+    if persist() {
+        match serde_json::to_value(&entry) {
+            Ok(result) => {
+                if let Err(err) = result_cache::Query::save(&conn.koji, key.clone(), result).await {
+                    log::error!("[CACHE] Unable to save result {}: {:?}", key, err)
+                }
+                let max_rows = limit("PERSIST_CACHE_MAX_ROWS", DEFAULT_MAX_ROWS);
+                match result_cache::Query::prune(&conn.koji, max_age(), max_rows).await {
+                    Ok(0) => {}
+                    Ok(deleted) => log::debug!("[CACHE] Pruned {} saved results", deleted),
+                    Err(err) => log::error!("[CACHE] Unable to prune saved results: {:?}", err),
+                }
+            }
+            Err(err) => log::error!("[CACHE] Unable to serialize result {}: {}", key, err),
+        }
+    }
+    if let Ok(mut cache) = memory().lock() {
+        cache.insert(key, entry);
+    }
+}
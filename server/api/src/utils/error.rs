@@ -1,7 +1,11 @@
+use actix_web::{http::StatusCode, HttpResponse};
+use algorithms::stats::Stats;
 use migration::DbErr;
 use model::error::ModelError;
 use thiserror::Error;
 
+use super::response::Response;
+
 #[derive(Error, Debug)]
 pub enum Error {
     #[error("`{0}`")]
@@ -35,3 +39,53 @@ impl From<ModelError> for Error {
         Self::Model(error)
     }
 }
+
+/// Errors from a calculation that can either run as a request or as a background job
+#[derive(Error, Debug)]
+pub enum CalculationError {
+    #[error("{0}")]
+    BadRequest(&'static str),
+    #[error("Cancelled")]
+    Cancelled,
+    /// A plugin failed, the message includes the end of its stderr
+    #[error("{0}")]
+    Plugin(String),
+    #[error("{0}")]
+    Internal(String),
+}
+
+impl CalculationError {
+    pub fn internal(error: impl std::fmt::Display) -> Self {
+        Self::Internal(error.to_string())
+    }
+
+    /// Errors if any of the plugins that ran for the stats failed
+    pub fn check_plugins(stats: &Stats) -> Result<(), Self> {
+        if stats.plugin_errors.is_empty() {
+            Ok(())
+        } else {
+            Err(Self::Plugin(stats.plugin_errors.join("\n")))
+        }
+    }
+}
+
+impl actix_web::ResponseError for CalculationError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            Self::BadRequest(_) => StatusCode::BAD_REQUEST,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            Self::BadRequest(message) => {
+                HttpResponse::BadRequest().json(Response::send_error(message))
+            }
+            Self::Plugin(message) => {
+                HttpResponse::InternalServerError().json(Response::send_error(message))
+            }
+            _ => HttpResponse::InternalServerError().body(self.to_string()),
+        }
+    }
+}
@@ -0,0 +1,122 @@
+//! Progress of a calculation, streamed to the client as server-sent events
+//!
+//! Each event is sent as `event: <name>` followed by a JSON `data` line. The stream ends after
+//! a `result` event with the same body as the non streaming endpoint, or an `error` event.
+use std::{future::Future, thread};
+
+use actix_web::{rt::System, web::Bytes};
+use algorithms::{context::Context, stats::Stats};
+use futures::{
+    channel::mpsc::{self, UnboundedSender},
+    Stream, StreamExt,
+};
+use geojson::Feature;
+use model::api::{args::ReturnTypeArg, Precision};
+use serde::Serialize;
+
+use crate::public::v1::calculate::Calculation;
+
+use super::{
+    error::CalculationError,
+    jobs::Tracker,
+    response::{self, Response},
+};
+
+pub type Sender = UnboundedSender<Event>;
+
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum Event {
+    /// The data points were fetched
+    Points {
+        count: usize,
+    },
+    /// The clusters were found
+    Clusters {
+        count: usize,
+        cluster_time: Precision,
+    },
+    /// The clusters were routed
+    Route {
+        routes: usize,
+        route_time: Precision,
+    },
+    /// The route of one of the children of a `parent` is done
+    Child {
+        index: usize,
+        name: String,
+        feature: Feature,
+        stats: Stats,
+    },
+    Result(Response),
+    Error(Response),
+}
+
+impl Event {
+    fn name(&self) -> &'static str {
+        match self {
+            Event::Points { .. } => "points",
+            Event::Clusters { .. } => "clusters",
+            Event::Route { .. } => "route",
+            Event::Child { .. } => "child",
+            Event::Result(_) => "result",
+            Event::Error(_) => "error",
+        }
+    }
+
+    fn to_bytes(&self) -> Bytes {
+        let data = serde_json::to_string(self).unwrap_or_else(|err| {
+            log::error!(
+                "[EVENTS] Unable to serialize {} event: {}",
+                self.name(),
+                err
+            );
+            "null".to_string()
+        });
+        Bytes::from(format!("event: {}\ndata: {}\n\n", self.name(), data))
+    }
+}
+
+/// Runs the calculation on its own thread and streams its events.
+/// The calculation is cancelled when the client disconnects and the stream is dropped
+pub fn stream<F, Fut>(
+    return_type: ReturnTypeArg,
+    benchmark_mode: bool,
+    calc: F,
+) -> impl Stream<Item = Result<Bytes, actix_web::Error>>
+where
+    F: FnOnce(Tracker<'static>) -> Fut + Send + 'static,
+    Fut: Future<Output = Result<Calculation, CalculationError>>,
+{
+    let (sender, receiver) = mpsc::unbounded();
+    let context = Context::default();
+    let cancel = context.cancel_on_drop();
+    let tracker = Tracker::new(context).with_events(sender.clone());
+
+    thread::spawn(move || {
+        let event = match System::new().block_on(calc(tracker)) {
+            Ok(Calculation {
+                collection,
+                stats,
+                instance,
+            }) => {
+                stats.log(Some(instance));
+                Event::Result(response::success(
+                    collection,
+                    return_type,
+                    Some(stats),
+                    benchmark_mode,
+                ))
+            }
+            Err(CalculationError::Cancelled) => return,
+            Err(err) => Event::Error(Response::send_error(&err.to_string())),
+        };
+        // the client may already be gone
+        sender.unbounded_send(event).ok();
+    });
+
+    receiver.map(move |event| {
+        let _cancel = &cancel;
+        Ok(event.to_bytes())
+    })
+}
@@ -0,0 +1,287 @@
+//! Background runner for the calculations submitted to `/api/v1/jobs`
+//!
+//! Jobs are stored in the Kōji database and run one at a time on their own thread, so a long
+//! calculation doesn't tie up the HTTP workers. Jobs that were still running when Kōji stopped
+//! are put back in the queue on startup.
+//!
+//! Jobs and the calculations of direct requests share `MAX_CALCULATIONS` slots, defaulting to
+//! the number of CPUs. Calculations past the limit wait for a free slot.
+use std::{
+    future::Future,
+    sync::{Condvar, Mutex, OnceLock},
+    thread,
+    time::Duration,
+};
+
+use actix_web::rt::{time::sleep, System};
+use algorithms::context::Context;
+use model::{
+    api::args::Args,
+    db::{
+        job,
+        sea_orm_active_enums::{JobPhase, JobStatus},
+    },
+    KojiDb,
+};
+use serde_json::json;
+
+use crate::public::v1::calculate::{self, Calculation};
+
+use super::{
+    error::CalculationError,
+    events::{self, Event},
+    response,
+};
+
+/// How long the runner waits before checking for new jobs when the queue is empty
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// How often the progress of a running job is saved and checked for cancellation
+const WATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often a calculation waiting for a slot checks whether it was cancelled
+const SLOT_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Limits how many calculations run at once
+struct Slots {
+    running: Mutex<usize>,
+    freed: Condvar,
+    limit: usize,
+}
+
+/// A taken slot, it's freed when dropped
+struct Slot(&'static Slots);
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        if let Ok(mut running) = self.0.running.lock() {
+            *running -= 1;
+        }
+        self.0.freed.notify_one();
+    }
+}
+
+impl Slots {
+    /// Blocks until a slot is free, gives up once the context is cancelled
+    fn acquire(&'static self, context: &Context) -> Result<Slot, CalculationError> {
+        let mut running = self.running.lock().map_err(CalculationError::internal)?;
+        while *running >= self.limit {
+            if context.is_cancelled() {
+                return Err(CalculationError::Cancelled);
+            }
+            running = self
+                .freed
+                .wait_timeout(running, SLOT_INTERVAL)
+                .map_err(CalculationError::internal)?
+                .0;
+        }
+        *running += 1;
+        Ok(Slot(self))
+    }
+}
+
+fn slots() -> &'static Slots {
+    static SLOTS: OnceLock<Slots> = OnceLock::new();
+    SLOTS.get_or_init(|| {
+        let limit = std::env::var("MAX_CALCULATIONS")
+            .ok()
+            .and_then(|limit| limit.parse().ok())
+            .filter(|limit| *limit > 0)
+            .unwrap_or_else(|| thread::available_parallelism().map_or(1, |cpus| cpus.get()));
+        Slots {
+            running: Mutex::new(0),
+            freed: Condvar::new(),
+            limit,
+        }
+    })
+}
+
+/// Lets a calculation be followed and stopped, the phases are saved when it runs as a job
+/// and the events are sent when it's streamed
+pub struct Tracker<'a> {
+    pub context: Context,
+    job: Option<(&'a KojiDb, u32)>,
+    events: Option<events::Sender>,
+}
+
+impl<'a> Tracker<'a> {
+    pub fn new(context: Context) -> Self {
+        Self {
+            context,
+            job: None,
+            events: None,
+        }
+    }
+
+    fn for_job(conn: &'a KojiDb, id: u32, context: Context) -> Self {
+        Self {
+            context,
+            job: Some((conn, id)),
+            events: None,
+        }
+    }
+
+    pub fn with_events(mut self, sender: events::Sender) -> Self {
+        self.events = Some(sender);
+        self
+    }
+
+    /// Sends the event when the calculation is streamed, it's only built in that case
+    pub fn emit(&self, event: impl FnOnce() -> Event) {
+        if let Some(sender) = self.events.as_ref() {
+            // the client may already be gone, the calculation gets cancelled then
+            sender.unbounded_send(event()).ok();
+        }
+    }
+
+    /// Enters the next phase, errors if the calculation was cancelled
+    pub async fn enter(&self, phase: JobPhase) -> Result<(), CalculationError> {
+        if self.context.is_cancelled() {
+            return Err(CalculationError::Cancelled);
+        }
+        self.context.report(0, 0);
+        if let Some((conn, id)) = self.job {
+            log::debug!("[JOBS] Job {} entering {:?}", id, phase);
+            match job::Query::set_phase(&conn.koji, id, phase).await {
+                Ok(true) => {}
+                Ok(false) => return Err(CalculationError::Cancelled),
+                Err(err) => return Err(CalculationError::internal(err)),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Runs the calculation on the blocking pool once a calculation slot is free and waits for it
+/// without blocking the caller's thread. The context is cancelled when the returned future is
+/// dropped, e.g. when the client of the request that started the calculation disconnects
+pub async fn run_detached<F, Fut>(calc: F) -> Result<Calculation, CalculationError>
+where
+    F: FnOnce(Context) -> Fut + Send + 'static,
+    Fut: Future<Output = Result<Calculation, CalculationError>>,
+{
+    let context = Context::default();
+    let _cancel = context.cancel_on_drop();
+    actix_web::rt::task::spawn_blocking(move || {
+        let _slot = slots().acquire(&context)?;
+        System::new().block_on(calc(context))
+    })
+    .await
+    .map_err(|_| CalculationError::internal("calculation thread panicked"))?
+}
+
+/// Starts the job runner on its own thread
+pub fn spawn_runner(conn: KojiDb) -> std::io::Result<()> {
+    thread::Builder::new()
+        .name("koji-jobs".to_string())
+        .spawn(move || System::new().block_on(run(conn)))?;
+    Ok(())
+}
+
+async fn run(conn: KojiDb) {
+    match job::Query::requeue_interrupted(&conn.koji).await {
+        Ok(0) => {}
+        Ok(count) => log::info!("[JOBS] Requeued {} interrupted jobs", count),
+        Err(err) => log::error!("[JOBS] Unable to requeue interrupted jobs: {:?}", err),
+    }
+    loop {
+        match job::Query::claim_next(&conn.koji).await {
+            Ok(Some(job)) => execute(&conn, job).await,
+            Ok(None) => sleep(POLL_INTERVAL).await,
+            Err(err) => {
+                log::error!("[JOBS] Unable to fetch the next job: {:?}", err);
+                sleep(POLL_INTERVAL).await
+            }
+        }
+    }
+}
+
+async fn calculate(
+    conn: KojiDb,
+    id: u32,
+    endpoint: String,
+    args: serde_json::Value,
+    context: Context,
+) -> Result<Calculation, CalculationError> {
+    let tracker = Tracker::for_job(&conn, id, context);
+    let args = serde_json::from_value::<Args>(args).map_err(CalculationError::internal)?;
+    match endpoint.split_once('/') {
+        Some((mode, category)) => {
+            calculate::run_cluster(&conn, mode, category, args.init(Some(mode)), &tracker).await
+        }
+        None => calculate::run_bootstrap(&conn, args.init(Some("bootstrap")), &tracker).await,
+    }
+}
+
+/// Saves the progress of the running job and cancels its context once the job is cancelled
+async fn watch(conn: &KojiDb, id: u32, context: &Context) {
+    match job::Query::get_status(&conn.koji, id).await {
+        Ok(status) => match status.status {
+            JobStatus::Cancelled => context.cancel(),
+            JobStatus::Running => {
+                if let Some(phase) = status.phase {
+                    let progress = phase.progress(context.progress());
+                    if let Err(err) = job::Query::set_progress(&conn.koji, id, progress).await {
+                        log::error!(
+                            "[JOBS] Unable to save the progress of job {}: {:?}",
+                            id,
+                            err
+                        )
+                    }
+                }
+            }
+            _ => {}
+        },
+        Err(err) => log::error!("[JOBS] Unable to check job {}: {:?}", id, err),
+    }
+}
+
+async fn execute(conn: &KojiDb, job: job::Model) {
+    log::info!("[JOBS] Starting job {} ({})", job.id, job.endpoint);
+    let context = Context::default();
+
+    let handle = {
+        let conn = conn.clone();
+        let context = context.clone();
+        thread::spawn(move || {
+            let _slot = slots().acquire(&context)?;
+            System::new().block_on(calculate(conn, job.id, job.endpoint, job.args, context))
+        })
+    };
+    while !handle.is_finished() {
+        sleep(WATCH_INTERVAL).await;
+        watch(conn, job.id, &context).await;
+    }
+    let result = handle
+        .join()
+        .unwrap_or_else(|_| Err(CalculationError::internal("calculation thread panicked")));
+
+    let saved = match result {
+        Ok(Calculation {
+            collection,
+            stats,
+            instance,
+        }) => {
+            stats.log(Some(instance));
+            let details = if stats.cluster_details.is_empty() {
+                None
+            } else {
+                Some(json!(response::cluster_stats_collection(
+                    collection.clone(),
+                    Some(&stats)
+                )))
+            };
+            job::Query::complete(&conn.koji, job.id, json!(collection), details, json!(stats)).await
+        }
+        Err(CalculationError::Cancelled) => Ok(false),
+        Err(err) => {
+            log::error!("[JOBS] Job {} failed: {}", job.id, err);
+            job::Query::fail(&conn.koji, job.id, err.to_string()).await
+        }
+    };
+    match saved {
+        Ok(true) => log::info!("[JOBS] Saved the outcome of job {}", job.id),
+        Ok(false) => log::info!("[JOBS] Job {} was cancelled", job.id),
+        Err(err) => log::error!("[JOBS] Unable to save job {}: {:?}", job.id, err),
+    }
+}
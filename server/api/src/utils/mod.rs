@@ -15,7 +15,10 @@ use model::{
 };
 
 pub mod auth;
+pub mod cache;
 pub mod error;
+pub mod events;
+pub mod jobs;
 pub mod request;
 pub mod response;
 
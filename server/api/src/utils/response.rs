@@ -1,15 +1,15 @@
 use super::*;
 
 use actix_web::HttpResponse;
-use algorithms::stats::Stats;
+use algorithms::{manifest::Manifest, stats::Stats};
 use geojson::JsonValue;
-use model::api::{Precision, ToGeometry, ToSql};
+use model::api::{Precision, ToGeometry, ToGpx, ToKml, ToShapefile, ToSql, ToWkt};
 use serde::Serialize;
 use serde_json::json;
 
 use crate::model::api::{
-    args::ReturnTypeArg, GeoFormats, ToMultiStruct, ToMultiVec, ToPoracleVec, ToSingleStruct,
-    ToSingleVec, ToText,
+    args::ReturnTypeArg, GeoFormats, ToCollection, ToFeature, ToMultiStruct, ToMultiVec,
+    ToPoracleVec, ToSingleStruct, ToSingleVec, ToText,
 };
 
 #[derive(Debug, Serialize)]
@@ -23,6 +23,7 @@ pub struct ConfigResponse {
     pub route_plugins: Vec<String>,
     pub clustering_plugins: Vec<String>,
     pub bootstrap_plugins: Vec<String>,
+    pub plugin_manifests: &'static [Manifest],
 }
 
 #[derive(Debug, Serialize, Clone)]
@@ -46,6 +47,30 @@ impl Response {
     }
 }
 
+/// Turns the detailed cluster stats into point features,
+/// falls back to the original collection when they weren't generated
+pub fn cluster_stats_collection(
+    value: FeatureCollection,
+    stats: Option<&Stats>,
+) -> FeatureCollection {
+    match stats {
+        Some(stats) if !stats.cluster_details.is_empty() => stats
+            .cluster_details
+            .iter()
+            .map(|detail| {
+                let mut feature = detail.center.to_feature(None);
+                feature.set_property("point_count", detail.point_count);
+                feature.set_property("unique_points", detail.unique_points);
+                feature.set_property("next_distance", detail.next_distance);
+                feature.set_property("cell_id", detail.cell_id.clone());
+                feature
+            })
+            .collect::<Vec<_>>()
+            .to_collection(None, None),
+        _ => value,
+    }
+}
+
 pub fn send(
     value: FeatureCollection,
     return_type: ReturnTypeArg,
@@ -56,41 +81,83 @@ pub fn send(
     if let Some(stats) = stats.as_ref() {
         stats.log(area);
     }
-    HttpResponse::Ok().json(Response {
+    HttpResponse::Ok().json(success(value, return_type, stats, benchmark_mode))
+}
+
+/// Body of a successful calculation response
+pub fn success(
+    value: FeatureCollection,
+    return_type: ReturnTypeArg,
+    stats: Option<Stats>,
+    benchmark_mode: bool,
+) -> Response {
+    Response {
         message: "Success".to_string(),
         status: "ok".to_string(),
         status_code: 200,
-        data: if benchmark_mode { None } else { Some(json!(match return_type {
-            ReturnTypeArg::SingleStruct => GeoFormats::SingleStruct(value.to_single_struct()),
-            ReturnTypeArg::MultiStruct => GeoFormats::MultiStruct(value.to_multi_struct()),
-            ReturnTypeArg::Text => GeoFormats::Text(value.to_text(",", "\n", true)),
-            ReturnTypeArg::AltText => GeoFormats::Text(value.to_text(" ", ",", false)),
-            ReturnTypeArg::SingleArray => GeoFormats::SingleArray(value.to_single_vec()),
-            ReturnTypeArg::MultiArray => GeoFormats::MultiArray(value.to_multi_vec()),
-            ReturnTypeArg::Geometry => {
-                if value.features.len() == 1 {
-                    GeoFormats::Geometry(value.features.first().unwrap().to_owned().to_geometry())
-                } else {
-                    log::info!("\"Geometry\" was requested as the return type but multiple features were found so a Vec of geometries is being returned");
-                    GeoFormats::GeometryVec(value.into_iter().map(|feat| feat.to_geometry()).collect())
-                }
-            },
-            ReturnTypeArg::GeometryVec => GeoFormats::GeometryVec(value.into_iter().map(|feat| feat.to_geometry()).collect()),
-            ReturnTypeArg::Feature => {
-                if value.features.len() == 1 {
-                    let feat = GeoFormats::Feature(value.features.first().unwrap().clone());
-                    feat
-                } else {
-                    log::info!("\"Feature\" was requested as the return type but multiple features were found so a Vec of features is being returned");
-                    GeoFormats::FeatureVec(value.features)
-                }
-            }
-            ReturnTypeArg::FeatureVec => GeoFormats::FeatureVec(value.features),
-            ReturnTypeArg::FeatureCollection => GeoFormats::FeatureCollection(value),
-            ReturnTypeArg::Poracle => GeoFormats::Poracle(value.to_poracle_vec()),
-            ReturnTypeArg::PoracleSingle => GeoFormats::PoracleSingle(value.to_poracle_vec().first().unwrap().clone()),
-            ReturnTypeArg::Sql => GeoFormats::Text(value.to_sql()),
-        }))},
+        data: if benchmark_mode {
+            None
+        } else {
+            Some(json!(convert(value, return_type, stats.as_ref())))
+        },
         stats,
-    })
+    }
+}
+
+/// Converts the collection to the requested return type
+pub fn convert(
+    value: FeatureCollection,
+    return_type: ReturnTypeArg,
+    stats: Option<&Stats>,
+) -> GeoFormats {
+    match return_type {
+        ReturnTypeArg::SingleStruct => GeoFormats::SingleStruct(value.to_single_struct()),
+        ReturnTypeArg::MultiStruct => GeoFormats::MultiStruct(value.to_multi_struct()),
+        ReturnTypeArg::Text => GeoFormats::Text(value.to_text(",", "\n", true)),
+        ReturnTypeArg::AltText => GeoFormats::Text(value.to_text(" ", ",", false)),
+        ReturnTypeArg::SingleArray => GeoFormats::SingleArray(value.to_single_vec()),
+        ReturnTypeArg::MultiArray => GeoFormats::MultiArray(value.to_multi_vec()),
+        ReturnTypeArg::Geometry => {
+            if value.features.len() == 1 {
+                GeoFormats::Geometry(value.features.first().unwrap().to_owned().to_geometry())
+            } else {
+                log::info!("\"Geometry\" was requested as the return type but multiple features were found so a Vec of geometries is being returned");
+                GeoFormats::GeometryVec(value.into_iter().map(|feat| feat.to_geometry()).collect())
+            }
+        }
+        ReturnTypeArg::GeometryVec => {
+            GeoFormats::GeometryVec(value.into_iter().map(|feat| feat.to_geometry()).collect())
+        }
+        ReturnTypeArg::Feature => {
+            if value.features.len() == 1 {
+                let feat = GeoFormats::Feature(value.features.first().unwrap().clone());
+                feat
+            } else {
+                log::info!("\"Feature\" was requested as the return type but multiple features were found so a Vec of features is being returned");
+                GeoFormats::FeatureVec(value.features)
+            }
+        }
+        ReturnTypeArg::FeatureVec => GeoFormats::FeatureVec(value.features),
+        ReturnTypeArg::FeatureCollection => GeoFormats::FeatureCollection(value),
+        ReturnTypeArg::Poracle => GeoFormats::Poracle(value.to_poracle_vec()),
+        ReturnTypeArg::PoracleSingle => {
+            GeoFormats::PoracleSingle(value.to_poracle_vec().first().unwrap().clone())
+        }
+        ReturnTypeArg::Sql => GeoFormats::Text(value.to_sql()),
+        ReturnTypeArg::Kml => GeoFormats::Text(value.to_kml()),
+        ReturnTypeArg::Kmz => GeoFormats::Text(value.to_kmz()),
+        ReturnTypeArg::Gpx => GeoFormats::Text(value.to_gpx()),
+        ReturnTypeArg::Wkt => GeoFormats::Text(value.to_wkt()),
+        ReturnTypeArg::Wkb => GeoFormats::Text(
+            value
+                .to_wkb()
+                .iter()
+                .map(|byte| format!("{:02X}", byte))
+                .collect(),
+        ),
+        ReturnTypeArg::Shapefile => GeoFormats::Text(value.to_shapefile()),
+        ReturnTypeArg::ClusterStats => {
+            GeoFormats::FeatureCollection(cluster_stats_collection(value, stats))
+        }
+    }
 }
@@ -20,6 +20,9 @@ mod m20230301_051446_tile_server_table;
 mod m20230407_045757_parent_column;
 mod m20230505_150751_hop_count;
 mod m20230626_155916_project_description;
+mod m20261017_120000_job_table;
+mod m20261017_130000_result_cache_table;
+mod m20261017_140000_result_cache_created_at;
 
 pub struct Migrator;
 
@@ -47,6 +50,9 @@ impl MigratorTrait for Migrator {
             Box::new(m20230407_045757_parent_column::Migration),
             Box::new(m20230505_150751_hop_count::Migration),
             Box::new(m20230626_155916_project_description::Migration),
+            Box::new(m20261017_120000_job_table::Migration),
+            Box::new(m20261017_130000_result_cache_table::Migration),
+            Box::new(m20261017_140000_result_cache_created_at::Migration),
         ]
     }
 }
@@ -0,0 +1,132 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        log::info!("[MIGRATION_21] creating job table");
+        manager
+            .create_table(
+                Table::create()
+                    .table(Job::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(Job::Id)
+                            .integer()
+                            .not_null()
+                            .unsigned()
+                            .auto_increment()
+                            .primary_key(),
+                    )
+                    .col(ColumnDef::new(Job::Endpoint).string().not_null())
+                    .col(
+                        ColumnDef::new(Job::Status)
+                            .enumeration(
+                                Status::Enum,
+                                vec![
+                                    Status::Queued,
+                                    Status::Running,
+                                    Status::Completed,
+                                    Status::Failed,
+                                    Status::Cancelled,
+                                ],
+                            )
+                            .not_null(),
+                    )
+                    .col(ColumnDef::new(Job::Phase).enumeration(
+                        Phase::Enum,
+                        vec![
+                            Phase::FetchingPoints,
+                            Phase::Clustering,
+                            Phase::Routing,
+                            Phase::Stats,
+                        ],
+                    ))
+                    .col(
+                        ColumnDef::new(Job::Progress)
+                            .tiny_unsigned()
+                            .not_null()
+                            .default(0),
+                    )
+                    .col(ColumnDef::new(Job::Args).json().not_null())
+                    .col(ColumnDef::new(Job::Result).json())
+                    .col(ColumnDef::new(Job::Details).json())
+                    .col(ColumnDef::new(Job::Stats).json())
+                    .col(ColumnDef::new(Job::Error).text())
+                    .col(ColumnDef::new(Job::StartedAt).timestamp().null())
+                    .col(ColumnDef::new(Job::FinishedAt).timestamp().null())
+                    .col(
+                        ColumnDef::new(Job::CreatedAt)
+                            .timestamp()
+                            .not_null()
+                            .extra("DEFAULT CURRENT_TIMESTAMP".to_string()),
+                    )
+                    .col(
+                        ColumnDef::new(Job::UpdatedAt).timestamp().not_null().extra(
+                            "DEFAULT CURRENT_TIMESTAMP ON UPDATE CURRENT_TIMESTAMP".to_string(),
+                        ),
+                    )
+                    .index(Index::create().name("idx_job_status").col(Job::Status))
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        log::info!("[MIGRATION_21] dropping job table");
+        manager
+            .drop_table(Table::drop().table(Job::Table).to_owned())
+            .await
+    }
+}
+
+#[derive(Iden)]
+enum Job {
+    Table,
+    Id,
+    Endpoint,
+    Status,
+    Phase,
+    Progress,
+    Args,
+    Result,
+    Details,
+    Stats,
+    Error,
+    StartedAt,
+    FinishedAt,
+    CreatedAt,
+    UpdatedAt,
+}
+
+#[derive(Iden)]
+enum Status {
+    #[iden = "status"]
+    Enum,
+    #[iden = "queued"]
+    Queued,
+    #[iden = "running"]
+    Running,
+    #[iden = "completed"]
+    Completed,
+    #[iden = "failed"]
+    Failed,
+    #[iden = "cancelled"]
+    Cancelled,
+}
+
+#[derive(Iden)]
+enum Phase {
+    #[iden = "phase"]
+    Enum,
+    #[iden = "fetching_points"]
+    FetchingPoints,
+    #[iden = "clustering"]
+    Clustering,
+    #[iden = "routing"]
+    Routing,
+    #[iden = "stats"]
+    Stats,
+}
@@ -0,0 +1,47 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        log::info!("[MIGRATION_22] creating result_cache table");
+        manager
+            .create_table(
+                Table::create()
+                    .table(ResultCache::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(ResultCache::Hash)
+                            .string()
+                            .not_null()
+                            .primary_key(),
+                    )
+                    .col(ColumnDef::new(ResultCache::Result).json().not_null())
+                    .col(
+                        ColumnDef::new(ResultCache::CreatedAt)
+                            .timestamp()
+                            .not_null()
+                            .extra("DEFAULT CURRENT_TIMESTAMP".to_string()),
+                    )
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        log::info!("[MIGRATION_22] dropping result_cache table");
+        manager
+            .drop_table(Table::drop().table(ResultCache::Table).to_owned())
+            .await
+    }
+}
+
+#[derive(Iden)]
+enum ResultCache {
+    Table,
+    Hash,
+    Result,
+    CreatedAt,
+}
@@ -0,0 +1,38 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        log::info!("[MIGRATION_23] indexing the created_at column of result_cache");
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx_result_cache_created_at")
+                    .table(ResultCache::Table)
+                    .col(ResultCache::CreatedAt)
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        log::info!("[MIGRATION_23] dropping the created_at index of result_cache");
+        manager
+            .drop_index(
+                Index::drop()
+                    .name("idx_result_cache_created_at")
+                    .table(ResultCache::Table)
+                    .to_owned(),
+            )
+            .await
+    }
+}
+
+#[derive(Iden)]
+enum ResultCache {
+    Table,
+    CreatedAt,
+}
@@ -5,6 +5,9 @@ use crate::{
     utils::{get_enum, get_enum_by_geometry_string},
 };
 
+/// Longest time budget of the routing algorithms, in seconds
+const MAX_ROUTING_TIME: i64 = 3_600;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Auth {
     pub password: String,
@@ -172,6 +175,18 @@ pub enum ReturnTypeArg {
     PoracleSingle,
     Poracle,
     Sql,
+    /// A FeatureCollection of the clusters with their detailed stats as properties
+    ClusterStats,
+    Kml,
+    /// Zipped KML, encoded as base64
+    Kmz,
+    /// Each route as a GPX route, its points are named by their hop index
+    Gpx,
+    Wkt,
+    /// Hex encoded WKB
+    Wkb,
+    /// Zipped ESRI Shapefile of the polygons, encoded as base64
+    Shapefile,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -181,6 +196,66 @@ pub enum SpawnpointTth {
     Unknown,
 }
 
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub enum DeviceBalance {
+    /// Each loop has the same number of clusters
+    Hops,
+    /// Each loop has roughly the same length
+    Distance,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub enum CooldownModel {
+    /// Interpolated from the distance/cooldown table used by Pokémon GO
+    Standard,
+    /// Distance divided by `route_speed`
+    Speed,
+}
+
+/// Weights of data points pulled from the database, any weight that isn't set defaults to `1`
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct DataPointWeights {
+    /// Spawnpoints with a known despawn time
+    pub known_tth: Option<Precision>,
+    /// Spawnpoints without a known despawn time
+    pub unknown_tth: Option<Precision>,
+    pub gym: Option<Precision>,
+    pub pokestop: Option<Precision>,
+}
+
+impl DataPointWeights {
+    /// Returns the weight of a data point based on the prefix of its normalized id
+    pub fn get(&self, id: &str) -> Precision {
+        match id.chars().next() {
+            Some('v') => self.known_tth,
+            Some('u') => self.unknown_tth,
+            Some('g') => self.gym,
+            Some('p') => self.pokestop,
+            _ => None,
+        }
+        .unwrap_or(1.)
+    }
+}
+
+/// Limits on how far apart consecutive clusters of a route can be
+#[derive(Debug, Clone, Serialize)]
+pub struct HopConstraints {
+    /// Longest allowed hop in meters
+    pub max_distance: Option<Precision>,
+    /// Inserts intermediate points into hops that are still too long after reordering
+    pub fill: bool,
+    pub cooldown: Option<CooldownModel>,
+    /// Meters per second, used by [CooldownModel::Speed]
+    pub speed: Precision,
+}
+
+/// Endpoints of an open path, either end can be left as `None` to let the route finish anywhere
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct OpenPath {
+    pub start: Option<point_array::PointArray>,
+    pub end: Option<point_array::PointArray>,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(untagged)]
 pub enum DataPointsArg {
@@ -188,6 +263,66 @@ pub enum DataPointsArg {
     Struct(single_struct::SingleStruct),
     Feature(Feature),
     FeatureCollection(FeatureCollection),
+    /// A GPX document, e.g. a walk recording or a list of waypoints
+    Gpx(String),
+}
+
+impl ToSingleVec for DataPointsArg {
+    fn to_single_vec(self) -> single_vec::SingleVec {
+        resolve_data_points(Some(self))
+    }
+}
+
+/// A route saved in the Kōji database, by id or name, or the route itself
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum RouteArg {
+    /// A GPX document, tried first since it's a string like the name of a stored route
+    Gpx(#[serde(deserialize_with = "deserialize_gpx")] String),
+    Stored(UnknownId),
+    Inline(DataPointsArg),
+}
+
+fn deserialize_gpx<'de, D>(deserializer: D) -> Result<String, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let text = String::deserialize(deserializer)?;
+    if gpx::detect(&text) {
+        Ok(text)
+    } else {
+        Err(serde::de::Error::custom("expected a GPX document"))
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct RouteDiffArgs {
+    /// The route to compare against, usually the one that is currently saved
+    ///
+    /// Accepts [RouteArg]
+    pub before: RouteArg,
+    /// The new route
+    ///
+    /// Accepts [RouteArg]
+    pub after: RouteArg,
+    /// Data points used to compare the coverage of both routes
+    ///
+    /// Accepts [DataPointsArg]
+    ///
+    /// Default: `None`
+    pub data_points: Option<DataPointsArg>,
+    /// Radius of the clusters, in meters
+    ///
+    /// Default: `70`
+    pub radius: Option<Precision>,
+    /// Minimum number of points used when scoring both routes
+    ///
+    /// Default: `1`
+    pub min_points: Option<usize>,
+    /// Compares the routes as open paths, a closed loop is compared regardless of where it starts
+    ///
+    /// Default: `false`
+    pub open_path: Option<bool>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -218,6 +353,18 @@ pub struct Args {
     ///
     /// Default: `false`
     pub benchmark_mode: Option<bool>,
+    /// Aligns honeycomb and radius bootstrap circles to a global hex lattice,
+    /// so the circles of neighboring areas with the same radius line up
+    ///
+    /// Default: `false`, implied when `neighbors` are set
+    pub fixed_grid: Option<bool>,
+    /// Areas bordering `area` that are also bootstrapped on the fixed grid.
+    /// Border circles are only kept by the area that has the best claim to them
+    ///
+    /// Accepts an optional [GeoFormats]
+    ///
+    /// Default: `None`
+    pub neighbors: Option<GeoFormats>,
     /// Args to be applied to a custom bootstrapping plugin
     ///
     /// Default: `''`
@@ -232,6 +379,16 @@ pub struct Args {
     ///
     /// Default: `''`
     pub clustering_args: Option<String>,
+    /// Amount of time in seconds for the `Optimal` cluster mode to search before it returns
+    /// the best solution found so far
+    ///
+    /// Default: `0` (auto)
+    pub clustering_time: Option<u64>,
+    /// Amount of time in seconds to spend trying to remove clusters after clustering,
+    /// by merging nearby clusters and shifting neighbors to cover the points of another
+    ///
+    /// Default: `0` (disabled)
+    pub local_search_time: Option<u64>,
     /// Cluster mode selection
     ///
     /// Accepts [ClusterMode]
@@ -247,22 +404,43 @@ pub struct Args {
     /// Data points to cluster or reroute.
     /// Overrides any inputted area.
     ///
+    /// Features with a numeric `weight` property enable weighted clustering,
+    /// points without one are weighted `1`
+    ///
     /// Accepts [DataPointsArg]
     pub data_points: Option<DataPointsArg>,
     /// Clusters to run through the stat producer.
     ///
     /// Accepts [DataPointsArg]
     pub clusters: Option<DataPointsArg>,
-    /// Number of devices to use in VRP routing
+    /// Number of devices to split the route between,
+    /// each device gets its own loop
     ///
     /// Default: `1`
-    ///
-    /// Deprecated
     pub devices: Option<usize>,
+    /// How the loops are balanced when routing for multiple devices
+    ///
+    /// Accepts [DeviceBalance]
+    ///
+    /// Default: `Hops`
+    pub device_balance: Option<DeviceBalance>,
     /// The maximum amount of clusters to return
     ///
     /// Default: [USIZE::MAX]
     pub max_clusters: Option<usize>,
+    /// If true, the route is returned as an open path that doesn't loop back to its first cluster.
+    /// Implied when `route_start` or `route_end` are set
+    ///
+    /// Default: `false`
+    pub open_path: Option<bool>,
+    /// Point that an open path should start from, in `[lat, lon]` format
+    ///
+    /// Default: `None` (start anywhere)
+    pub route_start: Option<point_array::PointArray>,
+    /// Point that an open path should end at, in `[lat, lon]` format
+    ///
+    /// Default: `None` (end anywhere)
+    pub route_end: Option<point_array::PointArray>,
     /// Whether to use the fast or slow clustering algorithm
     ///
     /// Default: `true`
@@ -301,6 +479,11 @@ pub struct Args {
     ///
     /// Default: `None`
     pub parent: Option<UnknownId>,
+    /// When clustering the children of `parent`, assigns each cluster to the child that contains it
+    /// and returns a separate route for every child instead of one route for the parent
+    ///
+    /// Default: `false`
+    pub split_by_child: Option<bool>,
     /// Radius of the circle to be used in clustering/routing,
     /// in meters
     ///
@@ -328,12 +511,37 @@ pub struct Args {
     ///
     /// Default: `1`
     pub route_split_level: Option<u64>,
-    /// Amount of time for the TSP solver to run
-    ///
-    /// Default: `0` (auto)
+    /// Amount of time in seconds for the TSP solver to run
     ///
-    /// Deprecated
+    /// Default: `0` (auto), negative values are treated as `0` and values above `3600` as `3600`
     pub routing_time: Option<i64>,
+    /// Travel speed used to estimate arrival times for time-aware routing,
+    /// in meters per second
+    ///
+    /// Default: `25`
+    pub route_speed: Option<Precision>,
+    /// Second of the hour that a time-aware route starts at, used to estimate arrival times
+    ///
+    /// Accepts 0-3599, larger values are rejected
+    ///
+    /// Default: `0`
+    pub route_start_offset: Option<u32>,
+    /// Longest distance allowed between two consecutive clusters of a route, in meters.
+    /// Routes are reordered to avoid longer hops where possible
+    ///
+    /// Default: `None` (unlimited)
+    pub max_hop_distance: Option<Precision>,
+    /// Inserts intermediate points into hops that are still longer than `max_hop_distance`
+    /// after reordering
+    ///
+    /// Default: `true`
+    pub hop_fill: Option<bool>,
+    /// Estimates the teleport cooldown of each hop, routes are reordered to reduce the total
+    ///
+    /// Accepts [CooldownModel]
+    ///
+    /// Default: `None`
+    pub cooldown: Option<CooldownModel>,
     /// S2 Level to use for calculation mode
     ///
     /// Accepts 10-20
@@ -354,6 +562,14 @@ pub struct Args {
     ///
     /// Default: `false`
     pub save_to_scanner: Option<bool>,
+    /// Updates an existing route instead of clustering and routing from scratch.
+    /// Uses `clusters` as the existing route, or the route saved in the Kōji database under `instance`.
+    /// Clusters that still cover a data point keep their order, clusters for newly uncovered
+    /// data points are inserted at their cheapest position.
+    /// Can't be combined with `devices`
+    ///
+    /// Default: `false`
+    pub incremental: Option<bool>,
     /// Simplifies Polygons and MultiPolygons when converting them
     ///
     /// Default: `false`
@@ -376,38 +592,77 @@ pub struct Args {
     ///
     /// Default: `false`
     pub center_clusters: Option<bool>,
+    /// Adds a record for every cluster to the stats, with the number of points it covers,
+    /// the number of points only it covers, the distance to the next cluster, and its S2 cell,
+    /// along with histograms of the cluster sizes and hop distances.
+    /// The size bins double in width, `0`, `1`, `2-3`, `4-7`, and so on
+    /// Implied when `return_type` is `ClusterStats`
+    ///
+    /// Default: `false`
+    pub detailed_stats: Option<bool>,
+    /// Skips the result cache, the clusters and routes are always recalculated
+    ///
+    /// Default: `false`
+    pub no_cache: Option<bool>,
+    /// Enables weighted clustering for data points pulled from the database,
+    /// clusters are picked by the total weight they cover instead of the number of points
+    ///
+    /// Accepts [DataPointWeights]
+    ///
+    /// Default: `None`
+    pub weights: Option<DataPointWeights>,
 }
 
+#[derive(Serialize)]
 pub struct ArgsUnwrapped {
     pub area: FeatureCollection,
+    pub fixed_grid: bool,
+    pub neighbors: FeatureCollection,
     pub benchmark_mode: bool,
     pub calculation_mode: CalculationMode,
     pub cluster_mode: ClusterMode,
     pub cluster_split_level: u64,
     pub max_clusters: usize,
+    pub open_path: Option<OpenPath>,
+    #[serde(skip)]
     pub clusters: single_vec::SingleVec,
+    #[serde(skip)]
     pub data_points: single_vec::SingleVec,
+    #[serde(skip)]
+    pub data_point_weights: Vec<Precision>,
     pub devices: usize,
+    pub device_balance: DeviceBalance,
     pub generations: usize,
     pub instance: String,
     pub min_points: usize,
     pub radius: Precision,
     pub return_type: ReturnTypeArg,
     pub parent: Option<UnknownId>,
+    pub split_by_child: bool,
     pub last_seen: u32,
     pub s2_level: u8,
     pub s2_size: u8,
     pub save_to_db: bool,
     pub save_to_scanner: bool,
+    pub incremental: bool,
     pub simplify: bool,
     pub sort_by: SortBy,
     pub tth: SpawnpointTth,
     pub mode: Type,
     pub route_split_level: u64,
     pub routing_args: String,
+    pub routing_time: u64,
+    pub route_speed: Precision,
+    pub route_start_offset: u32,
+    pub hop_constraints: Option<HopConstraints>,
     pub clustering_args: String,
+    pub clustering_time: u64,
+    pub local_search_time: u64,
     pub bootstrapping_args: String,
     pub center_clusters: bool,
+    pub detailed_stats: bool,
+    pub no_cache: bool,
+    pub weights: Option<DataPointWeights>,
 }
 
 fn validate_s2_cell(value_to_check: Option<u64>, label: &str) -> u64 {
@@ -427,6 +682,31 @@ fn validate_s2_cell(value_to_check: Option<u64>, label: &str) -> u64 {
     }
 }
 
+/// Reads the `weight` property of each feature,
+/// returns an empty Vec when none of the features are weighted
+fn resolve_data_point_weights(data_points: &Option<DataPointsArg>) -> Vec<Precision> {
+    let features = match data_points {
+        Some(DataPointsArg::Feature(feature)) => vec![feature.clone()],
+        Some(DataPointsArg::FeatureCollection(fc)) => fc.features.clone(),
+        _ => return vec![],
+    };
+    let get_weight = |feature: &Feature| {
+        feature
+            .property("weight")
+            .and_then(|weight| weight.as_f64())
+    };
+    if !features.iter().any(|feature| get_weight(feature).is_some()) {
+        return vec![];
+    }
+    features
+        .into_iter()
+        .flat_map(|feature| {
+            let weight = get_weight(&feature).unwrap_or(1.);
+            vec![weight; feature.to_single_vec().len()]
+        })
+        .collect()
+}
+
 fn resolve_data_points(data_points: Option<DataPointsArg>) -> single_vec::SingleVec {
     if let Some(data_points) = data_points {
         match data_points {
@@ -434,6 +714,13 @@ fn resolve_data_points(data_points: Option<DataPointsArg>) -> single_vec::Single
             DataPointsArg::Array(data_points) => data_points,
             DataPointsArg::Feature(data_points) => data_points.to_single_vec(),
             DataPointsArg::FeatureCollection(data_points) => data_points.to_single_vec(),
+            DataPointsArg::Gpx(data_points) => match gpx::parse(&data_points) {
+                Ok(collection) => collection.to_single_vec(),
+                Err(err) => {
+                    log::error!("[GPX] Unable to parse the data points: {}", err);
+                    vec![]
+                }
+            },
         }
     } else {
         vec![]
@@ -448,15 +735,21 @@ impl Args {
         let Args {
             area,
             benchmark_mode,
+            fixed_grid,
+            neighbors,
             s2_level,
             calculation_mode,
             cluster_mode,
             cluster_split_level,
             max_clusters,
+            open_path,
+            route_start,
+            route_end,
             s2_size,
             clusters,
             data_points,
             devices,
+            device_balance,
             fast,
             generations,
             instance,
@@ -464,11 +757,18 @@ impl Args {
             radius,
             return_type,
             routing_time,
+            route_speed,
+            route_start_offset,
+            max_hop_distance,
+            hop_fill,
+            cooldown,
             only_unique,
             parent,
+            split_by_child,
             last_seen,
             save_to_db,
             save_to_scanner,
+            incremental,
             route_chunk_size,
             simplify,
             geometry_type,
@@ -478,16 +778,31 @@ impl Args {
             route_split_level,
             routing_args,
             clustering_args,
+            clustering_time,
+            local_search_time,
             bootstrapping_args,
             center_clusters,
+            detailed_stats,
+            no_cache,
+            weights,
         } = self;
         let enum_type = get_enum_by_geometry_string(geometry_type);
         let (area, default_return_type) = if let Some(area) = area {
             (
                 area.clone().to_collection(instance.clone(), enum_type),
                 match area {
+                    GeoFormats::Wkt(_) => ReturnTypeArg::Wkt,
                     GeoFormats::Text(area) => {
-                        if area.text_test() {
+                        if shapefile::detect(&area) {
+                            ReturnTypeArg::Shapefile
+                        } else if let Some(encoding) = kml::detect(&area) {
+                            match encoding {
+                                kml::Encoding::Kml => ReturnTypeArg::Kml,
+                                kml::Encoding::Kmz => ReturnTypeArg::Kmz,
+                            }
+                        } else if gpx::detect(&area) {
+                            ReturnTypeArg::Gpx
+                        } else if area.text_test() {
                             ReturnTypeArg::AltText
                         } else {
                             ReturnTypeArg::Text
@@ -509,6 +824,10 @@ impl Args {
             (FeatureCollection::default(), ReturnTypeArg::SingleArray)
         };
         let benchmark_mode = benchmark_mode.unwrap_or(false);
+        let neighbors = neighbors
+            .map(|neighbors| neighbors.to_collection(None, None))
+            .unwrap_or(FeatureCollection::default());
+        let fixed_grid = fixed_grid.unwrap_or(false) || !neighbors.features.is_empty();
         let calculation_mode = calculation_mode.unwrap_or(CalculationMode::Radius);
         let s2_level = s2_level.unwrap_or(15);
         let s2_size = s2_size.unwrap_or(9);
@@ -524,8 +843,10 @@ impl Args {
             }
         });
         let cluster_split_level = validate_s2_cell(cluster_split_level, "cluster_split_level");
+        let data_point_weights = resolve_data_point_weights(&data_points);
         let data_points = resolve_data_points(data_points);
         let devices = devices.unwrap_or(1);
+        let device_balance = device_balance.unwrap_or(DeviceBalance::Hops);
         let generations = generations.unwrap_or(1);
         let instance = instance.unwrap_or("".to_string());
         let min_points = min_points.unwrap_or(1);
@@ -544,18 +865,48 @@ impl Args {
         } else {
             usize::MAX
         };
+        let open_path =
+            if open_path.unwrap_or(false) || route_start.is_some() || route_end.is_some() {
+                Some(OpenPath {
+                    start: route_start,
+                    end: route_end,
+                })
+            } else {
+                None
+            };
         let center_clusters = center_clusters.unwrap_or(false);
+        let detailed_stats =
+            detailed_stats.unwrap_or(false) || matches!(return_type, ReturnTypeArg::ClusterStats);
+        let no_cache = no_cache.unwrap_or(false);
         let clusters = resolve_data_points(clusters);
         let last_seen = last_seen.unwrap_or(0);
+        let split_by_child = split_by_child.unwrap_or(false);
         let save_to_db = save_to_db.unwrap_or(false);
         let save_to_scanner = save_to_scanner.unwrap_or(false);
+        let incremental = incremental.unwrap_or(false);
         let simplify = simplify.unwrap_or(false);
         let sort_by = sort_by.unwrap_or(SortBy::Unset);
         let tth = tth.unwrap_or(SpawnpointTth::All);
         let mode = get_enum(mode);
         let route_split_level = validate_s2_cell(route_split_level, "route_split_level");
         let routing_args = routing_args.unwrap_or("".to_string());
+        let routing_time = routing_time.unwrap_or(0).clamp(0, MAX_ROUTING_TIME) as u64;
+        let route_speed = route_speed.unwrap_or(25.);
+        let route_start_offset = route_start_offset.unwrap_or(0);
+        let max_hop_distance = max_hop_distance.filter(|distance| *distance > 0.);
+        let hop_constraints = if max_hop_distance.is_some() || cooldown.is_some() {
+            Some(HopConstraints {
+                max_distance: max_hop_distance,
+                fill: hop_fill.unwrap_or(true),
+                cooldown,
+                speed: route_speed,
+            })
+        } else {
+            None
+        };
 
+        let clustering_time = clustering_time.unwrap_or(0);
+        let local_search_time = local_search_time.unwrap_or(0);
         let mut clustering_args = clustering_args.unwrap_or("".to_string());
         clustering_args += &format!(" --radius {}", radius);
         clustering_args += &format!(" --min_points {}", min_points);
@@ -567,26 +918,29 @@ impl Args {
         if route_chunk_size.is_some() {
             log::warn!("route_chunk_size is now deprecated, please use route_split_level")
         }
-        if routing_time.is_some() {
-            log::warn!("routing_time is now deprecated, please use route_split_level")
-        }
         if only_unique.is_some() {
             log::warn!("only_unique is now deprecated and does nothing");
         }
         ArgsUnwrapped {
             area,
             benchmark_mode,
+            fixed_grid,
+            neighbors,
             cluster_mode,
             clusters,
             max_clusters,
+            open_path,
             cluster_split_level,
             s2_level,
             calculation_mode,
             s2_size,
             data_points,
+            data_point_weights,
             devices,
+            device_balance,
             generations,
             parent,
+            split_by_child,
             instance,
             min_points,
             radius,
@@ -594,15 +948,25 @@ impl Args {
             last_seen,
             save_to_db,
             save_to_scanner,
+            incremental,
             simplify,
             sort_by,
             tth,
             mode,
             route_split_level,
             routing_args,
+            routing_time,
+            route_speed,
+            route_start_offset,
+            hop_constraints,
             clustering_args,
+            clustering_time,
+            local_search_time,
             bootstrapping_args,
             center_clusters,
+            detailed_stats,
+            no_cache,
+            weights,
         }
     }
 }
@@ -632,6 +996,13 @@ pub fn get_return_type(return_type: String, default_return_type: &ReturnTypeArg)
         "poracle" => ReturnTypeArg::Poracle,
         "featurecollection" | "feature_collection" => ReturnTypeArg::FeatureCollection,
         "sql" => ReturnTypeArg::Sql,
+        "clusterstats" | "cluster_stats" => ReturnTypeArg::ClusterStats,
+        "kml" => ReturnTypeArg::Kml,
+        "kmz" => ReturnTypeArg::Kmz,
+        "gpx" => ReturnTypeArg::Gpx,
+        "wkt" => ReturnTypeArg::Wkt,
+        "wkb" => ReturnTypeArg::Wkb,
+        "shapefile" | "shp" => ReturnTypeArg::Shapefile,
         _ => default_return_type.clone(),
     }
 }
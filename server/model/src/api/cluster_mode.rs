@@ -1,6 +1,6 @@
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub enum ClusterMode {
     Honeycomb,
     Fastest,
@@ -8,6 +8,7 @@ pub enum ClusterMode {
     Balanced,
     Better,
     Best,
+    Optimal,
     Custom(String),
 }
 
@@ -24,6 +25,7 @@ impl<'de> Deserialize<'de> for ClusterMode {
             "balanced" => Ok(ClusterMode::Balanced),
             "better" => Ok(ClusterMode::Better),
             "best" => Ok(ClusterMode::Best),
+            "optimal" => Ok(ClusterMode::Optimal),
             "bruteforce" => {
                 log::warn!("bruteforce is now deprecated, using `better` strategy instead");
                 Ok(ClusterMode::Better)
@@ -45,7 +47,8 @@ impl PartialEq for ClusterMode {
             | (ClusterMode::Fast, ClusterMode::Fast)
             | (ClusterMode::Balanced, ClusterMode::Balanced)
             | (ClusterMode::Better, ClusterMode::Better)
-            | (ClusterMode::Best, ClusterMode::Best) => true,
+            | (ClusterMode::Best, ClusterMode::Best)
+            | (ClusterMode::Optimal, ClusterMode::Optimal) => true,
             _ => false,
         }
     }
@@ -62,6 +65,7 @@ impl ToString for ClusterMode {
             ClusterMode::Balanced => "Balanced",
             ClusterMode::Better => "Better",
             ClusterMode::Best => "Best",
+            ClusterMode::Optimal => "Optimal",
             ClusterMode::Custom(s) => s,
         }
         .to_string()
@@ -0,0 +1,269 @@
+//! GPX, e.g. walk recordings or waypoint lists from other apps
+//!
+//! Waypoints are read into points, routes and tracks into multi points so they keep their order,
+//! the segments of a track are joined together. `<name>` becomes `name`. Routes are written as
+//! `<rte>` with each point named by its hop index, starting at 1.
+use roxmltree::{Document, Node};
+use serde_json::Map;
+
+use super::{
+    kml::{escape, is},
+    *,
+};
+
+/// Whether the text looks like a GPX document
+pub fn detect(text: &str) -> bool {
+    let text = text.trim_start();
+    text.starts_with('<') && text.contains("<gpx")
+}
+
+/// Parses the text if it's GPX, `None` when it isn't
+pub fn from_text(text: &str) -> Option<Result<FeatureCollection, String>> {
+    if detect(text) {
+        Some(parse(text))
+    } else {
+        None
+    }
+}
+
+pub fn parse(gpx: &str) -> Result<FeatureCollection, String> {
+    let document = Document::parse(gpx).map_err(|err| err.to_string())?;
+    let root = document.root_element();
+    if root.tag_name().name() != "gpx" {
+        return Err(format!(
+            "expected a <gpx> document, found <{}>",
+            root.tag_name().name()
+        ));
+    }
+    let features = root
+        .children()
+        .filter_map(|node| match node.tag_name().name() {
+            "wpt" => point(&node).map(|point| feature(&node, Value::Point(point))),
+            "rte" => multi_point(&node, node.children().filter(|child| is(child, "rtept"))),
+            "trk" => multi_point(
+                &node,
+                node.children()
+                    .filter(|child| is(child, "trkseg"))
+                    .flat_map(|segment| segment.children())
+                    .filter(|child| is(child, "trkpt")),
+            ),
+            _ => None,
+        })
+        .collect();
+    Ok(FeatureCollection {
+        bbox: None,
+        features,
+        foreign_members: None,
+    })
+}
+
+/// `[lon, lat]` from the attributes of a `wpt`, `rtept` or `trkpt`
+fn point(node: &Node) -> Option<Vec<f64>> {
+    let coord = |name: &str| node.attribute(name)?.trim().parse::<f64>().ok();
+    Some(vec![coord("lon")?, coord("lat")?])
+}
+
+fn multi_point<'a, 'input: 'a>(
+    node: &Node,
+    points: impl Iterator<Item = Node<'a, 'input>>,
+) -> Option<Feature> {
+    let points: Vec<Vec<f64>> = points.filter_map(|point| self::point(&point)).collect();
+    if points.is_empty() {
+        None
+    } else {
+        Some(feature(node, Value::MultiPoint(points)))
+    }
+}
+
+fn feature(node: &Node, value: Value) -> Feature {
+    let mut properties = Map::new();
+    let name = node
+        .children()
+        .find(|child| is(child, "name"))
+        .and_then(|child| child.text())
+        .map(|name| name.trim().to_string());
+    if let Some(name) = name {
+        properties.insert("name".to_string(), name.into());
+    }
+    Feature {
+        bbox: None,
+        geometry: Some(Geometry::new(value)),
+        id: None,
+        properties: Some(properties),
+        foreign_members: None,
+    }
+}
+
+fn write_name(name: Option<&str>) -> String {
+    name.map(|name| format!("<name>{}</name>", escape(name)))
+        .unwrap_or_default()
+}
+
+fn write_point(tag: &str, point: &[f64], name: Option<&str>) -> String {
+    if point.len() < 2 {
+        return String::new();
+    }
+    format!(
+        "<{tag} lat=\"{}\" lon=\"{}\">{}</{tag}>",
+        point[1],
+        point[0],
+        write_name(name)
+    )
+}
+
+fn write_track(segments: &[Vec<Vec<f64>>], name: Option<&str>) -> String {
+    let segments: String = segments
+        .iter()
+        .map(|segment| {
+            let points: String = segment
+                .iter()
+                .map(|point| write_point("trkpt", point, None))
+                .collect();
+            format!("<trkseg>{}</trkseg>", points)
+        })
+        .collect();
+    format!("<trk>{}{}</trk>", write_name(name), segments)
+}
+
+/// GPX requires the waypoints to come before the routes and the routes before the tracks
+#[derive(Default)]
+struct Elements {
+    waypoints: String,
+    routes: String,
+    tracks: String,
+}
+
+impl Elements {
+    fn geometry(&mut self, value: &Value, name: Option<&str>) {
+        match value {
+            Value::Point(point) => self.waypoints.push_str(&write_point("wpt", point, name)),
+            Value::MultiPoint(points) => {
+                let points: String = points
+                    .iter()
+                    .enumerate()
+                    .map(|(hop, point)| write_point("rtept", point, Some(&(hop + 1).to_string())))
+                    .collect();
+                self.routes
+                    .push_str(&format!("<rte>{}{}</rte>", write_name(name), points));
+            }
+            Value::LineString(line) => self.tracks.push_str(&write_track(&[line.clone()], name)),
+            Value::MultiLineString(lines) => self.tracks.push_str(&write_track(lines, name)),
+            Value::Polygon(rings) => self.tracks.push_str(&write_track(rings, name)),
+            Value::MultiPolygon(polygons) => {
+                self.tracks.push_str(&write_track(&polygons.concat(), name))
+            }
+            Value::GeometryCollection(geometries) => geometries
+                .iter()
+                .for_each(|geometry| self.geometry(&geometry.value, name)),
+        }
+    }
+
+    fn feature(&mut self, feature: &Feature) {
+        let name = feature
+            .property("__name")
+            .or_else(|| feature.property("name"))
+            .and_then(|name| name.as_str());
+        if let Some(geometry) = feature.geometry.as_ref() {
+            self.geometry(&geometry.value, name);
+        }
+    }
+}
+
+impl ToGpx for FeatureCollection {
+    fn to_gpx(self) -> String {
+        let mut elements = Elements::default();
+        self.features
+            .iter()
+            .for_each(|feature| elements.feature(feature));
+        format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<gpx version=\"1.1\" creator=\"Koji\" xmlns=\"http://www.topografix.com/GPX/1/1\">{}{}{}</gpx>",
+            elements.waypoints, elements.routes, elements.tracks
+        )
+    }
+}
+
+impl ToGpx for Feature {
+    fn to_gpx(self) -> String {
+        self.to_collection(None, None).to_gpx()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RECORDING: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<gpx version="1.1" creator="Tracker" xmlns="http://www.topografix.com/GPX/1/1">
+  <wpt lat="40.78" lon="-73.97"><name>Start</name></wpt>
+  <trk>
+    <name>Morning Walk</name>
+    <trkseg>
+      <trkpt lat="40.76" lon="-73.98"><ele>10</ele></trkpt>
+      <trkpt lat="40.77" lon="-73.97"></trkpt>
+    </trkseg>
+    <trkseg>
+      <trkpt lat="40.78" lon="-73.96"></trkpt>
+    </trkseg>
+  </trk>
+</gpx>"#;
+
+    #[test]
+    fn parses_waypoints_and_tracks() {
+        let collection = from_text(RECORDING).unwrap().unwrap();
+        assert_eq!(collection.features.len(), 2);
+        assert_eq!(
+            collection.features[1].property("name").unwrap(),
+            "Morning Walk"
+        );
+        assert_eq!(
+            collection.to_single_vec(),
+            vec![
+                [40.78, -73.97],
+                [40.76, -73.98],
+                [40.77, -73.97],
+                [40.78, -73.96]
+            ]
+        );
+    }
+
+    #[test]
+    fn writes_waypoints_then_routes_then_tracks() {
+        let geometry = |value| Some(Geometry::new(value));
+        let track = Feature {
+            geometry: geometry(Value::LineString(vec![
+                vec![-73.98, 40.76],
+                vec![-73.97, 40.77],
+            ])),
+            ..Default::default()
+        };
+        let mixed = Feature {
+            geometry: geometry(Value::GeometryCollection(vec![
+                Geometry::new(Value::MultiPoint(vec![vec![-73.96, 40.78]])),
+                Geometry::new(Value::Point(vec![-73.95, 40.79])),
+            ])),
+            ..Default::default()
+        };
+        let gpx = vec![track, mixed]
+            .into_iter()
+            .collect::<FeatureCollection>()
+            .to_gpx();
+        let wpt = gpx.find("<wpt").unwrap();
+        let rte = gpx.find("<rte").unwrap();
+        let trk = gpx.find("<trk").unwrap();
+        assert!(wpt < rte && rte < trk);
+    }
+
+    #[test]
+    fn writes_routes_with_hop_names() {
+        let route = vec![[40.76, -73.98], [40.77, -73.97], [40.78, -73.96]]
+            .to_feature(Some(Type::CirclePokemon));
+        let gpx = route.to_gpx();
+        assert!(gpx.contains("<rtept lat=\"40.77\" lon=\"-73.97\"><name>2</name></rtept>"));
+
+        let collection = parse(&gpx).unwrap();
+        assert_eq!(
+            collection.to_single_vec(),
+            vec![[40.76, -73.98], [40.77, -73.97], [40.78, -73.96]]
+        );
+    }
+}
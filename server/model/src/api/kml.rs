@@ -0,0 +1,424 @@
+//! KML and KMZ, e.g. the fences exported from Google My Maps
+//!
+//! Placemarks are read into features, `<name>` and `<description>` become the `name` and
+//! `description` properties, and the ExtendedData fields are added to the properties as strings.
+//! KMZ files are exchanged as base64 strings since they're sent in JSON bodies.
+use std::io::{Cursor, Read, Write};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use roxmltree::{Document, Node};
+use serde_json::Map;
+
+use super::*;
+
+/// The local file header that every zip, and so every KMZ, starts with, encoded as base64
+pub(super) const KMZ_PREFIX: &str = "UEsDB";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Kml,
+    Kmz,
+}
+
+/// Whether the text looks like a KML document or a base64 encoded KMZ file
+pub fn detect(text: &str) -> Option<Encoding> {
+    let text = text.trim_start();
+    if text.starts_with('<') && text.contains("<kml") {
+        Some(Encoding::Kml)
+    } else if text.starts_with(KMZ_PREFIX) {
+        Some(Encoding::Kmz)
+    } else {
+        None
+    }
+}
+
+/// Parses the text if it's KML or KMZ, `None` when it's neither
+pub fn from_text(text: &str) -> Option<Result<FeatureCollection, String>> {
+    match detect(text)? {
+        Encoding::Kml => Some(parse(text)),
+        Encoding::Kmz => Some(
+            STANDARD
+                .decode(text.trim())
+                .map_err(|err| format!("invalid base64: {}", err))
+                .and_then(|bytes| parse_kmz(&bytes)),
+        ),
+    }
+}
+
+pub fn parse(kml: &str) -> Result<FeatureCollection, String> {
+    let document = Document::parse(kml).map_err(|err| err.to_string())?;
+    let features = document
+        .descendants()
+        .filter(|node| is(node, "Placemark"))
+        .filter_map(placemark)
+        .collect();
+    Ok(FeatureCollection {
+        bbox: None,
+        features,
+        foreign_members: None,
+    })
+}
+
+/// Parses the first `.kml` file of the archive, which is `doc.kml` in KMZ files from Google
+pub fn parse_kmz(bytes: &[u8]) -> Result<FeatureCollection, String> {
+    let mut archive = zip::ZipArchive::new(Cursor::new(bytes)).map_err(|err| err.to_string())?;
+    let name = archive
+        .file_names()
+        .find(|name| name.to_lowercase().ends_with(".kml"))
+        .map(|name| name.to_string())
+        .ok_or_else(|| "no .kml file in the archive".to_string())?;
+    let mut kml = String::new();
+    archive
+        .by_name(&name)
+        .map_err(|err| err.to_string())?
+        .read_to_string(&mut kml)
+        .map_err(|err| err.to_string())?;
+    parse(&kml)
+}
+
+pub(super) fn is(node: &Node, name: &str) -> bool {
+    node.is_element() && node.tag_name().name() == name
+}
+
+fn child<'a, 'input>(node: &Node<'a, 'input>, name: &str) -> Option<Node<'a, 'input>> {
+    node.children().find(|child| is(child, name))
+}
+
+fn text(node: &Node, name: &str) -> Option<String> {
+    child(node, name)
+        .and_then(|child| child.text())
+        .map(|text| text.trim().to_string())
+}
+
+/// `lon,lat[,alt]` tuples separated by whitespace
+fn coordinates(node: &Node) -> Vec<Vec<f64>> {
+    text(node, "coordinates")
+        .unwrap_or_default()
+        .split_whitespace()
+        .filter_map(|tuple| {
+            let mut coords = tuple.split(',').map(|coord| coord.parse::<f64>());
+            match (coords.next(), coords.next()) {
+                (Some(Ok(lon)), Some(Ok(lat))) => Some(vec![lon, lat]),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+fn ring(node: Option<Node>) -> Option<Vec<Vec<f64>>> {
+    let ring = child(&node?, "LinearRing")?;
+    let points = coordinates(&ring);
+    if points.is_empty() {
+        None
+    } else {
+        Some(points)
+    }
+}
+
+fn geometry(node: &Node) -> Option<Value> {
+    match node.tag_name().name() {
+        "Point" => coordinates(node).into_iter().next().map(Value::Point),
+        "LineString" => Some(Value::LineString(coordinates(node))),
+        "Polygon" => {
+            let mut rings = vec![ring(child(node, "outerBoundaryIs"))?];
+            rings.extend(
+                node.children()
+                    .filter(|child| is(child, "innerBoundaryIs"))
+                    .filter_map(|child| ring(Some(child))),
+            );
+            Some(Value::Polygon(rings))
+        }
+        "MultiGeometry" => {
+            let values: Vec<Value> = node
+                .children()
+                .filter(|child| child.is_element())
+                .filter_map(|child| geometry(&child))
+                .collect();
+            if values.is_empty() {
+                None
+            } else if values
+                .iter()
+                .all(|value| matches!(value, Value::Polygon(_)))
+            {
+                Some(Value::MultiPolygon(
+                    values
+                        .into_iter()
+                        .filter_map(|value| match value {
+                            Value::Polygon(polygon) => Some(polygon),
+                            _ => None,
+                        })
+                        .collect(),
+                ))
+            } else if values.iter().all(|value| matches!(value, Value::Point(_))) {
+                Some(Value::MultiPoint(
+                    values
+                        .into_iter()
+                        .filter_map(|value| match value {
+                            Value::Point(point) => Some(point),
+                            _ => None,
+                        })
+                        .collect(),
+                ))
+            } else {
+                Some(Value::GeometryCollection(
+                    values.into_iter().map(Geometry::new).collect(),
+                ))
+            }
+        }
+        _ => None,
+    }
+}
+
+fn placemark(node: Node) -> Option<Feature> {
+    let value = node
+        .children()
+        .filter(|child| child.is_element())
+        .find_map(|child| geometry(&child))?;
+
+    let mut properties = Map::new();
+    if let Some(name) = text(&node, "name") {
+        properties.insert("name".to_string(), name.into());
+    }
+    if let Some(description) = text(&node, "description") {
+        properties.insert("description".to_string(), description.into());
+    }
+    if let Some(data) = child(&node, "ExtendedData") {
+        for field in data.descendants() {
+            let value = if is(&field, "Data") {
+                text(&field, "value")
+            } else if is(&field, "SimpleData") {
+                field.text().map(|text| text.trim().to_string())
+            } else {
+                continue;
+            };
+            if let (Some(name), Some(value)) = (field.attribute("name"), value) {
+                properties.insert(name.to_string(), value.into());
+            }
+        }
+    }
+    Some(Feature {
+        bbox: None,
+        geometry: Some(Geometry::new(value)),
+        id: None,
+        properties: Some(properties),
+        foreign_members: None,
+    })
+}
+
+pub(super) fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+fn write_coordinates(points: &[Vec<f64>]) -> String {
+    let points: Vec<String> = points
+        .iter()
+        .filter(|point| point.len() >= 2)
+        .map(|point| format!("{},{}", point[0], point[1]))
+        .collect();
+    format!("<coordinates>{}</coordinates>", points.join(" "))
+}
+
+fn write_polygon(rings: &[Vec<Vec<f64>>]) -> String {
+    let mut kml = String::from("<Polygon>");
+    for (i, ring) in rings.iter().enumerate() {
+        let boundary = if i == 0 {
+            "outerBoundaryIs"
+        } else {
+            "innerBoundaryIs"
+        };
+        kml.push_str(&format!(
+            "<{boundary}><LinearRing>{}</LinearRing></{boundary}>",
+            write_coordinates(ring)
+        ));
+    }
+    kml.push_str("</Polygon>");
+    kml
+}
+
+fn write_geometry(value: &Value) -> String {
+    let multi = |parts: Vec<String>| format!("<MultiGeometry>{}</MultiGeometry>", parts.concat());
+    match value {
+        Value::Point(point) => format!("<Point>{}</Point>", write_coordinates(&[point.clone()])),
+        Value::MultiPoint(points) => multi(
+            points
+                .iter()
+                .map(|point| format!("<Point>{}</Point>", write_coordinates(&[point.clone()])))
+                .collect(),
+        ),
+        Value::LineString(line) => format!("<LineString>{}</LineString>", write_coordinates(line)),
+        Value::MultiLineString(lines) => multi(
+            lines
+                .iter()
+                .map(|line| format!("<LineString>{}</LineString>", write_coordinates(line)))
+                .collect(),
+        ),
+        Value::Polygon(rings) => write_polygon(rings),
+        Value::MultiPolygon(polygons) => multi(
+            polygons
+                .iter()
+                .map(|polygon| write_polygon(polygon))
+                .collect(),
+        ),
+        Value::GeometryCollection(geometries) => multi(
+            geometries
+                .iter()
+                .map(|geometry| write_geometry(&geometry.value))
+                .collect(),
+        ),
+    }
+}
+
+fn write_placemark(feature: &Feature) -> String {
+    let mut kml = String::from("<Placemark>");
+    let name = feature
+        .property("__name")
+        .or_else(|| feature.property("name"))
+        .and_then(|name| name.as_str());
+    if let Some(name) = name {
+        kml.push_str(&format!("<name>{}</name>", escape(name)));
+    }
+    if let Some(description) = feature
+        .property("description")
+        .and_then(|description| description.as_str())
+    {
+        kml.push_str(&format!(
+            "<description>{}</description>",
+            escape(description)
+        ));
+    }
+    let data: Vec<String> = feature
+        .properties_iter()
+        .filter(|(key, _)| {
+            !key.starts_with("__") && key.as_str() != "name" && key.as_str() != "description"
+        })
+        .map(|(key, value)| {
+            let value = match value {
+                serde_json::Value::String(value) => value.clone(),
+                value => value.to_string(),
+            };
+            format!(
+                "<Data name=\"{}\"><value>{}</value></Data>",
+                escape(key),
+                escape(&value)
+            )
+        })
+        .collect();
+    if !data.is_empty() {
+        kml.push_str(&format!("<ExtendedData>{}</ExtendedData>", data.concat()));
+    }
+    if let Some(geometry) = feature.geometry.as_ref() {
+        kml.push_str(&write_geometry(&geometry.value));
+    }
+    kml.push_str("</Placemark>");
+    kml
+}
+
+impl ToKml for FeatureCollection {
+    fn to_kml(self) -> String {
+        let placemarks: Vec<String> = self.features.iter().map(write_placemark).collect();
+        format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<kml xmlns=\"http://www.opengis.net/kml/2.2\"><Document>{}</Document></kml>",
+            placemarks.concat()
+        )
+    }
+
+    fn to_kmz(self) -> String {
+        let kml = self.to_kml();
+        let mut buffer = Cursor::new(vec![]);
+        let zipped = {
+            let mut writer = zip::ZipWriter::new(&mut buffer);
+            writer
+                .start_file("doc.kml", zip::write::FileOptions::default())
+                .and_then(|_| writer.write_all(kml.as_bytes()).map_err(Into::into))
+                .and_then(|_| writer.finish().map(|_| ()))
+        };
+        match zipped {
+            Ok(_) => STANDARD.encode(buffer.into_inner()),
+            Err(err) => {
+                log::error!("[KMZ] Unable to create archive: {}", err);
+                String::new()
+            }
+        }
+    }
+}
+
+impl ToKml for Feature {
+    fn to_kml(self) -> String {
+        self.to_collection(None, None).to_kml()
+    }
+
+    fn to_kmz(self) -> String {
+        self.to_collection(None, None).to_kmz()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MY_MAPS: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<kml xmlns="http://www.opengis.net/kml/2.2">
+  <Document>
+    <Folder>
+      <Placemark>
+        <name>Central Park</name>
+        <description>North &amp; south</description>
+        <ExtendedData><Data name="level"><value>15</value></Data></ExtendedData>
+        <Polygon><outerBoundaryIs><LinearRing><coordinates>
+          -73.98,40.76,0 -73.95,40.80,0 -73.94,40.79,0 -73.98,40.76,0
+        </coordinates></LinearRing></outerBoundaryIs></Polygon>
+      </Placemark>
+      <Placemark>
+        <name>Stop</name>
+        <Point><coordinates>-73.97,40.78,0</coordinates></Point>
+      </Placemark>
+    </Folder>
+  </Document>
+</kml>"#;
+
+    #[test]
+    fn parses_placemarks() {
+        let collection = parse(MY_MAPS).unwrap();
+        assert_eq!(collection.features.len(), 2);
+        let park = &collection.features[0];
+        assert_eq!(park.property("name").unwrap(), "Central Park");
+        assert_eq!(park.property("description").unwrap(), "North & south");
+        assert_eq!(park.property("level").unwrap(), "15");
+        match &park.geometry.as_ref().unwrap().value {
+            Value::Polygon(rings) => assert_eq!(rings[0][1], vec![-73.95, 40.80]),
+            value => panic!("expected a polygon, found {:?}", value),
+        }
+    }
+
+    #[test]
+    fn keeps_names_when_converted() {
+        // same path as the convert endpoint
+        let collection = GeoFormats::Text(MY_MAPS.to_string()).to_collection(None, None);
+        let names: Vec<_> = collection
+            .into_iter()
+            .map(|feature| feature.remove_internal_props())
+            .map(|feature| feature.property("name").cloned())
+            .collect();
+        assert_eq!(
+            names,
+            vec![Some("Central Park".into()), Some("Stop".into())]
+        );
+    }
+
+    #[test]
+    fn round_trips_through_kmz() {
+        let kmz = parse(MY_MAPS).unwrap().to_kmz();
+        assert_eq!(detect(&kmz), Some(Encoding::Kmz));
+        let collection = from_text(&kmz).unwrap().unwrap();
+        assert_eq!(collection.features.len(), 2);
+        assert_eq!(collection.features[1].property("name").unwrap(), "Stop");
+        assert_eq!(
+            collection.features[1].geometry.as_ref().unwrap().value,
+            Value::Point(vec![-73.97, 40.78])
+        );
+    }
+}
@@ -13,15 +13,19 @@ pub mod cluster_mode;
 pub mod collection;
 pub mod feature;
 pub mod geometry;
+pub mod gpx;
+pub mod kml;
 pub mod multi_struct;
 pub mod multi_vec;
 pub mod point_array;
 pub mod point_struct;
 pub mod poracle;
+pub mod shapefile;
 pub mod single_struct;
 pub mod single_vec;
 pub mod sort_by;
 pub mod text;
+pub mod wkt;
 
 pub type Precision = f64;
 
@@ -116,9 +120,32 @@ pub trait ToSql {
     fn to_sql(self) -> String;
 }
 
+pub trait ToKml {
+    fn to_kml(self) -> String;
+    /// Zipped KML, encoded as base64
+    fn to_kmz(self) -> String;
+}
+
+pub trait ToGpx {
+    fn to_gpx(self) -> String;
+}
+
+pub trait ToWkt {
+    fn to_wkt(self) -> String;
+    fn to_wkb(self) -> Vec<u8>;
+}
+
+pub trait ToShapefile {
+    /// Zipped ESRI Shapefile of the polygons, encoded as base64
+    fn to_shapefile(self) -> String;
+}
+
+#[serde_with::serde_as]
 #[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(untagged)]
 pub enum GeoFormats {
+    /// Strings that can be parsed as WKT, before any other text
+    Wkt(#[serde_as(as = "serde_with::DisplayFromStr")] ::wkt::Wkt<Precision>),
     Text(String),
     SingleArray(single_vec::SingleVec),
     MultiArray(multi_vec::MultiVec),
@@ -134,10 +161,29 @@ pub enum GeoFormats {
     Bound(args::BoundsArg),
 }
 
+/// Logs the error of an area that couldn't be parsed and falls back to an empty collection
+fn or_empty(parsed: Result<FeatureCollection, String>) -> FeatureCollection {
+    parsed.unwrap_or_else(|err| {
+        log::error!("[GEOFORMATS] Unable to parse the area: {}", err);
+        FeatureCollection {
+            bbox: None,
+            features: vec![],
+            foreign_members: None,
+        }
+    })
+}
+
 impl ToCollection for GeoFormats {
     fn to_collection(self, name: Option<String>, enum_type: Option<Type>) -> FeatureCollection {
         match self {
-            GeoFormats::Text(area) => area.to_collection(name, enum_type),
+            GeoFormats::Wkt(area) => or_empty(wkt::parse(area)).to_collection(name, enum_type),
+            GeoFormats::Text(area) => match shapefile::from_text(&area)
+                .or_else(|| kml::from_text(&area))
+                .or_else(|| gpx::from_text(&area))
+            {
+                Some(parsed) => or_empty(parsed).to_collection(name, enum_type),
+                None => area.to_collection(name, enum_type),
+            },
             GeoFormats::SingleArray(area) => area.to_collection(name, enum_type),
             GeoFormats::MultiArray(area) => area.to_collection(name, enum_type),
             GeoFormats::SingleStruct(area) => area.to_collection(name, enum_type),
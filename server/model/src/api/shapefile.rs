@@ -0,0 +1,676 @@
+//! Zipped ESRI Shapefiles of polygon layers, e.g. from QGIS or ArcGIS
+//!
+//! Archives are exchanged as base64 strings like KMZ files. The fields of the `.dbf` file become
+//! properties and the other way around when writing, where the `__` properties are left out.
+//! Features that aren't polygons are skipped when writing and coordinates are always WGS 84.
+use std::io::{Cursor, Read, Write};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::Datelike;
+use serde_json::Map;
+use zip::ZipArchive;
+
+use super::{kml::KMZ_PREFIX, *};
+
+/// Name of the files in the archives that are written
+const LAYER: &str = "koji";
+const PRJ: &str = "GEOGCS[\"GCS_WGS_1984\",DATUM[\"D_WGS_1984\",SPHEROID[\"WGS_1984\",6378137.0,298.257223563]],PRIMEM[\"Greenwich\",0.0],UNIT[\"Degree\",0.0174532925199433]]";
+const FILE_CODE: i32 = 9994;
+const VERSION: i32 = 1000;
+const NULL_SHAPE: i32 = 0;
+const POLYGON: i32 = 5;
+const POLYGON_Z: i32 = 15;
+const POLYGON_M: i32 = 25;
+/// Longest name of a `.dbf` field
+const FIELD_NAME: usize = 10;
+/// Longest value of a `.dbf` character field
+const FIELD_LENGTH: usize = 254;
+
+type Archive = ZipArchive<Cursor<Vec<u8>>>;
+
+fn archive(text: &str) -> Option<Archive> {
+    let text = text.trim();
+    if !text.starts_with(KMZ_PREFIX) {
+        return None;
+    }
+    let bytes = STANDARD.decode(text).ok()?;
+    ZipArchive::new(Cursor::new(bytes)).ok()
+}
+
+fn find(archive: &Archive, extension: &str) -> Option<String> {
+    archive
+        .file_names()
+        .find(|name| name.to_lowercase().ends_with(extension))
+        .map(|name| name.to_string())
+}
+
+/// Whether the text is a base64 encoded zip with a `.shp` file in it
+pub fn detect(text: &str) -> bool {
+    archive(text).map_or(false, |archive| find(&archive, ".shp").is_some())
+}
+
+/// Parses the text if it's a zipped Shapefile, `None` when it isn't
+pub fn from_text(text: &str) -> Option<Result<FeatureCollection, String>> {
+    let mut archive = archive(text)?;
+    let shp = find(&archive, ".shp")?;
+    Some(parse(&mut archive, &shp))
+}
+
+fn read_file(archive: &mut Archive, name: &str) -> Result<Vec<u8>, String> {
+    let mut bytes = vec![];
+    archive
+        .by_name(name)
+        .map_err(|err| err.to_string())?
+        .read_to_end(&mut bytes)
+        .map_err(|err| err.to_string())?;
+    Ok(bytes)
+}
+
+/// Reads the polygons of the `.shp` file and the fields of the `.dbf` file next to it, if any
+fn parse(archive: &mut Archive, shp: &str) -> Result<FeatureCollection, String> {
+    let shapes = read_shp(&read_file(archive, shp)?)?;
+    let stem = shp[..shp.len() - 4].to_lowercase();
+    let dbf = archive
+        .file_names()
+        .find(|name| name.to_lowercase() == format!("{}.dbf", stem))
+        .map(|name| name.to_string());
+    let mut records = match dbf {
+        Some(dbf) => read_dbf(&read_file(archive, &dbf)?)?,
+        None => vec![],
+    }
+    .into_iter();
+
+    let features = shapes
+        .into_iter()
+        .filter_map(|shape| {
+            let properties = records.next().flatten().unwrap_or_default();
+            Some(Feature {
+                bbox: None,
+                geometry: Some(Geometry::new(shape?)),
+                id: None,
+                properties: Some(properties),
+                foreign_members: None,
+            })
+        })
+        .collect();
+    Ok(FeatureCollection {
+        bbox: None,
+        features,
+        foreign_members: None,
+    })
+}
+
+fn slice(bytes: &[u8], offset: usize, len: usize) -> Result<&[u8], String> {
+    offset
+        .checked_add(len)
+        .and_then(|end| bytes.get(offset..end))
+        .ok_or_else(|| "unexpected end of file".to_string())
+}
+
+/// Offsets and lengths computed from the values in the files, which can't be trusted
+fn checked(value: Option<usize>) -> Result<usize, String> {
+    value.ok_or_else(|| "offset out of range".to_string())
+}
+
+fn unsigned(value: i32) -> Result<usize, String> {
+    u32::try_from(value)
+        .map(|value| value as usize)
+        .map_err(|_| format!("unexpected negative value {}", value))
+}
+
+fn be_i32(bytes: &[u8], offset: usize) -> Result<i32, String> {
+    Ok(i32::from_be_bytes(
+        slice(bytes, offset, 4)?.try_into().unwrap(),
+    ))
+}
+
+fn le_i32(bytes: &[u8], offset: usize) -> Result<i32, String> {
+    Ok(i32::from_le_bytes(
+        slice(bytes, offset, 4)?.try_into().unwrap(),
+    ))
+}
+
+fn le_f64(bytes: &[u8], offset: usize) -> Result<f64, String> {
+    Ok(f64::from_le_bytes(
+        slice(bytes, offset, 8)?.try_into().unwrap(),
+    ))
+}
+
+/// Twice the signed area of the ring, negative when it's clockwise
+fn signed_area(ring: &[Vec<f64>]) -> f64 {
+    ring.windows(2)
+        .map(|pair| pair[0][0] * pair[1][1] - pair[1][0] * pair[0][1])
+        .sum()
+}
+
+/// One geometry per record, `None` for null shapes
+fn read_shp(bytes: &[u8]) -> Result<Vec<Option<Value>>, String> {
+    if be_i32(bytes, 0)? != FILE_CODE {
+        return Err("not a .shp file".to_string());
+    }
+    let mut shapes = vec![];
+    let mut offset: usize = 100;
+    while checked(offset.checked_add(8))? <= bytes.len() {
+        let length = checked(unsigned(be_i32(bytes, offset + 4)?)?.checked_mul(2))?;
+        let content = slice(bytes, offset + 8, length)?;
+        offset = checked((offset + 8).checked_add(length))?;
+
+        let shape_type = le_i32(content, 0)?;
+        if shape_type == NULL_SHAPE {
+            shapes.push(None);
+            continue;
+        }
+        if ![POLYGON, POLYGON_Z, POLYGON_M].contains(&shape_type) {
+            return Err(format!(
+                "only polygon layers are supported, found shape type {}",
+                shape_type
+            ));
+        }
+        // the Z and M values come after the points and are ignored
+        let num_parts = unsigned(le_i32(content, 36)?)?;
+        let num_points = unsigned(le_i32(content, 40)?)?;
+        let points_start = checked(num_parts.checked_mul(4).and_then(|len| len.checked_add(44)))?;
+        let mut starts = (0..num_parts)
+            .map(|part| unsigned(le_i32(content, 44 + part * 4)?))
+            .collect::<Result<Vec<_>, _>>()?;
+        starts.push(num_points);
+
+        let mut polygons: Vec<Vec<Vec<Vec<f64>>>> = vec![];
+        for part in starts.windows(2) {
+            let mut ring = (part[0]..part[1].min(num_points))
+                .map(|point| {
+                    let offset = checked(
+                        point
+                            .checked_mul(16)
+                            .and_then(|offset| offset.checked_add(points_start)),
+                    )?;
+                    Ok(vec![le_f64(content, offset)?, le_f64(content, offset + 8)?])
+                })
+                .collect::<Result<Vec<_>, String>>()?;
+            if ring.is_empty() {
+                continue;
+            }
+            // outer rings are clockwise in Shapefiles and counterclockwise in GeoJSON
+            let outer = signed_area(&ring) <= 0.;
+            ring.reverse();
+            match polygons.last_mut() {
+                Some(polygon) if !outer => polygon.push(ring),
+                _ => polygons.push(vec![ring]),
+            }
+        }
+        shapes.push(match polygons.len() {
+            0 => None,
+            1 => polygons.pop().map(Value::Polygon),
+            _ => Some(Value::MultiPolygon(polygons)),
+        });
+    }
+    Ok(shapes)
+}
+
+/// The fields of each record, `None` for deleted records
+fn read_dbf(bytes: &[u8]) -> Result<Vec<Option<Map<String, serde_json::Value>>>, String> {
+    let num_records = unsigned(le_i32(bytes, 4)?)?;
+    let header_length = u16::from_le_bytes(slice(bytes, 8, 2)?.try_into().unwrap()) as usize;
+    let record_length = u16::from_le_bytes(slice(bytes, 10, 2)?.try_into().unwrap()) as usize;
+
+    let mut fields = vec![];
+    let mut offset = 32;
+    while offset < header_length.saturating_sub(1) && bytes.get(offset) != Some(&0x0D) {
+        let descriptor = slice(bytes, offset, 32)?;
+        let name = String::from_utf8_lossy(&descriptor[..11])
+            .trim_end_matches('\0')
+            .to_string();
+        fields.push((name, descriptor[11], descriptor[16] as usize));
+        offset += 32;
+    }
+
+    (0..num_records)
+        .map(|record| {
+            let offset = checked(
+                record
+                    .checked_mul(record_length)
+                    .and_then(|offset| offset.checked_add(header_length)),
+            )?;
+            let record = slice(bytes, offset, record_length)?;
+            if record.first() == Some(&b'*') {
+                return Ok(None);
+            }
+            let mut properties = Map::new();
+            let mut offset = 1;
+            for (name, kind, length) in fields.iter() {
+                let raw = String::from_utf8_lossy(slice(record, offset, *length)?);
+                offset += length;
+                let raw = raw.trim();
+                let value = match kind {
+                    b'N' | b'F' => match raw.parse::<i64>() {
+                        Ok(integer) => Some(integer.into()),
+                        Err(_) => raw.parse::<f64>().ok().map(Into::into),
+                    },
+                    b'L' => match raw {
+                        "T" | "t" | "Y" | "y" => Some(true.into()),
+                        "F" | "f" | "N" | "n" => Some(false.into()),
+                        _ => None,
+                    },
+                    _ if raw.is_empty() => None,
+                    _ => Some(raw.into()),
+                };
+                if let Some(value) = value {
+                    properties.insert(name.clone(), value);
+                }
+            }
+            Ok(Some(properties))
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FieldKind {
+    Logical,
+    Integer,
+    Float,
+    Character(usize),
+}
+
+impl FieldKind {
+    fn of(value: &serde_json::Value) -> Option<Self> {
+        match value {
+            serde_json::Value::Null => None,
+            serde_json::Value::Bool(_) => Some(Self::Logical),
+            serde_json::Value::Number(number) if number.is_i64() => Some(Self::Integer),
+            serde_json::Value::Number(_) => Some(Self::Float),
+            value => Some(Self::Character(text_value(value).len())),
+        }
+    }
+
+    /// The kind that fits the values of both
+    fn merge(self, other: Self) -> Self {
+        match (self, other) {
+            (a, b) if a == b => a,
+            (Self::Integer, Self::Float) | (Self::Float, Self::Integer) => Self::Float,
+            (a, b) => Self::Character(a.length().max(b.length())),
+        }
+    }
+
+    fn code(&self) -> u8 {
+        match self {
+            Self::Logical => b'L',
+            Self::Integer | Self::Float => b'N',
+            Self::Character(_) => b'C',
+        }
+    }
+
+    fn length(&self) -> usize {
+        match self {
+            Self::Logical => 1,
+            Self::Integer => 20,
+            Self::Float => 24,
+            Self::Character(length) => (*length).clamp(1, FIELD_LENGTH),
+        }
+    }
+
+    fn decimals(&self) -> u8 {
+        match self {
+            Self::Float => 10,
+            _ => 0,
+        }
+    }
+
+    fn write(&self, value: Option<&serde_json::Value>) -> Vec<u8> {
+        let length = self.length();
+        let text = match (self, value) {
+            (_, None | Some(serde_json::Value::Null)) => String::new(),
+            (Self::Logical, Some(value)) => if value.as_bool().unwrap_or(false) {
+                "T"
+            } else {
+                "F"
+            }
+            .to_string(),
+            (Self::Integer, Some(value)) => {
+                format!("{:>length$}", value.as_i64().unwrap_or_default())
+            }
+            (Self::Float, Some(value)) => format!(
+                "{:>length$.decimals$}",
+                value.as_f64().unwrap_or_default(),
+                decimals = self.decimals() as usize
+            ),
+            (Self::Character(_), Some(value)) => text_value(value),
+        };
+        let mut bytes = text.into_bytes();
+        if bytes.len() > length {
+            // don't leave half of a character at the end
+            let text = String::from_utf8_lossy(&bytes[..length]).replace('\u{FFFD}', "");
+            bytes = text.into_bytes();
+        }
+        bytes.resize(length, b' ');
+        bytes
+    }
+}
+
+fn text_value(value: &serde_json::Value) -> String {
+    match value {
+        serde_json::Value::String(value) => value.clone(),
+        value => value.to_string(),
+    }
+}
+
+/// The properties that are written to the `.dbf` file
+fn attributes(feature: &Feature) -> Map<String, serde_json::Value> {
+    feature
+        .properties_iter()
+        .filter(|(key, _)| !key.starts_with("__"))
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect()
+}
+
+/// Field names are at most 10 ASCII characters, later properties that end up with the same name
+/// as an earlier one are left out
+fn field_name(key: &str) -> String {
+    key.chars()
+        .map(|char| {
+            if char.is_ascii_alphanumeric() {
+                char
+            } else {
+                '_'
+            }
+        })
+        .take(FIELD_NAME)
+        .collect()
+}
+
+/// Polygons with the outer rings clockwise and the holes counterclockwise
+fn shapefile_rings(value: &Value) -> Option<Vec<Vec<Vec<f64>>>> {
+    let polygons = match value {
+        Value::Polygon(polygon) => vec![polygon.clone()],
+        Value::MultiPolygon(polygons) => polygons.clone(),
+        _ => return None,
+    };
+    Some(
+        polygons
+            .into_iter()
+            .flat_map(|polygon| {
+                polygon.into_iter().enumerate().map(|(i, mut ring)| {
+                    ring.retain(|point| point.len() >= 2);
+                    let clockwise = signed_area(&ring) < 0.;
+                    if (i == 0) != clockwise {
+                        ring.reverse();
+                    }
+                    ring
+                })
+            })
+            .filter(|ring| !ring.is_empty())
+            .collect(),
+    )
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Bounds([f64; 4]);
+
+impl Bounds {
+    fn new(rings: &[Vec<Vec<f64>>]) -> Self {
+        let mut bounds = [f64::MAX, f64::MAX, f64::MIN, f64::MIN];
+        for point in rings.iter().flatten() {
+            bounds[0] = bounds[0].min(point[0]);
+            bounds[1] = bounds[1].min(point[1]);
+            bounds[2] = bounds[2].max(point[0]);
+            bounds[3] = bounds[3].max(point[1]);
+        }
+        Self(bounds)
+    }
+
+    fn extend(&mut self, other: &Self) {
+        self.0[0] = self.0[0].min(other.0[0]);
+        self.0[1] = self.0[1].min(other.0[1]);
+        self.0[2] = self.0[2].max(other.0[2]);
+        self.0[3] = self.0[3].max(other.0[3]);
+    }
+
+    fn write(&self, bytes: &mut Vec<u8>) {
+        self.0
+            .iter()
+            .for_each(|bound| bytes.extend(bound.to_le_bytes()));
+    }
+}
+
+/// The 100 byte header of the `.shp` and `.shx` files
+fn write_header(bytes: &mut Vec<u8>, file_length: usize, bounds: &Bounds) {
+    bytes.extend(FILE_CODE.to_be_bytes());
+    bytes.extend([0; 20]);
+    bytes.extend(((file_length / 2) as i32).to_be_bytes());
+    bytes.extend(VERSION.to_le_bytes());
+    bytes.extend(POLYGON.to_le_bytes());
+    bounds.write(bytes);
+    // Z and M ranges
+    bytes.extend([0; 32]);
+}
+
+/// The `.shp` and `.shx` files
+fn write_shp(shapes: &[Vec<Vec<Vec<f64>>>]) -> (Vec<u8>, Vec<u8>) {
+    let mut records = vec![];
+    let mut index = vec![];
+    let mut bounds: Option<Bounds> = None;
+    for (i, rings) in shapes.iter().enumerate() {
+        let shape_bounds = Bounds::new(rings);
+        bounds.get_or_insert(shape_bounds).extend(&shape_bounds);
+        let num_points: usize = rings.iter().map(|ring| ring.len()).sum();
+        let mut content = vec![];
+        content.extend(POLYGON.to_le_bytes());
+        shape_bounds.write(&mut content);
+        content.extend((rings.len() as i32).to_le_bytes());
+        content.extend((num_points as i32).to_le_bytes());
+        let mut start = 0;
+        for ring in rings {
+            content.extend((start as i32).to_le_bytes());
+            start += ring.len();
+        }
+        for point in rings.iter().flatten() {
+            content.extend(point[0].to_le_bytes());
+            content.extend(point[1].to_le_bytes());
+        }
+
+        index.extend((((100 + records.len()) / 2) as i32).to_be_bytes());
+        index.extend(((content.len() / 2) as i32).to_be_bytes());
+        records.extend((i as i32 + 1).to_be_bytes());
+        records.extend(((content.len() / 2) as i32).to_be_bytes());
+        records.extend(content);
+    }
+    let bounds = bounds.unwrap_or_default();
+
+    let mut shp = Vec::with_capacity(100 + records.len());
+    write_header(&mut shp, 100 + records.len(), &bounds);
+    shp.extend(records);
+    let mut shx = Vec::with_capacity(100 + index.len());
+    write_header(&mut shx, 100 + index.len(), &bounds);
+    shx.extend(index);
+    (shp, shx)
+}
+
+fn write_dbf(records: &[Map<String, serde_json::Value>]) -> Vec<u8> {
+    let mut fields: Vec<(String, String, FieldKind)> = vec![];
+    for record in records {
+        for (key, value) in record {
+            let Some(kind) = FieldKind::of(value) else {
+                continue;
+            };
+            match fields.iter_mut().find(|(_, field, _)| field == key) {
+                Some((_, _, field_kind)) => *field_kind = field_kind.merge(kind),
+                None => {
+                    let name = field_name(key);
+                    if fields.iter().all(|(other, _, _)| *other != name) {
+                        fields.push((name, key.clone(), kind));
+                    }
+                }
+            }
+        }
+    }
+
+    let header_length = 32 + fields.len() * 32 + 1;
+    let record_length = 1 + fields
+        .iter()
+        .map(|(_, _, kind)| kind.length())
+        .sum::<usize>();
+    let today = chrono::Utc::now().date_naive();
+    let mut dbf = vec![
+        0x03,
+        (today.year() - 1900) as u8,
+        today.month() as u8,
+        today.day() as u8,
+    ];
+    dbf.extend((records.len() as u32).to_le_bytes());
+    dbf.extend((header_length as u16).to_le_bytes());
+    dbf.extend((record_length as u16).to_le_bytes());
+    dbf.extend([0; 20]);
+    for (name, _, kind) in fields.iter() {
+        let mut descriptor = [0; 32];
+        descriptor[..name.len()].copy_from_slice(name.as_bytes());
+        descriptor[11] = kind.code();
+        descriptor[16] = kind.length() as u8;
+        descriptor[17] = kind.decimals();
+        dbf.extend(descriptor);
+    }
+    dbf.push(0x0D);
+    for record in records {
+        dbf.push(b' ');
+        for (_, key, kind) in fields.iter() {
+            dbf.extend(kind.write(record.get(key)));
+        }
+    }
+    dbf.push(0x1A);
+    dbf
+}
+
+impl ToShapefile for FeatureCollection {
+    fn to_shapefile(self) -> String {
+        let mut shapes = vec![];
+        let mut records = vec![];
+        for feature in self.features.iter() {
+            if let Some(rings) = feature
+                .geometry
+                .as_ref()
+                .and_then(|geometry| shapefile_rings(&geometry.value))
+            {
+                shapes.push(rings);
+                records.push(attributes(feature));
+            }
+        }
+        if shapes.len() < self.features.len() {
+            log::warn!(
+                "[SHAPEFILE] Skipped {} features that aren't polygons",
+                self.features.len() - shapes.len()
+            );
+        }
+        let (shp, shx) = write_shp(&shapes);
+        let dbf = write_dbf(&records);
+
+        let mut buffer = Cursor::new(vec![]);
+        let zipped = {
+            let mut writer = zip::ZipWriter::new(&mut buffer);
+            [
+                ("shp", shp.as_slice()),
+                ("shx", shx.as_slice()),
+                ("dbf", dbf.as_slice()),
+                ("prj", PRJ.as_bytes()),
+                ("cpg", "UTF-8".as_bytes()),
+            ]
+            .into_iter()
+            .try_for_each(|(extension, bytes)| {
+                writer.start_file(
+                    format!("{}.{}", LAYER, extension),
+                    zip::write::FileOptions::default(),
+                )?;
+                writer.write_all(bytes).map_err(zip::result::ZipError::from)
+            })
+            .and_then(|_| writer.finish().map(|_| ()))
+        };
+        match zipped {
+            Ok(_) => STANDARD.encode(buffer.into_inner()),
+            Err(err) => {
+                log::error!("[SHAPEFILE] Unable to create archive: {}", err);
+                String::new()
+            }
+        }
+    }
+}
+
+impl ToShapefile for Feature {
+    fn to_shapefile(self) -> String {
+        self.to_collection(None, None).to_shapefile()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fence() -> Feature {
+        let mut properties = Map::new();
+        properties.insert("name".to_string(), "Central Park".into());
+        properties.insert("__id".to_string(), 4.into());
+        properties.insert("level".to_string(), 15.into());
+        properties.insert("active".to_string(), true.into());
+        Feature {
+            bbox: None,
+            geometry: Some(Geometry::new(Value::Polygon(vec![
+                vec![
+                    vec![-73.98, 40.76],
+                    vec![-73.94, 40.79],
+                    vec![-73.95, 40.80],
+                    vec![-73.98, 40.76],
+                ],
+                vec![
+                    vec![-73.96, 40.78],
+                    vec![-73.955, 40.785],
+                    vec![-73.95, 40.78],
+                    vec![-73.96, 40.78],
+                ],
+            ]))),
+            id: None,
+            properties: Some(properties),
+            foreign_members: None,
+        }
+    }
+
+    #[test]
+    fn round_trips_polygons_and_fields() {
+        let shapefile = fence().to_shapefile();
+        assert!(detect(&shapefile));
+        let collection = from_text(&shapefile).unwrap().unwrap();
+        assert_eq!(collection.features.len(), 1);
+
+        let feature = &collection.features[0];
+        assert_eq!(feature.property("name").unwrap(), "Central Park");
+        assert_eq!(feature.property("level").unwrap(), 15);
+        assert_eq!(feature.property("active").unwrap(), true);
+        assert!(feature.property("__id").is_none());
+        assert_eq!(
+            feature.geometry.as_ref().unwrap().value,
+            fence().geometry.unwrap().value
+        );
+    }
+
+    #[test]
+    fn rejects_negative_lengths() {
+        let (mut shp, _) = write_shp(&[shapefile_rings(&fence().geometry.unwrap().value).unwrap()]);
+        // content length of the first record
+        shp[104..108].copy_from_slice(&(-1i32).to_be_bytes());
+        assert!(read_shp(&shp).is_err());
+
+        let mut dbf = write_dbf(&[attributes(&fence())]);
+        dbf[4..8].copy_from_slice(&(-1i32).to_le_bytes());
+        assert!(read_dbf(&dbf).is_err());
+    }
+
+    #[test]
+    fn skips_other_geometries() {
+        let point = Feature {
+            geometry: Some(Geometry::new(Value::Point(vec![-73.97, 40.78]))),
+            ..Feature::default()
+        };
+        let collection = FeatureCollection {
+            bbox: None,
+            features: vec![fence(), point],
+            foreign_members: None,
+        };
+        let parsed = from_text(&collection.to_shapefile()).unwrap().unwrap();
+        assert_eq!(parsed.features.len(), 1);
+    }
+}
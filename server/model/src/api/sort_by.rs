@@ -1,6 +1,6 @@
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub enum SortBy {
     Unset,
     GeoHash,
@@ -8,6 +8,8 @@ pub enum SortBy {
     Random,
     S2Cell,
     LatLon,
+    Tsp,
+    TimeAware,
     Custom(String),
 }
 
@@ -19,6 +21,8 @@ impl PartialEq for SortBy {
             (SortBy::PointCount, SortBy::PointCount) => true,
             (SortBy::Random, SortBy::Random) => true,
             (SortBy::S2Cell, SortBy::S2Cell) => true,
+            (SortBy::Tsp, SortBy::Tsp) => true,
+            (SortBy::TimeAware, SortBy::TimeAware) => true,
             _ => false,
         }
     }
@@ -42,8 +46,10 @@ impl<'de> Deserialize<'de> for SortBy {
             "s2" | "s2cell" => Ok(SortBy::S2Cell),
             "latlon" => Ok(SortBy::LatLon),
             "" | "none" | "unset" => Ok(SortBy::Unset),
-            // This is for backwards compatibility since the custom below would end up with a value of "TSP"
-            "tsp" => Ok(SortBy::Custom("tsp".to_string())),
+            "tsp" => Ok(SortBy::Tsp),
+            "time_aware" | "timeaware" | "tth" => Ok(SortBy::TimeAware),
+            // The OR-Tools binary is still installed as the `tsp` plugin
+            "ortools" | "or-tools" | "or_tools" => Ok(SortBy::Custom("tsp".to_string())),
             _ => Ok(SortBy::Custom(s)),
         }
     }
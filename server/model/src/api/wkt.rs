@@ -0,0 +1,184 @@
+//! Well-known text and binary, e.g. for PostGIS or QGIS
+//!
+//! A collection with a single feature is written as the geometry of that feature, otherwise the
+//! geometries of all features are written as a `GEOMETRYCOLLECTION`. WKB is little endian.
+use ::wkt::{ToWkt as _, Wkt};
+use serde_json::Map;
+
+use super::*;
+
+/// Reads the geometry into features, a `GEOMETRYCOLLECTION` gets one feature per geometry
+pub fn parse(wkt: Wkt<Precision>) -> Result<FeatureCollection, String> {
+    let geometry =
+        geo_types::Geometry::<Precision>::try_from(wkt).map_err(|err| err.to_string())?;
+    let geometries = match geometry {
+        geo_types::Geometry::GeometryCollection(collection) => collection.0,
+        geometry => vec![geometry],
+    };
+    Ok(FeatureCollection {
+        bbox: None,
+        features: geometries
+            .iter()
+            .map(|geometry| Feature {
+                bbox: None,
+                geometry: Some(Geometry::new(Value::from(geometry))),
+                id: None,
+                properties: Some(Map::new()),
+                foreign_members: None,
+            })
+            .collect(),
+        foreign_members: None,
+    })
+}
+
+fn collect(collection: FeatureCollection) -> Geometry {
+    let geometries: Vec<Geometry> = collection
+        .into_iter()
+        .filter_map(|feature| feature.geometry)
+        .collect();
+    if geometries.len() == 1 {
+        geometries.into_iter().next().unwrap()
+    } else {
+        Geometry::new(Value::GeometryCollection(geometries))
+    }
+}
+
+struct WkbWriter(Vec<u8>);
+
+impl WkbWriter {
+    fn header(&mut self, kind: u32) {
+        self.0.push(1);
+        self.0.extend(kind.to_le_bytes());
+    }
+
+    fn count(&mut self, count: usize) {
+        self.0.extend((count as u32).to_le_bytes());
+    }
+
+    fn coords(&mut self, point: &[f64]) {
+        for coord in point.iter().take(2) {
+            self.0.extend(coord.to_le_bytes());
+        }
+    }
+
+    fn points(&mut self, points: &[Vec<f64>]) {
+        self.count(points.len());
+        points.iter().for_each(|point| self.coords(point));
+    }
+
+    fn rings(&mut self, rings: &[Vec<Vec<f64>>]) {
+        self.count(rings.len());
+        rings.iter().for_each(|ring| self.points(ring));
+    }
+
+    fn geometry(&mut self, value: &Value) {
+        match value {
+            Value::Point(point) => {
+                self.header(1);
+                self.coords(point);
+            }
+            Value::LineString(line) => {
+                self.header(2);
+                self.points(line);
+            }
+            Value::Polygon(rings) => {
+                self.header(3);
+                self.rings(rings);
+            }
+            Value::MultiPoint(points) => {
+                self.header(4);
+                self.count(points.len());
+                points.iter().for_each(|point| {
+                    self.header(1);
+                    self.coords(point);
+                });
+            }
+            Value::MultiLineString(lines) => {
+                self.header(5);
+                self.count(lines.len());
+                lines.iter().for_each(|line| {
+                    self.header(2);
+                    self.points(line);
+                });
+            }
+            Value::MultiPolygon(polygons) => {
+                self.header(6);
+                self.count(polygons.len());
+                polygons.iter().for_each(|rings| {
+                    self.header(3);
+                    self.rings(rings);
+                });
+            }
+            Value::GeometryCollection(geometries) => {
+                self.header(7);
+                self.count(geometries.len());
+                geometries
+                    .iter()
+                    .for_each(|geometry| self.geometry(&geometry.value));
+            }
+        }
+    }
+}
+
+impl ToWkt for Geometry {
+    fn to_wkt(self) -> String {
+        match geo_types::Geometry::<Precision>::try_from(self) {
+            Ok(geometry) => geometry.wkt_string(),
+            Err(err) => {
+                log::error!("[WKT] Unable to convert the geometry: {}", err);
+                String::new()
+            }
+        }
+    }
+
+    fn to_wkb(self) -> Vec<u8> {
+        let mut writer = WkbWriter(vec![]);
+        writer.geometry(&self.value);
+        writer.0
+    }
+}
+
+impl ToWkt for Feature {
+    fn to_wkt(self) -> String {
+        self.to_geometry().to_wkt()
+    }
+
+    fn to_wkb(self) -> Vec<u8> {
+        self.to_geometry().to_wkb()
+    }
+}
+
+impl ToWkt for FeatureCollection {
+    fn to_wkt(self) -> String {
+        collect(self).to_wkt()
+    }
+
+    fn to_wkb(self) -> Vec<u8> {
+        collect(self).to_wkb()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    const FENCE: &str = "POLYGON((-73.98 40.76,-73.95 40.8,-73.94 40.79,-73.98 40.76))";
+
+    #[test]
+    fn round_trips_wkt() {
+        let collection = parse(Wkt::from_str(FENCE).unwrap()).unwrap();
+        assert_eq!(collection.features.len(), 1);
+        assert_eq!(collection.to_wkt(), FENCE);
+    }
+
+    #[test]
+    fn writes_wkb() {
+        let point = Geometry::new(Value::Point(vec![1., 2.]));
+        let mut expected = vec![1, 1, 0, 0, 0];
+        expected.extend(1f64.to_le_bytes());
+        expected.extend(2f64.to_le_bytes());
+        assert_eq!(point.to_wkb(), expected);
+    }
+}
@@ -944,6 +944,15 @@ impl Query {
     pub async fn by_parent(
         db: &DatabaseConnection,
         parent: &UnknownId,
+    ) -> Result<FeatureCollection, ModelError> {
+        Self::by_parent_with_args(db, parent, &ApiQueryArgs::default()).await
+    }
+
+    /// The children of the parent, with the properties that the args ask for
+    pub async fn by_parent_with_args(
+        db: &DatabaseConnection,
+        parent: &UnknownId,
+        args: &ApiQueryArgs,
     ) -> Result<FeatureCollection, ModelError> {
         let parent_id = match parent {
             UnknownId::Number(id) => id.clone(),
@@ -960,13 +969,12 @@ impl Query {
             .filter(Column::Parent.eq(parent_id))
             .all(db)
             .await?;
-        let args = ApiQueryArgs::default();
 
         let items: Vec<Feature> = items
             .into_iter()
             .filter_map(|result| {
                 result
-                    .to_feature(&HashMap::new(), &HashMap::new(), &args)
+                    .to_feature(&HashMap::new(), &HashMap::new(), args)
                     .ok()
             })
             .collect();
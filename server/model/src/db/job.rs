@@ -0,0 +1,211 @@
+use super::*;
+
+use sea_orm::entity::prelude::*;
+
+use crate::{
+    db::sea_orm_active_enums::{JobPhase, JobStatus},
+    error::ModelError,
+};
+
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Eq, Serialize, Deserialize)]
+#[sea_orm(table_name = "job")]
+pub struct Model {
+    #[sea_orm(primary_key)]
+    pub id: u32,
+    /// Calculation the job runs, `bootstrap` or `{mode}/{category}`
+    pub endpoint: String,
+    pub status: JobStatus,
+    pub phase: Option<JobPhase>,
+    pub progress: u8,
+    pub args: Json,
+    pub result: Option<Json>,
+    /// Clusters with their detailed stats, only saved when `detailed_stats` was requested
+    pub details: Option<Json>,
+    pub stats: Option<Json>,
+    pub error: Option<String>,
+    pub started_at: Option<DateTimeUtc>,
+    pub finished_at: Option<DateTimeUtc>,
+    pub created_at: DateTimeUtc,
+    pub updated_at: DateTimeUtc,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
+
+#[derive(Debug, Serialize, Deserialize, FromQueryResult)]
+pub struct JobNoResult {
+    pub id: u32,
+    pub endpoint: String,
+    pub status: JobStatus,
+    pub phase: Option<JobPhase>,
+    pub progress: u8,
+    pub stats: Option<Json>,
+    pub error: Option<String>,
+    pub started_at: Option<DateTimeUtc>,
+    pub finished_at: Option<DateTimeUtc>,
+    pub created_at: DateTimeUtc,
+    pub updated_at: DateTimeUtc,
+}
+
+pub struct Query;
+
+impl Query {
+    pub async fn create(
+        db: &DatabaseConnection,
+        endpoint: String,
+        args: Json,
+    ) -> Result<Model, ModelError> {
+        let model = ActiveModel {
+            endpoint: Set(endpoint),
+            status: Set(JobStatus::Queued),
+            progress: Set(0),
+            args: Set(args),
+            ..Default::default()
+        }
+        .insert(db)
+        .await?;
+        Ok(model)
+    }
+
+    pub async fn get_one(db: &DatabaseConnection, id: u32) -> Result<Model, ModelError> {
+        match Entity::find_by_id(id).one(db).await? {
+            Some(record) => Ok(record),
+            None => Err(ModelError::Job("Does not exist".to_string())),
+        }
+    }
+
+    /// Returns the job without its args and result
+    pub async fn get_status(db: &DatabaseConnection, id: u32) -> Result<JobNoResult, ModelError> {
+        let record = Entity::find_by_id(id)
+            .select_only()
+            .column(Column::Id)
+            .column(Column::Endpoint)
+            .column(Column::Status)
+            .column(Column::Phase)
+            .column(Column::Progress)
+            .column(Column::Stats)
+            .column(Column::Error)
+            .column(Column::StartedAt)
+            .column(Column::FinishedAt)
+            .column(Column::CreatedAt)
+            .column(Column::UpdatedAt)
+            .into_model::<JobNoResult>()
+            .one(db)
+            .await?;
+        match record {
+            Some(record) => Ok(record),
+            None => Err(ModelError::Job("Does not exist".to_string())),
+        }
+    }
+
+    /// Marks the oldest queued job as running and returns it
+    pub async fn claim_next(db: &DatabaseConnection) -> Result<Option<Model>, DbErr> {
+        let queued = Entity::find()
+            .filter(Column::Status.eq(JobStatus::Queued))
+            .order_by(Column::Id, Order::Asc)
+            .one(db)
+            .await?;
+        if let Some(mut job) = queued {
+            let claimed = Entity::update_many()
+                .col_expr(Column::Status, Expr::value(JobStatus::Running))
+                .col_expr(Column::StartedAt, Expr::value(Utc::now()))
+                .filter(Column::Id.eq(job.id))
+                .filter(Column::Status.eq(JobStatus::Queued))
+                .exec(db)
+                .await?;
+            if claimed.rows_affected == 1 {
+                job.status = JobStatus::Running;
+                return Ok(Some(job));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Updates the phase of a running job, returns false if the job is no longer running
+    pub async fn set_phase(
+        db: &DatabaseConnection,
+        id: u32,
+        phase: JobPhase,
+    ) -> Result<bool, DbErr> {
+        let result = Entity::update_many()
+            .col_expr(Column::Progress, Expr::value(phase.progress(0.)))
+            .col_expr(Column::Phase, Expr::value(phase))
+            .filter(Column::Id.eq(id))
+            .filter(Column::Status.eq(JobStatus::Running))
+            .exec(db)
+            .await?;
+        Ok(result.rows_affected > 0)
+    }
+
+    /// Raises the progress of a running job, it never goes back down
+    pub async fn set_progress(db: &DatabaseConnection, id: u32, progress: u8) -> Result<(), DbErr> {
+        Entity::update_many()
+            .col_expr(Column::Progress, Expr::value(progress))
+            .filter(Column::Id.eq(id))
+            .filter(Column::Status.eq(JobStatus::Running))
+            .filter(Column::Progress.lt(progress))
+            .exec(db)
+            .await?;
+        Ok(())
+    }
+
+    /// Saves the result of a running job, returns false if it was cancelled in the meantime
+    pub async fn complete(
+        db: &DatabaseConnection,
+        id: u32,
+        result: Json,
+        details: Option<Json>,
+        stats: Json,
+    ) -> Result<bool, DbErr> {
+        let result = Entity::update_many()
+            .col_expr(Column::Status, Expr::value(JobStatus::Completed))
+            .col_expr(Column::Progress, Expr::value(100))
+            .col_expr(Column::Result, Expr::value(result))
+            .col_expr(Column::Details, Expr::value(details))
+            .col_expr(Column::Stats, Expr::value(stats))
+            .col_expr(Column::FinishedAt, Expr::value(Utc::now()))
+            .filter(Column::Id.eq(id))
+            .filter(Column::Status.eq(JobStatus::Running))
+            .exec(db)
+            .await?;
+        Ok(result.rows_affected > 0)
+    }
+
+    pub async fn fail(db: &DatabaseConnection, id: u32, error: String) -> Result<bool, DbErr> {
+        let result = Entity::update_many()
+            .col_expr(Column::Status, Expr::value(JobStatus::Failed))
+            .col_expr(Column::Error, Expr::value(error))
+            .col_expr(Column::FinishedAt, Expr::value(Utc::now()))
+            .filter(Column::Id.eq(id))
+            .filter(Column::Status.eq(JobStatus::Running))
+            .exec(db)
+            .await?;
+        Ok(result.rows_affected > 0)
+    }
+
+    /// Cancels a queued or running job, returns false if it had already finished
+    pub async fn cancel(db: &DatabaseConnection, id: u32) -> Result<bool, DbErr> {
+        let result = Entity::update_many()
+            .col_expr(Column::Status, Expr::value(JobStatus::Cancelled))
+            .col_expr(Column::FinishedAt, Expr::value(Utc::now()))
+            .filter(Column::Id.eq(id))
+            .filter(Column::Status.is_in([JobStatus::Queued, JobStatus::Running]))
+            .exec(db)
+            .await?;
+        Ok(result.rows_affected > 0)
+    }
+
+    /// Puts jobs that were running when Kōji stopped back in the queue
+    pub async fn requeue_interrupted(db: &DatabaseConnection) -> Result<u64, DbErr> {
+        let result = Entity::update_many()
+            .col_expr(Column::Status, Expr::value(JobStatus::Queued))
+            .col_expr(Column::Phase, Expr::value(Option::<JobPhase>::None))
+            .col_expr(Column::Progress, Expr::value(0))
+            .filter(Column::Status.eq(JobStatus::Running))
+            .exec(db)
+            .await?;
+        Ok(result.rows_affected)
+    }
+}
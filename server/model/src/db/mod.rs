@@ -17,10 +17,12 @@ pub mod geofence_project;
 pub mod geofence_property;
 pub mod gym;
 pub mod instance;
+pub mod job;
 pub mod pokestop;
 pub mod prelude;
 pub mod project;
 pub mod property;
+pub mod result_cache;
 pub mod route;
 pub mod sea_orm_active_enums;
 pub mod spawnpoint;
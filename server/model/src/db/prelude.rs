@@ -5,8 +5,10 @@ pub use super::geofence_project::Entity as GeofenceProject;
 pub use super::geofence_property::Entity as GeofenceProperty;
 pub use super::gym::Entity as Gym;
 pub use super::instance::Entity as Instance;
+pub use super::job::Entity as Job;
 pub use super::pokestop::Entity as Pokestop;
 pub use super::project::Entity as Project;
 pub use super::property::Entity as Property;
+pub use super::result_cache::Entity as ResultCache;
 pub use super::route::Entity as Route;
 pub use super::spawnpoint::Entity as Spawnpoint;
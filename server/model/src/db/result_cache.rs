@@ -0,0 +1,92 @@
+use super::*;
+
+use sea_orm::{entity::prelude::*, sea_query::OnConflict};
+
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Eq, Serialize, Deserialize)]
+#[sea_orm(table_name = "result_cache")]
+pub struct Model {
+    /// Hash of the area, data points, and args of the calculation
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub hash: String,
+    pub result: Json,
+    pub created_at: DateTimeUtc,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
+
+pub struct Query;
+
+/// Results saved before this are older than `max_age` hours
+fn cutoff(max_age: Option<u64>) -> Option<DateTimeUtc> {
+    let max_age = max_age?.checked_mul(3_600)?;
+    let max_age = chrono::Duration::from_std(std::time::Duration::from_secs(max_age)).ok()?;
+    Utc::now().checked_sub_signed(max_age)
+}
+
+impl Query {
+    /// Cached result of the hash, results older than `max_age` hours are ignored
+    pub async fn get(
+        db: &DatabaseConnection,
+        hash: &str,
+        max_age: Option<u64>,
+    ) -> Result<Option<Json>, DbErr> {
+        let mut query = Entity::find_by_id(hash.to_string());
+        if let Some(since) = cutoff(max_age) {
+            query = query.filter(Column::CreatedAt.gte(since));
+        }
+        Ok(query.one(db).await?.map(|model| model.result))
+    }
+
+    /// Deletes the results older than `max_age` hours and all but the newest `max_rows`,
+    /// returns how many were deleted
+    pub async fn prune(
+        db: &DatabaseConnection,
+        max_age: Option<u64>,
+        max_rows: Option<u64>,
+    ) -> Result<u64, DbErr> {
+        let mut deleted = 0;
+        if let Some(since) = cutoff(max_age) {
+            deleted += Entity::delete_many()
+                .filter(Column::CreatedAt.lt(since))
+                .exec(db)
+                .await?
+                .rows_affected;
+        }
+        if let Some(max_rows) = max_rows {
+            // the oldest result that's kept, everything saved before it goes
+            let oldest_kept = Entity::find()
+                .order_by_desc(Column::CreatedAt)
+                .offset(max_rows.saturating_sub(1))
+                .one(db)
+                .await?;
+            if let Some(oldest_kept) = oldest_kept {
+                deleted += Entity::delete_many()
+                    .filter(Column::CreatedAt.lt(oldest_kept.created_at))
+                    .exec(db)
+                    .await?
+                    .rows_affected;
+            }
+        }
+        Ok(deleted)
+    }
+
+    /// Saves the result, replacing any older result with the same hash
+    pub async fn save(db: &DatabaseConnection, hash: String, result: Json) -> Result<(), DbErr> {
+        Entity::insert(ActiveModel {
+            hash: Set(hash),
+            result: Set(result),
+            created_at: Set(Utc::now()),
+        })
+        .on_conflict(
+            OnConflict::column(Column::Hash)
+                .update_columns([Column::Result, Column::CreatedAt])
+                .to_owned(),
+        )
+        .exec(db)
+        .await?;
+        Ok(())
+    }
+}
@@ -151,3 +151,73 @@ impl Serialize for RouteMode {
         }
     }
 }
+
+#[derive(Debug, Clone, PartialEq, Eq, EnumIter, DeriveActiveEnum, Deserialize)]
+#[sea_orm(rs_type = "String", db_type = "Enum", enum_name = "status")]
+pub enum JobStatus {
+    #[sea_orm(string_value = "queued")]
+    Queued,
+    #[sea_orm(string_value = "running")]
+    Running,
+    #[sea_orm(string_value = "completed")]
+    Completed,
+    #[sea_orm(string_value = "failed")]
+    Failed,
+    #[sea_orm(string_value = "cancelled")]
+    Cancelled,
+}
+
+impl Serialize for JobStatus {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        match self {
+            JobStatus::Queued => serializer.serialize_str("queued"),
+            JobStatus::Running => serializer.serialize_str("running"),
+            JobStatus::Completed => serializer.serialize_str("completed"),
+            JobStatus::Failed => serializer.serialize_str("failed"),
+            JobStatus::Cancelled => serializer.serialize_str("cancelled"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, EnumIter, DeriveActiveEnum, Deserialize)]
+#[sea_orm(rs_type = "String", db_type = "Enum", enum_name = "phase")]
+pub enum JobPhase {
+    #[sea_orm(string_value = "fetching_points")]
+    FetchingPoints,
+    #[sea_orm(string_value = "clustering")]
+    Clustering,
+    #[sea_orm(string_value = "routing")]
+    Routing,
+    #[sea_orm(string_value = "stats")]
+    Stats,
+}
+
+impl JobPhase {
+    /// Rough progress of a job that is `fraction` (0 to 1) of the way through this phase
+    pub fn progress(&self, fraction: f64) -> u8 {
+        let (start, end) = match self {
+            JobPhase::FetchingPoints => (0., 10.),
+            JobPhase::Clustering => (10., 60.),
+            JobPhase::Routing => (60., 90.),
+            JobPhase::Stats => (90., 100.),
+        };
+        (start + (end - start) * fraction.clamp(0., 1.)) as u8
+    }
+}
+
+impl Serialize for JobPhase {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        match self {
+            JobPhase::FetchingPoints => serializer.serialize_str("fetching_points"),
+            JobPhase::Clustering => serializer.serialize_str("clustering"),
+            JobPhase::Routing => serializer.serialize_str("routing"),
+            JobPhase::Stats => serializer.serialize_str("stats"),
+        }
+    }
+}
@@ -102,6 +102,28 @@ impl Query {
         Ok(utils::normalize::spawnpoint(items))
     }
 
+    /// Returns the spawnpoints with a known despawn time in the area, used for time-aware routing
+    pub async fn area_tth(
+        conn: &DatabaseConnection,
+        area: &FeatureCollection,
+        last_seen: u32,
+    ) -> Result<Vec<Spawnpoint>, DbErr> {
+        spawnpoint::Entity::find()
+            .from_raw_sql(Statement::from_sql_and_values(
+                DbBackend::MySql,
+                format!(
+                    "SELECT lat, lon, despawn_sec FROM spawnpoint WHERE last_seen >= {} AND despawn_sec IS NOT NULL AND ({}) LIMIT 2000000",
+                    last_seen,
+                    utils::sql_raw(area)
+                )
+                .as_str(),
+                vec![],
+            ))
+            .into_model::<Spawnpoint>()
+            .all(conn)
+            .await
+    }
+
     pub async fn stats(
         conn: &DatabaseConnection,
         area: &FeatureCollection,
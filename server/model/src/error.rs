@@ -21,6 +21,8 @@ pub enum ModelError {
     Route(String),
     #[error("[TileServer]: {0}")]
     TileServer(String),
+    #[error("[JOB]: {0}")]
+    Job(String),
     #[error("Not Implemented: {0}")]
     NotImplemented(String),
     #[error("{0}")]